@@ -0,0 +1,68 @@
+/*
+   Hashes the game's source files into CATCH_EM_SOURCE_HASH, which the
+   handshake's build hash is made from. Any change to the source, such as a
+   new Message variant, makes a different build that clients and servers can
+   tell apart even when the package version stays the same.
+*/
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const SOURCE_DIR: &str = "src";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", SOURCE_DIR);
+
+    let mut files = Vec::new();
+    collect_sources(Path::new(SOURCE_DIR), &mut files);
+    // Sorted so the hash doesn't depend on the order the file system lists them in
+    files.sort();
+
+    let mut hash = Fnv1a::default();
+    for file in files {
+        let contents = fs::read(&file)
+            .unwrap_or_else(|err| panic!("could not read {}: {}", file.display(), err));
+        // Paths are hashed with forward slashes and contents without carriage returns, so a
+        // checkout on any platform hashes the same
+        hash.write(file.to_string_lossy().replace('\\', "/").as_bytes());
+        hash.write(&[0]);
+        hash.write(
+            &contents
+                .into_iter()
+                .filter(|byte| *byte != b'\r')
+                .collect::<Vec<u8>>(),
+        );
+        hash.write(&[0]);
+    }
+    println!("cargo:rustc-env=CATCH_EM_SOURCE_HASH={:016x}", hash.0);
+}
+
+fn collect_sources(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries =
+        fs::read_dir(dir).unwrap_or_else(|err| panic!("could not read {}: {}", dir.display(), err));
+    for entry in entries {
+        let path = entry.expect("could not read directory entry").path();
+        if path.is_dir() {
+            collect_sources(&path, files);
+        } else if path.extension().is_some_and(|extension| extension == "rs") {
+            files.push(path);
+        }
+    }
+}
+
+/// FNV-1a, so the hash is stable across builds and platforms.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+}
@@ -0,0 +1,397 @@
+/*
+   Command line interface and config file.
+
+   `catch-em server` runs a dedicated server and `catch-em client` joins one.
+   Their settings can also be kept in a TOML config file, read from
+   catch-em.toml in the working directory unless --config names another, with
+   a [server] and a [client] table taking the same keys as the flags:
+
+       [server]
+       listen = "0.0.0.0:8080"
+       tick_rate = 60
+
+       [client]
+       connect = "192.168.1.20:8080"
+       name = "jenna"
+
+   Flags given on the command line override the file, and settings missing
+   from both take their defaults. The flags simulating a bad network are only
+   read from the command line.
+*/
+
+use std::fs;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand};
+use serde_derive::Deserialize;
+
+use crate::networking::conditions::{LinkConditions, NetworkConditions};
+use crate::Map;
+
+/// Read when no --config is given, if it exists.
+const DEFAULT_CONFIG_PATH: &str = "catch-em.toml";
+const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:8080";
+const DEFAULT_TICK_RATE: u32 = 30;
+const DEFAULT_CONNECT_ADDRESS: &str = "127.0.0.1:8080";
+const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1:8082";
+const DEFAULT_PLAYER_NAME: &str = "player";
+
+#[derive(Parser, Debug)]
+#[command(about = "A multiplayer game of catch")]
+pub struct Cli {
+    /// TOML file to read settings from, which flags override [default: catch-em.toml if it exists]
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Runs a dedicated server
+    Server {
+        #[command(flatten)]
+        settings: ServerSettings,
+        #[command(flatten)]
+        conditions: ConditionArgs,
+    },
+    /// Joins a server
+    Client {
+        #[command(flatten)]
+        settings: ClientSettings,
+        #[command(flatten)]
+        conditions: ConditionArgs,
+    },
+}
+
+/// Server settings as given on the command line or in the config file, before defaults.
+#[derive(Args, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    /// Address to listen on [default: 127.0.0.1:8080]
+    #[arg(long, value_name = "ADDR")]
+    pub listen: Option<SocketAddr>,
+    /// Server updates per second [default: 30]
+    #[arg(long, value_name = "HZ", value_parser = clap::value_parser!(u32).range(1..))]
+    pub tick_rate: Option<u32>,
+    /// glTF scene under assets/ to load the level from [default: playground.glb]
+    #[arg(long, value_name = "FILE")]
+    pub map: Option<String>,
+}
+
+impl ServerSettings {
+    /// Takes the settings missing here from `file`.
+    fn or(self, file: Self) -> Self {
+        Self {
+            listen: self.listen.or(file.listen),
+            tick_rate: self.tick_rate.or(file.tick_rate),
+            map: self.map.or(file.map),
+        }
+    }
+}
+
+/// Client settings as given on the command line or in the config file, before defaults.
+#[derive(Args, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientSettings {
+    /// Address of the server to join [default: 127.0.0.1:8080]
+    #[arg(long, value_name = "ADDR")]
+    pub connect: Option<SocketAddr>,
+    /// Local address to send from [default: 127.0.0.1:8082]
+    #[arg(long, value_name = "ADDR")]
+    pub bind: Option<SocketAddr>,
+    /// Name the server knows the player by [default: player]
+    #[arg(long)]
+    pub name: Option<String>,
+    /// glTF scene under assets/ to load the level from, which should match the server's
+    /// [default: playground.glb]
+    #[arg(long, value_name = "FILE")]
+    pub map: Option<String>,
+}
+
+impl ClientSettings {
+    /// Takes the settings missing here from `file`.
+    fn or(self, file: Self) -> Self {
+        Self {
+            connect: self.connect.or(file.connect),
+            bind: self.bind.or(file.bind),
+            name: self.name.or(file.name),
+            map: self.map.or(file.map),
+        }
+    }
+}
+
+/// Flags simulating a bad network in front of the socket, see conditions.rs. Each condition
+/// applies to both directions unless it is given for one with a `send-` or `recv-` prefix.
+#[derive(Args, Debug, Default, Clone, PartialEq)]
+#[command(next_help_heading = "Simulated network conditions")]
+pub struct ConditionArgs {
+    /// Delay added to every datagram
+    #[arg(long, value_name = "MS")]
+    latency: Option<u64>,
+    /// --latency for datagrams sent
+    #[arg(long, value_name = "MS", hide_short_help = true)]
+    send_latency: Option<u64>,
+    /// --latency for datagrams received
+    #[arg(long, value_name = "MS", hide_short_help = true)]
+    recv_latency: Option<u64>,
+    /// Most extra delay added to a datagram at random
+    #[arg(long, value_name = "MS")]
+    jitter: Option<u64>,
+    /// --jitter for datagrams sent
+    #[arg(long, value_name = "MS", hide_short_help = true)]
+    send_jitter: Option<u64>,
+    /// --jitter for datagrams received
+    #[arg(long, value_name = "MS", hide_short_help = true)]
+    recv_jitter: Option<u64>,
+    /// Chance of a datagram being dropped, from 0 to 1
+    #[arg(long, value_name = "P", value_parser = parse_chance)]
+    loss: Option<f64>,
+    /// --loss for datagrams sent
+    #[arg(long, value_name = "P", value_parser = parse_chance, hide_short_help = true)]
+    send_loss: Option<f64>,
+    /// --loss for datagrams received
+    #[arg(long, value_name = "P", value_parser = parse_chance, hide_short_help = true)]
+    recv_loss: Option<f64>,
+    /// Chance of a datagram arriving twice, from 0 to 1
+    #[arg(long, value_name = "P", value_parser = parse_chance)]
+    duplication: Option<f64>,
+    /// --duplication for datagrams sent
+    #[arg(long, value_name = "P", value_parser = parse_chance, hide_short_help = true)]
+    send_duplication: Option<f64>,
+    /// --duplication for datagrams received
+    #[arg(long, value_name = "P", value_parser = parse_chance, hide_short_help = true)]
+    recv_duplication: Option<f64>,
+    /// Chance of a datagram being overtaken by the next, from 0 to 1
+    #[arg(long, value_name = "P", value_parser = parse_chance)]
+    reordering: Option<f64>,
+    /// --reordering for datagrams sent
+    #[arg(long, value_name = "P", value_parser = parse_chance, hide_short_help = true)]
+    send_reordering: Option<f64>,
+    /// --reordering for datagrams received
+    #[arg(long, value_name = "P", value_parser = parse_chance, hide_short_help = true)]
+    recv_reordering: Option<f64>,
+    /// Seeds the random choices, so that a run can be reproduced
+    #[arg(long, value_name = "N")]
+    net_seed: Option<u64>,
+}
+
+fn parse_chance(value: &str) -> Result<f64, String> {
+    match value.parse() {
+        Ok(chance) if (0. ..=1.).contains(&chance) => Ok(chance),
+        _ => Err(format!("expected a chance from 0 to 1, not {:?}", value)),
+    }
+}
+
+impl ConditionArgs {
+    /// The conditions to simulate, or `None` if no flag was given.
+    fn conditions(&self) -> Option<NetworkConditions> {
+        let conditions = NetworkConditions {
+            outgoing: LinkConditions {
+                latency: millis(self.send_latency.or(self.latency)),
+                jitter: millis(self.send_jitter.or(self.jitter)),
+                loss: self.send_loss.or(self.loss).unwrap_or(0.),
+                duplication: self.send_duplication.or(self.duplication).unwrap_or(0.),
+                reordering: self.send_reordering.or(self.reordering).unwrap_or(0.),
+            },
+            incoming: LinkConditions {
+                latency: millis(self.recv_latency.or(self.latency)),
+                jitter: millis(self.recv_jitter.or(self.jitter)),
+                loss: self.recv_loss.or(self.loss).unwrap_or(0.),
+                duplication: self.recv_duplication.or(self.duplication).unwrap_or(0.),
+                reordering: self.recv_reordering.or(self.reordering).unwrap_or(0.),
+            },
+            seed: self.net_seed.unwrap_or(0),
+        };
+        (*self != Self::default()).then_some(conditions)
+    }
+}
+
+fn millis(value: Option<u64>) -> Duration {
+    Duration::from_millis(value.unwrap_or(0))
+}
+
+/// The contents of a config file.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    server: ServerSettings,
+    client: ClientSettings,
+}
+
+impl ConfigFile {
+    /// Reads `path`, or the default config file if there is one.
+    fn read(path: Option<&Path>) -> Result<Self, String> {
+        let (path, required) = match path {
+            Some(path) => (path, true),
+            None => (Path::new(DEFAULT_CONFIG_PATH), false),
+        };
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == ErrorKind::NotFound && !required => {
+                return Ok(Self::default())
+            }
+            Err(err) => return Err(format!("could not read {}: {}", path.display(), err)),
+        };
+        Self::parse(&text).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|err| err.to_string())
+    }
+}
+
+pub struct ServerConfig {
+    pub listen: SocketAddr,
+    pub tick_duration: Duration,
+    pub map: Map,
+    pub conditions: Option<NetworkConditions>,
+}
+
+pub struct ClientConfig {
+    pub connect: SocketAddr,
+    pub bind: SocketAddr,
+    pub name: String,
+    pub map: Map,
+    pub conditions: Option<NetworkConditions>,
+}
+
+pub enum Config {
+    Server(ServerConfig),
+    Client(ClientConfig),
+}
+
+impl Cli {
+    /// Layers the flags over the config file and fills in the defaults.
+    pub fn resolve(self) -> Result<Config, String> {
+        let file = ConfigFile::read(self.config.as_deref())?;
+        self.command.resolve(file)
+    }
+}
+
+impl Command {
+    fn resolve(self, file: ConfigFile) -> Result<Config, String> {
+        match self {
+            Command::Server {
+                settings,
+                conditions,
+            } => {
+                let settings = settings.or(file.server);
+                let tick_rate = settings.tick_rate.unwrap_or(DEFAULT_TICK_RATE);
+                if tick_rate == 0 {
+                    return Err("tick_rate must be at least 1".to_string());
+                }
+                Ok(Config::Server(ServerConfig {
+                    listen: settings
+                        .listen
+                        .unwrap_or_else(|| DEFAULT_LISTEN_ADDRESS.parse().unwrap()),
+                    tick_duration: Duration::from_secs(1) / tick_rate,
+                    map: settings.map.map(Map).unwrap_or_default(),
+                    conditions: conditions.conditions(),
+                }))
+            }
+            Command::Client {
+                settings,
+                conditions,
+            } => {
+                let settings = settings.or(file.client);
+                Ok(Config::Client(ClientConfig {
+                    connect: settings
+                        .connect
+                        .unwrap_or_else(|| DEFAULT_CONNECT_ADDRESS.parse().unwrap()),
+                    bind: settings
+                        .bind
+                        .unwrap_or_else(|| DEFAULT_BIND_ADDRESS.parse().unwrap()),
+                    name: settings
+                        .name
+                        .unwrap_or_else(|| DEFAULT_PLAYER_NAME.to_string()),
+                    map: settings.map.map(Map).unwrap_or_default(),
+                    conditions: conditions.conditions(),
+                }))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(args: &[&str], file: &str) -> Config {
+        let cli = Cli::try_parse_from([&["catch-em"], args].concat()).unwrap();
+        cli.command
+            .resolve(ConfigFile::parse(file).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_flags_override_config_file() {
+        let file = r#"
+            [server]
+            listen = "0.0.0.0:9000"
+            tick_rate = 60
+
+            [client]
+            connect = "10.0.0.1:9000"
+            name = "from file"
+        "#;
+
+        let Config::Server(server) = resolve(&["server", "--tick-rate", "20"], file) else {
+            panic!("expected a server config");
+        };
+        assert_eq!(server.listen, "0.0.0.0:9000".parse().unwrap());
+        assert_eq!(server.tick_duration, Duration::from_millis(50));
+        assert_eq!(server.map.0, Map::default().0);
+
+        let Config::Client(client) = resolve(&["client", "--name", "from flag"], file) else {
+            panic!("expected a client config");
+        };
+        assert_eq!(client.connect, "10.0.0.1:9000".parse().unwrap());
+        assert_eq!(client.bind, DEFAULT_BIND_ADDRESS.parse().unwrap());
+        assert_eq!(client.name, "from flag");
+    }
+
+    #[test]
+    fn test_rejects_bad_settings() {
+        assert!(ConfigFile::parse("[server]\nlisten_address = \"0.0.0.0:8080\"").is_err());
+        assert!(ConfigFile::parse("[server]\nlisten = \"localhost\"").is_err());
+        assert!(Cli::try_parse_from(["catch-em", "server", "--tick-rate", "0"]).is_err());
+        assert!(Cli::try_parse_from(["catch-em", "client", "--loss", "2"]).is_err());
+        assert!(Cli::try_parse_from(["catch-em"]).is_err());
+
+        let cli = Cli::try_parse_from(["catch-em", "server"]).unwrap();
+        let file = ConfigFile::parse("[server]\ntick_rate = 0").unwrap();
+        assert!(cli.command.resolve(file).is_err());
+    }
+
+    #[test]
+    fn test_conditions_from_flags() {
+        let Config::Client(client) = resolve(
+            &[
+                "client",
+                "--latency=100",
+                "--recv-loss",
+                "0.25",
+                "--net-seed",
+                "7",
+            ],
+            "",
+        ) else {
+            panic!("expected a client config");
+        };
+        let conditions = client.conditions.unwrap();
+        assert_eq!(conditions.outgoing.latency, Duration::from_millis(100));
+        assert_eq!(conditions.incoming.latency, Duration::from_millis(100));
+        assert_eq!(conditions.outgoing.loss, 0.);
+        assert_eq!(conditions.incoming.loss, 0.25);
+        assert_eq!(conditions.seed, 7);
+
+        let Config::Client(client) = resolve(&["client"], "") else {
+            panic!("expected a client config");
+        };
+        assert!(client.conditions.is_none());
+    }
+}
@@ -1,22 +1,40 @@
-use crate::networking::components::NetworkObjectType;
+use crate::networking::components::{NetworkObjectType, ObjectId};
 
 use crate::networking::handshake::{ConnectionStatus};
 
 use crate::networking::resources::PlayerId;
 
-use crate::game::entities::{spawn_player, spawn_player_facade};
+use crate::game::entities::{spawn_item_facade, spawn_player, spawn_player_facade, Tagged};
+use crate::game::overlay::NetworkOverlayPlugin;
 
+use crate::cli::ClientConfig;
+use crate::networking::fragmentation::DEFAULT_MTU;
 use crate::networking::{ClientPlugin};
-use crate::{display_text, manage_cursor, respawn, scene_colliders, setup};
+use crate::networking::replication::AppReplicationExt;
+use crate::{
+    display_connection_status, display_text, manage_cursor, respawn, scene_colliders, setup,
+};
 use bevy::prelude::*;
 use bevy_fps_controller::controller::FpsControllerPlugin;
 use bevy_rapier3d::plugin::{NoUserData, RapierPhysicsPlugin};
 use bevy_rapier3d::prelude::RapierConfiguration;
 
-pub fn main(socket_addr: String) {
-    App::new()
-        .insert_resource(ConnectionStatus::Initial)
-        .add_plugins(ClientPlugin("127.0.0.1:8080".parse().unwrap(), socket_addr))
+pub fn main(config: ClientConfig) {
+    let mut app = App::new();
+    // Must be inserted before the ClientPlugin to apply to its socket
+    if let Some(conditions) = config.conditions {
+        app.insert_resource(conditions);
+    }
+    app.insert_resource(ConnectionStatus::Initial)
+        .insert_resource(config.map)
+        .add_plugins(ClientPlugin {
+            server_addr: config.connect.to_string(),
+            bind_addr: config.bind.to_string(),
+            mtu: DEFAULT_MTU,
+            compression: true,
+            player_name: config.name,
+        })
+        .replicate::<Tagged>()
         .insert_resource(AmbientLight {
             color: Color::WHITE,
             brightness: 0.5,
@@ -26,37 +44,46 @@ pub fn main(socket_addr: String) {
         .add_plugins(DefaultPlugins)
         .add_plugins(RapierPhysicsPlugin::<NoUserData>::default())
         .add_plugins(FpsControllerPlugin)
+        .add_plugins(NetworkOverlayPlugin)
         .add_systems(Startup, setup)
         .add_systems(
             Update,
-            (manage_cursor, scene_colliders, display_text, respawn),
+            (
+                manage_cursor,
+                scene_colliders,
+                display_text,
+                display_connection_status,
+                respawn,
+            ),
         )
         .run();
 }
 
 pub(crate) fn spawn_network_object(
     object_type: &NetworkObjectType,
-    object_id: u8,
+    object_id: ObjectId,
     id: PlayerId,
     pos: Vec3,
-    mut commands: &mut Commands,
+    commands: &mut Commands,
 ) {
     println!("Spawning net object");
     match object_type {
         NetworkObjectType::Player => {
-            spawn_player(id, object_id, pos, &mut commands);
+            spawn_player(id, object_id, pos, commands);
         }
+        // Items are simulated by the server, so even their owner only gets a facade
+        NetworkObjectType::Item => warn!("Not spawning item {} as a locally simulated object", object_id),
     }
 }
 
 pub(crate) fn spawn_network_facade_object(
     object_type: &NetworkObjectType,
-    object_id: u8,
+    object_id: ObjectId,
     id: PlayerId,
     pos: Vec3,
-    mut commands: &mut Commands,
-    mut meshes: &mut ResMut<Assets<Mesh>>,
-    mut materials: &mut ResMut<Assets<StandardMaterial>>,
+    commands: &mut Commands,
+    meshes: &mut ResMut<Assets<Mesh>>,
+    materials: &mut ResMut<Assets<StandardMaterial>>,
 ) {
     println!("Spawning facade object");
     match object_type {
@@ -65,10 +92,13 @@ pub(crate) fn spawn_network_facade_object(
                 id,
                 object_id,
                 pos,
-                &mut commands,
-                &mut meshes,
-                &mut materials,
+                commands,
+                meshes,
+                materials,
             );
         }
+        NetworkObjectType::Item => {
+            spawn_item_facade(id, object_id, pos, commands, meshes, materials);
+        }
     }
 }
@@ -3,15 +3,18 @@ use std::f32::consts::TAU;
 use bevy::prelude::*;
 use bevy_rapier3d::prelude::*;
 
-use crate::networking::components::{NetworkObject, NetworkObjectType, NetworkTransform};
+use crate::game::simulation::InputQueue;
+use crate::networking::components::{NetworkObject, NetworkObjectType, NetworkTransform, ObjectId};
+use crate::networking::replication::Replicated;
 use crate::networking::resources::PlayerId;
 use bevy_fps_controller::controller::*;
+use serde_derive::{Deserialize, Serialize};
 
 pub const DEFAULT_SPAWN_POINT: Vec3 = Vec3::new(0.0, 1.0, 0.0);
 
 pub fn spawn_player_facade(
     id: PlayerId,
-    object_id: u8,
+    object_id: ObjectId,
     pos: Vec3,
     commands: &mut Commands,
     meshes: &mut ResMut<Assets<Mesh>>,
@@ -19,15 +22,7 @@ pub fn spawn_player_facade(
 ) {
     commands.spawn((
         Collider::capsule(pos, pos * 1.2, 0.5),
-        NetworkObject {
-            id: object_id,
-            owner: id,
-            object_type: NetworkObjectType::Player,
-            is_owned: false
-        },
-        NetworkTransform {
-            last_pos: Transform::from_translation(pos).translation,
-        },
+        NetworkTransformBundle::new(object_id, id, NetworkObjectType::Player, false),
         LockedAxes::ROTATION_LOCKED,
         ActiveEvents::COLLISION_EVENTS,
         Friction {
@@ -67,11 +62,9 @@ struct NetworkTransformBundle {
 }
 
 impl NetworkTransformBundle {
-    pub fn new(object_id: u8, owner_id: PlayerId, object_type: NetworkObjectType, start_pos: Vec3, is_owned: bool) -> NetworkTransformBundle {
-        return NetworkTransformBundle {
-            transform: NetworkTransform {
-                last_pos: Transform::from_translation(start_pos).translation,
-            },
+    pub fn new(object_id: ObjectId, owner_id: PlayerId, object_type: NetworkObjectType, is_owned: bool) -> NetworkTransformBundle {
+        NetworkTransformBundle {
+            transform: NetworkTransform::default(),
             object: NetworkObject {
                 id: object_id,
                 owner: owner_id,
@@ -82,8 +75,57 @@ impl NetworkTransformBundle {
     }
 }
 
-pub fn spawn_player(id: PlayerId, object_id: u8, pos: Vec3, commands: &mut Commands) {
+pub fn spawn_player(id: PlayerId, object_id: ObjectId, pos: Vec3, commands: &mut Commands) {
     commands.spawn((
+        player_body(pos),
+        NetworkObject {
+            id: object_id,
+            owner: id,
+            object_type: NetworkObjectType::Player,
+            is_owned: true
+        },
+        LogicalPlayer(0),
+        FPSControllerBundle {
+            input: FpsControllerInput {
+                pitch: -TAU / 12.0,
+                yaw: TAU * 5.0 / 8.0,
+                ..default()
+            },
+            controller: player_controller(),
+        }
+    ));
+}
+
+/// Spawns a player on the server, where it is moved by the inputs its client sends instead of
+/// the local keyboard and mouse.
+pub fn spawn_simulated_player(id: PlayerId, object_id: ObjectId, pos: Vec3, commands: &mut Commands) {
+    commands.spawn((
+        player_body(pos),
+        NetworkObject {
+            id: object_id,
+            owner: id,
+            object_type: NetworkObjectType::Player,
+            is_owned: false
+        },
+        InputQueue::default(),
+        Tagged(false),
+        FPSControllerBundle {
+            input: FpsControllerInput::default(),
+            controller: player_controller(),
+        }
+    ));
+}
+
+/// Whether a player is currently "it". Set by the server and replicated to every client.
+#[derive(Component, Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Tagged(pub bool);
+
+impl Replicated for Tagged {}
+
+/// Physics shared by every player capsule driven by an FpsController, so that the client and the
+/// server move players identically.
+fn player_body(pos: Vec3) -> impl Bundle {
+    (
         Collider::capsule(pos, pos * 1.5, 0.5),
         Friction {
             coefficient: 0.0,
@@ -93,12 +135,6 @@ pub fn spawn_player(id: PlayerId, object_id: u8, pos: Vec3, commands: &mut Comma
             coefficient: 0.0,
             combine_rule: CoefficientCombineRule::Min,
         },
-        NetworkObject {
-            id: object_id,
-            owner: id,
-            object_type: NetworkObjectType::Player,
-            is_owned: true
-        },
         ActiveEvents::COLLISION_EVENTS,
         Velocity::zero(),
         RigidBody::Dynamic,
@@ -108,17 +144,61 @@ pub fn spawn_player(id: PlayerId, object_id: u8, pos: Vec3, commands: &mut Comma
         GravityScale(0.0),
         Ccd { enabled: true }, // Prevent clipping when going fast
         TransformBundle::from_transform(Transform::from_translation(pos)),
-        LogicalPlayer(0),
-        FPSControllerBundle {
-            input: FpsControllerInput {
-                pitch: -TAU / 12.0,
-                yaw: TAU * 5.0 / 8.0,
-                ..default()
-            },
-            controller: FpsController {
-                air_acceleration: 80.0,
+    )
+}
+
+fn player_controller() -> FpsController {
+    FpsController {
+        air_acceleration: 80.0,
+        ..default()
+    }
+}
+
+/// Seconds a thrown item stays in the world before the server removes it.
+pub const ITEM_LIFETIME_SECS: f32 = 10.;
+const ITEM_RADIUS: f32 = 0.2;
+
+/// Counts down until the server removes the object it is attached to.
+#[derive(Component)]
+pub struct Lifetime(pub Timer);
+
+pub fn spawn_item_facade(
+    owner: PlayerId,
+    object_id: ObjectId,
+    pos: Vec3,
+    commands: &mut Commands,
+    meshes: &mut ResMut<Assets<Mesh>>,
+    materials: &mut ResMut<Assets<StandardMaterial>>,
+) {
+    commands.spawn((
+        NetworkTransformBundle::new(object_id, owner, NetworkObjectType::Item, false),
+        PbrBundle {
+            mesh: meshes.add(Mesh::from(shape::UVSphere {
+                radius: ITEM_RADIUS,
                 ..default()
-            },
-        }
+            })),
+            material: materials.add(Color::ORANGE_RED.into()),
+            transform: Transform::from_translation(pos),
+            ..default()
+        },
+    ));
+}
+
+/// Spawns a thrown item on the server, which simulates it for every client.
+pub fn spawn_simulated_item(owner: PlayerId, object_id: ObjectId, pos: Vec3, velocity: Vec3, commands: &mut Commands) {
+    commands.spawn((
+        Collider::ball(ITEM_RADIUS),
+        RigidBody::Dynamic,
+        Velocity::linear(velocity),
+        Restitution::coefficient(0.5),
+        Ccd { enabled: true },
+        TransformBundle::from_transform(Transform::from_translation(pos)),
+        NetworkObject {
+            id: object_id,
+            owner,
+            object_type: NetworkObjectType::Item,
+            is_owned: false
+        },
+        Lifetime(Timer::from_seconds(ITEM_LIFETIME_SECS, TimerMode::Once)),
     ));
 }
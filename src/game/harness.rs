@@ -0,0 +1,452 @@
+/*
+   Runs a server and any number of clients in one test, talking to each other
+   over an in-memory network instead of real sockets.
+
+   Every app is headless: the server runs its usual game and simulation, and
+   each client runs the ClientPlugin with just enough of bevy for it to spawn
+   the objects it is sent and reconcile its prediction, without a window,
+   renderer or input devices. The apps are stepped one after the other with
+   the same fixed timestep, server first, so every run of a test sees the same
+   sequence of frames. Anything a client sends reaches the server on the next
+   step, and anything the server sends reaches the clients later in the same
+   step.
+
+   Every client's socket sits behind a SimulatedSocket, which lets nothing go
+   wrong until a test changes the client's NetworkConditions.
+*/
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bevy::asset::AssetPlugin;
+use bevy::pbr::StandardMaterial;
+use bevy::prelude::*;
+use bevy::render::mesh::MeshPlugin;
+use bevy::scene::ScenePlugin;
+use bevy::time::{TimePlugin, TimeUpdateStrategy};
+use bevy_rapier3d::prelude::{NoUserData, RapierPhysicsPlugin};
+
+use crate::game::entities::Tagged;
+use crate::game::server::ServerGamePlugin;
+use crate::networking::components::{NetworkObject, NetworkObjectType};
+use crate::networking::conditions::NetworkConditions;
+use crate::networking::fragmentation::DEFAULT_MTU;
+use crate::networking::handshake::ConnectionStatus;
+use crate::networking::packet_systems::{Socket, VirtualNetwork};
+use crate::networking::replication::AppReplicationExt;
+use crate::networking::resources::PlayerId;
+use crate::networking::{ClientPlugin, ServerPlugin};
+
+/// How much time passes in every app on each step.
+const DEFAULT_STEP_MILLIS: u64 = 50;
+const SERVER_PORT: u16 = 8080;
+/// Clients are bound to consecutive ports from this one.
+const FIRST_CLIENT_PORT: u16 = 9000;
+
+pub struct TestHarness {
+    pub server: App,
+    // None once the client has been dropped
+    pub clients: Vec<Option<App>>,
+    pub step: Duration,
+    network: VirtualNetwork,
+}
+
+impl TestHarness {
+    /// Starts a server and `clients` clients, none of which has connected yet.
+    pub fn new(clients: usize) -> Self {
+        let step = Duration::from_millis(DEFAULT_STEP_MILLIS);
+        let network = VirtualNetwork::default();
+        let mut server = App::new();
+        server
+            .add_plugins(TimePlugin)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(step))
+            .insert_resource(Socket(Box::new(network.bind(server_addr()))))
+            .add_plugins(ServerPlugin {
+                listen_addr: server_addr().to_string(),
+                mtu: DEFAULT_MTU,
+                tick_duration: step,
+                compression: true,
+            })
+            .add_plugins(ServerGamePlugin);
+
+        let mut harness = Self {
+            server,
+            clients: Vec::new(),
+            step,
+            network,
+        };
+        for _ in 0..clients {
+            harness.add_client();
+        }
+        harness
+    }
+
+    /// Starts another client, returning its index.
+    pub fn add_client(&mut self) -> usize {
+        let index = self.clients.len();
+        let addr = SocketAddr::from(([127, 0, 0, 1], FIRST_CLIENT_PORT + index as u16));
+        let mut client = App::new();
+        client
+            .add_plugins((MinimalPlugins, TransformPlugin, HierarchyPlugin))
+            .add_plugins((AssetPlugin::default(), MeshPlugin, ScenePlugin))
+            .add_asset::<StandardMaterial>()
+            .add_plugins(RapierPhysicsPlugin::<NoUserData>::default())
+            .init_resource::<Input<MouseButton>>()
+            .insert_resource(TimeUpdateStrategy::ManualDuration(self.step))
+            .insert_resource(Socket(Box::new(self.network.connect(addr, server_addr()))))
+            .insert_resource(NetworkConditions::default())
+            .insert_resource(ConnectionStatus::Initial)
+            .add_plugins(ClientPlugin {
+                server_addr: server_addr().to_string(),
+                bind_addr: addr.to_string(),
+                mtu: DEFAULT_MTU,
+                compression: true,
+                player_name: format!("player {}", index),
+            })
+            // Must match the components the server replicates, in the same order
+            .replicate::<Tagged>();
+        self.clients.push(Some(client));
+        index
+    }
+
+    pub fn client(&mut self, index: usize) -> &mut App {
+        self.clients[index]
+            .as_mut()
+            .expect("client has been dropped")
+    }
+
+    /// Changes what happens to the datagrams a client sends and receives from now on.
+    pub fn set_conditions(&mut self, index: usize, conditions: NetworkConditions) {
+        self.client(index).insert_resource(conditions);
+    }
+
+    /// Drops a client without it saying goodbye, as if it had crashed. Its address stops
+    /// receiving anything.
+    pub fn drop_client(&mut self, index: usize) {
+        self.clients[index] = None;
+    }
+
+    /// Updates the server and then every client once.
+    pub fn step(&mut self) {
+        self.server.update();
+        for client in self.clients.iter_mut().flatten() {
+            client.update();
+        }
+    }
+
+    pub fn run_for(&mut self, duration: Duration) {
+        for _ in 0..steps_in(duration, self.step) {
+            self.step();
+        }
+    }
+
+    /// Steps until `condition` holds, for at most `timeout`. Returns whether it ever held.
+    pub fn run_until(
+        &mut self,
+        timeout: Duration,
+        mut condition: impl FnMut(&mut Self) -> bool,
+    ) -> bool {
+        for _ in 0..steps_in(timeout, self.step) {
+            if condition(self) {
+                return true;
+            }
+            self.step();
+        }
+        condition(self)
+    }
+
+    /// Steps until every remaining client has completed the handshake.
+    pub fn connect_all(&mut self) -> bool {
+        self.run_until(Duration::from_secs(5), |harness| {
+            harness.clients.iter().flatten().all(|client| {
+                matches!(
+                    client.world.resource::<ConnectionStatus>(),
+                    ConnectionStatus::Complete
+                )
+            })
+        })
+    }
+
+    pub fn player_id(&self, index: usize) -> PlayerId {
+        let client = self.clients[index]
+            .as_ref()
+            .expect("client has been dropped");
+        *client.world.resource::<PlayerId>()
+    }
+
+    /// The owners of the player facades a client has spawned, sorted.
+    pub fn player_facades(&mut self, index: usize) -> Vec<PlayerId> {
+        let world = &mut self.client(index).world;
+        let mut owners: Vec<PlayerId> = world
+            .query::<&NetworkObject>()
+            .iter(world)
+            .filter(|object| object.object_type == NetworkObjectType::Player && !object.is_owned)
+            .map(|object| object.owner)
+            .collect();
+        owners.sort_by_key(|owner| owner.0);
+        owners
+    }
+}
+
+fn server_addr() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], SERVER_PORT))
+}
+
+fn steps_in(duration: Duration, step: Duration) -> u32 {
+    (duration.as_secs_f64() / step.as_secs_f64()).ceil() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::server::{exit_on_shutdown, Shutdown, MAX_LIVE_ITEMS_PER_PLAYER};
+    use crate::networking::events::DisconnectReason;
+    use crate::networking::resources::NetworkGame;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use crate::networking::stats::{ConnectionStats, NetworkStats};
+
+    /// The ids of every client except `index`, sorted.
+    fn other_players(harness: &TestHarness, index: usize) -> Vec<PlayerId> {
+        let mut players: Vec<PlayerId> = (0..harness.clients.len())
+            .filter(|other| *other != index && harness.clients[*other].is_some())
+            .map(|other| harness.player_id(other))
+            .collect();
+        players.sort_by_key(|player| player.0);
+        players
+    }
+
+    #[test]
+    fn test_every_client_sees_every_other_player() {
+        let mut harness = TestHarness::new(3);
+        assert!(harness.connect_all());
+
+        let spawned = harness.run_until(Duration::from_secs(2), |harness| {
+            (0..3).all(|index| harness.player_facades(index) == other_players(harness, index))
+        });
+        assert!(spawned);
+        for index in 0..3 {
+            assert_eq!(harness.player_facades(index).len(), 2);
+        }
+
+        let names = &harness.server.world.resource::<NetworkGame>().players.names;
+        for index in 0..3 {
+            let name = names.get(&harness.player_id(index));
+            assert_eq!(name.map(String::as_str), Some(format!("player {}", index).as_str()));
+        }
+    }
+
+    /// Presses the throw button on a client for its next update.
+    fn throw(harness: &mut TestHarness, index: usize) {
+        let mut buttons = harness.client(index).world.resource_mut::<Input<MouseButton>>();
+        buttons.reset(MouseButton::Right);
+        buttons.press(MouseButton::Right);
+    }
+
+    /// How many items the server has for a player.
+    fn server_items(harness: &TestHarness, player: PlayerId) -> usize {
+        let network = harness.server.world.resource::<NetworkGame>();
+        network
+            .objects
+            .objects
+            .keys()
+            .filter(|object| object.owner == player && object.object_type == NetworkObjectType::Item)
+            .count()
+    }
+
+    #[test]
+    fn test_throws_are_limited_by_a_cooldown_and_live_items() {
+        let mut harness = TestHarness::new(1);
+        assert!(harness.connect_all());
+        let player = harness.player_id(0);
+
+        // Asking to throw on every step only throws once per cooldown
+        for _ in 0..steps_in(Duration::from_secs(1), harness.step) {
+            throw(&mut harness, 0);
+            harness.step();
+        }
+        harness.step();
+        assert_eq!(server_items(&harness, player), 2);
+
+        // Long enough for several more cooldowns, but not for any item to expire
+        for _ in 0..steps_in(Duration::from_secs(5), harness.step) {
+            throw(&mut harness, 0);
+            harness.step();
+        }
+        assert_eq!(server_items(&harness, player), MAX_LIVE_ITEMS_PER_PLAYER);
+    }
+
+    /// Whether `player` is tagged on a client, or None if the client has no Tagged for it.
+    fn tagged_on_client(harness: &mut TestHarness, index: usize, player: PlayerId) -> Option<bool> {
+        let world = &mut harness.client(index).world;
+        world
+            .query::<(&NetworkObject, Option<&Tagged>)>()
+            .iter(world)
+            .find(|(object, _)| object.owner == player && object.object_type == NetworkObjectType::Player)
+            .and_then(|(_, tagged)| tagged.map(|tagged| tagged.0))
+    }
+
+    #[test]
+    fn test_tagged_is_replicated_to_every_client() {
+        let mut harness = TestHarness::new(2);
+        assert!(harness.connect_all());
+        let player = harness.player_id(0);
+        let replicated = harness.run_until(Duration::from_secs(2), |harness| {
+            (0..2).all(|index| tagged_on_client(harness, index, player) == Some(false))
+        });
+        assert!(replicated);
+
+        let world = &mut harness.server.world;
+        let body = world
+            .query::<(Entity, &NetworkObject)>()
+            .iter(world)
+            .find(|(_, object)| object.owner == player && object.object_type == NetworkObjectType::Player)
+            .map(|(entity, _)| entity)
+            .unwrap();
+        world.get_mut::<Tagged>(body).unwrap().0 = true;
+        let tagged = harness.run_until(Duration::from_secs(1), |harness| {
+            (0..2).all(|index| tagged_on_client(harness, index, player) == Some(true))
+        });
+        assert!(tagged);
+
+        harness.server.world.entity_mut(body).remove::<Tagged>();
+        let removed = harness.run_until(Duration::from_secs(1), |harness| {
+            (0..2).all(|index| tagged_on_client(harness, index, player).is_none())
+        });
+        assert!(removed);
+    }
+
+    #[test]
+    fn test_server_keeps_stats_for_every_client() {
+        let mut harness = TestHarness::new(2);
+        assert!(harness.connect_all());
+        harness.run_for(Duration::from_secs(3));
+
+        let stats = harness.server.world.resource::<NetworkStats>();
+        assert_eq!(stats.connections.len(), 2);
+        for connection in stats.connections.values() {
+            let sample = connection.latest();
+            assert!(sample.bytes_sent_per_sec > 0.);
+            assert!(sample.packets_received_per_sec > 0.);
+            assert_eq!(sample.loss, 0.);
+            assert!(connection.rtt.is_some());
+        }
+        let client = harness.client(0).world.resource::<NetworkStats>();
+        assert!(client.get(server_addr()).is_some_and(|server| server.rtt.is_some()));
+    }
+
+    #[test]
+    fn test_loss_is_measured_in_both_directions() {
+        let mut harness = TestHarness::new(1);
+        assert!(harness.connect_all());
+        let mut lossy = NetworkConditions::default();
+        lossy.incoming.loss = 0.2;
+        lossy.outgoing.loss = 0.2;
+        harness.set_conditions(0, lossy);
+        harness.run_for(Duration::from_secs(3));
+
+        // Averaged over every sample, as a single second holds too few snapshots to rely on
+        let mean_loss = |connection: &ConnectionStats| {
+            connection.history.iter().map(|sample| sample.loss).sum::<f32>()
+                / connection.history.len() as f32
+        };
+        let client_addr = SocketAddr::from(([127, 0, 0, 1], FIRST_CLIENT_PORT));
+        let server = harness.server.world.resource::<NetworkStats>();
+        let from_client = mean_loss(server.get(client_addr).unwrap());
+        let client = harness.client(0).world.resource::<NetworkStats>();
+        let from_server = mean_loss(client.get(server_addr()).unwrap());
+        for loss in [from_client, from_server] {
+            assert!(loss > 0. && loss < 60., "measured {}% loss", loss);
+        }
+    }
+
+    #[test]
+    fn test_client_only_counts_authenticated_datagrams() {
+        let mut harness = TestHarness::new(1);
+        assert!(harness.connect_all());
+        let client_addr = SocketAddr::from(([127, 0, 0, 1], FIRST_CLIENT_PORT));
+
+        // The server goes quiet, and someone spoofing its address sends garbage instead
+        for _ in 0..steps_in(Duration::from_secs(2), harness.step) {
+            let socket = harness.server.world.resource::<Socket>();
+            socket.0.send_to(&[0; 100], client_addr).unwrap();
+            harness.client(0).update();
+        }
+        let client = harness.client(0).world.resource::<NetworkStats>();
+        let sample = client.get(server_addr()).unwrap().latest();
+        assert_eq!(sample.packets_received_per_sec, 0.);
+        assert_eq!(sample.bytes_received_per_sec, 0.);
+    }
+
+    #[test]
+    fn test_client_catches_up_after_reliable_message_is_given_up_on() {
+        let mut harness = TestHarness::new(2);
+        assert!(harness.connect_all());
+        harness.run_for(Duration::from_secs(1));
+
+        // The server gives up on announcing the new player to the deaf client before the client
+        // would notice the silence and time out
+        let mut deaf = NetworkConditions::default();
+        deaf.incoming.loss = 1.;
+        harness.set_conditions(0, deaf);
+        harness.add_client();
+        assert!(harness.connect_all());
+        harness.run_for(Duration::from_secs(3));
+        harness.set_conditions(0, NetworkConditions::default());
+
+        let caught_up = harness.run_until(Duration::from_secs(15), |harness| {
+            harness.player_facades(0) == other_players(harness, 0)
+        });
+        assert!(caught_up);
+    }
+
+    #[test]
+    fn test_clients_are_told_when_the_server_shuts_down() {
+        let mut harness = TestHarness::new(2);
+        assert!(harness.connect_all());
+
+        let requested = Arc::new(AtomicBool::new(false));
+        harness
+            .server
+            .insert_resource(Shutdown(requested.clone()))
+            .add_systems(First, exit_on_shutdown);
+        harness.step();
+        requested.store(true, Ordering::SeqCst);
+        harness.step();
+
+        // The runner stops the server after that update, so only the clients run from here on
+        for client in harness.clients.iter_mut().flatten() {
+            client.update();
+        }
+        for index in 0..2 {
+            assert!(matches!(
+                harness.client(index).world.resource::<ConnectionStatus>(),
+                ConnectionStatus::Disconnected {
+                    reason: DisconnectReason::Requested
+                }
+            ));
+        }
+    }
+
+    #[test]
+    fn test_timed_out_player_is_despawned_for_everyone_else() {
+        let mut harness = TestHarness::new(3);
+        assert!(harness.connect_all());
+        harness.run_for(Duration::from_secs(1));
+        let dropped = harness.player_id(2);
+        assert!(harness.player_facades(0).contains(&dropped));
+
+        // The player is kept through the idle timeout and the window for resuming the session
+        harness.drop_client(2);
+        let despawned = harness.run_until(Duration::from_secs(45), |harness| {
+            (0..2).all(|index| !harness.player_facades(index).contains(&dropped))
+        });
+        assert!(despawned);
+        for index in 0..2 {
+            assert_eq!(
+                harness.player_facades(index),
+                other_players(&harness, index)
+            );
+        }
+    }
+}
@@ -1,3 +1,7 @@
 pub mod client;
 pub mod entities;
+#[cfg(test)]
+pub mod harness;
+pub mod overlay;
 pub mod server;
+pub mod simulation;
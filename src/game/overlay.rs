@@ -0,0 +1,220 @@
+/*
+   A debug overlay showing the statistics of the connection to the server,
+   toggled with F3. Every statistic is shown with its current value above a
+   small bar graph of its recent samples, scaled to the largest one.
+*/
+
+use bevy::prelude::*;
+
+use crate::networking::packet_systems::SocketAddress;
+use crate::networking::stats::{ConnectionStats, NetworkStats, StatsSample};
+
+const TOGGLE_KEY: KeyCode = KeyCode::F3;
+const FONT_SIZE: f32 = 16.;
+const BAR_WIDTH: f32 = 3.;
+const GRAPH_HEIGHT: f32 = 32.;
+const BAR_COLOR: Color = Color::rgb(0.4, 0.9, 0.5);
+const BACKGROUND_COLOR: Color = Color::rgba(0., 0., 0., 0.6);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Graph {
+    Rtt,
+    Loss,
+    Resends,
+    Sent,
+    Received,
+}
+
+const GRAPHS: [Graph; 5] = [
+    Graph::Rtt,
+    Graph::Loss,
+    Graph::Resends,
+    Graph::Sent,
+    Graph::Received,
+];
+
+impl Graph {
+    fn value(self, sample: &StatsSample) -> f32 {
+        match self {
+            Graph::Rtt => sample.rtt.map_or(0., |rtt| rtt.as_secs_f32() * 1000.),
+            Graph::Loss => sample.loss,
+            Graph::Resends => sample.resend_rate,
+            Graph::Sent => sample.bytes_sent_per_sec,
+            Graph::Received => sample.bytes_received_per_sec,
+        }
+    }
+
+    fn label(self, stats: Option<&ConnectionStats>) -> String {
+        let Some(stats) = stats else {
+            return format!("{}: -", self.name());
+        };
+        let sample = stats.latest();
+        match self {
+            Graph::Rtt => match stats.rtt {
+                Some(rtt) => format!("rtt: {:.0} ms", rtt.as_secs_f32() * 1000.),
+                None => "rtt: -".to_string(),
+            },
+            Graph::Loss => format!("loss: {:.1}%", sample.loss),
+            Graph::Resends => format!("resends: {:.1}% ({} total)", sample.resend_rate, stats.resends),
+            Graph::Sent => format!(
+                "sent: {:.0} pkt/s, {:.1} KB/s",
+                sample.packets_sent_per_sec,
+                sample.bytes_sent_per_sec / 1024.
+            ),
+            Graph::Received => format!(
+                "recv: {:.0} pkt/s, {:.1} KB/s",
+                sample.packets_received_per_sec,
+                sample.bytes_received_per_sec / 1024.
+            ),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Graph::Rtt => "rtt",
+            Graph::Loss => "loss",
+            Graph::Resends => "resends",
+            Graph::Sent => "sent",
+            Graph::Received => "recv",
+        }
+    }
+}
+
+#[derive(Component)]
+struct NetworkOverlay;
+
+#[derive(Component)]
+struct GraphLabel(Graph);
+
+/// One bar of a graph, the last of which shows the most recent sample.
+#[derive(Component)]
+struct GraphBar {
+    graph: Graph,
+    index: usize,
+}
+
+pub struct NetworkOverlayPlugin;
+
+impl Plugin for NetworkOverlayPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, setup_network_overlay)
+            .add_systems(Update, (toggle_network_overlay, update_network_overlay));
+    }
+}
+
+fn setup_network_overlay(
+    mut commands: Commands,
+    assets: Res<AssetServer>,
+    stats: Res<NetworkStats>,
+) {
+    let text_style = TextStyle {
+        font: assets.load("fira_mono.ttf"),
+        font_size: FONT_SIZE,
+        color: Color::WHITE,
+    };
+    let root = NodeBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(5.0),
+            right: Val::Px(5.0),
+            flex_direction: FlexDirection::Column,
+            padding: UiRect::all(Val::Px(5.0)),
+            ..default()
+        },
+        background_color: BACKGROUND_COLOR.into(),
+        visibility: Visibility::Hidden,
+        ..default()
+    };
+    commands
+        .spawn((root, NetworkOverlay))
+        .with_children(|overlay| {
+            for graph in GRAPHS {
+                overlay.spawn((
+                    TextBundle::from_section("", text_style.clone()),
+                    GraphLabel(graph),
+                ));
+                let row = NodeBundle {
+                    style: Style {
+                        height: Val::Px(GRAPH_HEIGHT),
+                        align_items: AlignItems::FlexEnd,
+                        margin: UiRect::bottom(Val::Px(4.0)),
+                        ..default()
+                    },
+                    ..default()
+                };
+                overlay.spawn(row).with_children(|row| {
+                    for index in 0..stats.history_len {
+                        let bar = NodeBundle {
+                            style: Style {
+                                width: Val::Px(BAR_WIDTH),
+                                height: Val::Percent(0.),
+                                ..default()
+                            },
+                            background_color: BAR_COLOR.into(),
+                            ..default()
+                        };
+                        row.spawn((bar, GraphBar { graph, index }));
+                    }
+                });
+            }
+        });
+}
+
+fn toggle_network_overlay(
+    keys: Res<Input<KeyCode>>,
+    mut overlay: Query<&mut Visibility, With<NetworkOverlay>>,
+) {
+    if !keys.just_pressed(TOGGLE_KEY) {
+        return;
+    }
+    for mut visibility in &mut overlay {
+        *visibility = match *visibility {
+            Visibility::Hidden => Visibility::Inherited,
+            _ => Visibility::Hidden,
+        };
+    }
+}
+
+fn update_network_overlay(
+    stats: Res<NetworkStats>,
+    remote_addr: Res<SocketAddress>,
+    overlay: Query<&Visibility, With<NetworkOverlay>>,
+    mut labels: Query<(&GraphLabel, &mut Text)>,
+    mut bars: Query<(&GraphBar, &mut Style)>,
+) {
+    if overlay
+        .iter()
+        .all(|visibility| *visibility == Visibility::Hidden)
+    {
+        return;
+    }
+    let connection = stats.get(remote_addr.0);
+    for (label, mut text) in &mut labels {
+        text.sections[0].value = label.0.label(connection);
+    }
+
+    let history = connection.map(|connection| &connection.history);
+    // The newest sample goes in the last bar, so a short history is drawn from the right
+    let offset =
+        stats.history_len - history.map_or(0, |history| history.len().min(stats.history_len));
+    // GRAPHS is in declaration order, so a graph's largest value is at its discriminant
+    let maxima = GRAPHS.map(|graph| {
+        history.map_or(0., |history| {
+            history
+                .iter()
+                .map(|sample| graph.value(sample))
+                .fold(0., f32::max)
+        })
+    });
+    for (bar, mut style) in &mut bars {
+        let value = bar
+            .index
+            .checked_sub(offset)
+            .and_then(|index| history?.get(index))
+            .map_or(0., |sample| bar.graph.value(sample));
+        style.height = match maxima[bar.graph as usize] {
+            max if max > 0. => Val::Percent(value / max * 100.),
+            _ => Val::Percent(0.),
+        };
+    }
+}
@@ -1,151 +1,212 @@
 use std::{time::Duration};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
+use std::net::SocketAddr;
+
+use crate::networking::events::DisconnectReason;
 use crate::networking::handshake::server_handshake;
 
-use crate::game::entities::DEFAULT_SPAWN_POINT;
-use crate::networking::components::{NetworkObject, NetworkObjectType};
+use crate::game::entities::{Lifetime, Tagged, DEFAULT_SPAWN_POINT};
+use crate::game::simulation::{simulate_inputs, InputQueue, SimulationPlugin};
+use crate::networking::components::{NetworkObject, NetworkObjectType, ObjectState};
 use crate::networking::message::{serialize, Message};
+use crate::networking::replication::AppReplicationExt;
 
-use crate::networking::resources::{NetworkGame};
+use crate::cli::ServerConfig;
+use crate::networking::fragmentation::DEFAULT_MTU;
+use crate::networking::resources::{NetworkGame, PlayerId};
+use crate::networking::stats::NetworkStats;
 use crate::networking::{NetworkEvent, ServerPlugin, Transport};
 use bevy::log::Level;
 use bevy::time::TimePlugin;
+use bevy::app::AppExit;
 use bevy::{app::ScheduleRunnerPlugin, log::LogPlugin, prelude::*};
 
-const LISTEN_ADDRESS: &str = "127.0.0.1:8080";
-
-
-pub fn main() {
-    info!("Server now listening on {}", LISTEN_ADDRESS);
+pub fn main(config: ServerConfig) {
+    info!("Server now listening on {}", config.listen);
 
-    App::new()
-        // run the server at a reduced tick rate (100 ticks per minute)
-        .add_plugins(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f32(
-            1. / 30.,
-        )))
-        .add_plugins(TimePlugin::default())
+    let mut app = App::new();
+    // Must be inserted before the ServerPlugin to apply to its socket
+    if let Some(conditions) = config.conditions {
+        app.insert_resource(conditions);
+    }
+    app.insert_resource(config.map)
+        // run the server at its tick rate
+        .add_plugins(ScheduleRunnerPlugin::run_loop(config.tick_duration))
+        .add_plugins(TimePlugin)
         .add_plugins(LogPlugin {
             filter: "".to_string(),
             level: Level::INFO,
         })
-        .add_plugins(ServerPlugin(LISTEN_ADDRESS.parse().unwrap()))
-        .add_systems(Update, connection_handler)
+        .add_plugins(ServerPlugin {
+            listen_addr: config.listen.to_string(),
+            mtu: DEFAULT_MTU,
+            compression: true,
+            tick_duration: config.tick_duration,
+        })
+        .add_plugins(ServerGamePlugin)
+        .insert_resource(shutdown_on_ctrl_c())
+        .add_systems(First, exit_on_shutdown)
         .run();
 }
 
+/// Set once the process is asked to stop, which the headless server can't otherwise notice.
+#[derive(Resource)]
+pub struct Shutdown(pub Arc<AtomicBool>);
+
+/// Asks the server to stop on the first Ctrl+C, and gives up on it stopping cleanly on the second.
+fn shutdown_on_ctrl_c() -> Shutdown {
+    let requested = Arc::new(AtomicBool::new(false));
+    let handler_requested = requested.clone();
+    ctrlc::set_handler(move || {
+        if handler_requested.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+    })
+    .expect("could not install the Ctrl+C handler");
+    Shutdown(requested)
+}
+
+/// Exits the app once a shutdown was requested. The rest of the update still runs, which lets
+/// every client be sent a Disconnect before the runner stops.
+pub fn exit_on_shutdown(shutdown: Res<Shutdown>, mut exit: EventWriter<AppExit>) {
+    if shutdown.0.load(Ordering::SeqCst) {
+        info!("Shutting down");
+        exit.send(AppExit);
+    }
+}
+
+/// The game the server runs over the ServerPlugin.
+pub struct ServerGamePlugin;
+
+impl Plugin for ServerGamePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(SimulationPlugin)
+            .replicate::<Tagged>()
+            .init_resource::<LastThrows>()
+            .add_systems(
+                Update,
+                (
+                    connection_handler.before(simulate_inputs),
+                    expire_suspended_players,
+                    expire_items,
+                ),
+            );
+    }
+}
+
+/// Longest name a player can go by; longer names are cut short.
+const MAX_PLAYER_NAME_CHARS: usize = 32;
+
+/// How long a player whose connection dropped is kept around for them to resume their session.
+const SESSION_RESUME_WINDOW_SECS: f32 = 30.;
+
 fn connection_handler(
+    time: Res<Time>,
     mut events: EventReader<NetworkEvent>,
     mut transport: ResMut<Transport>,
     mut network: ResMut<NetworkGame>,
+    mut inputs: Query<(&NetworkObject, &mut InputQueue)>,
+    mut last_throws: ResMut<LastThrows>,
+    mut stats: ResMut<NetworkStats>,
 ) {
     for event in events.iter() {
         match event {
             NetworkEvent::Connected(handle) => {
                 info!("{}: connected!", handle);
-                server_handshake(handle, &mut transport);
+                server_handshake(handle, &mut network.players, time.elapsed(), &mut transport);
             }
-            NetworkEvent::Disconnected(handle) => {
-                info!("{}: disconnected!", handle);
-                let player_id = network
-                    .players
-                    .player_from_socket(*handle)
-                    .expect("Disconnected user not recorded in players");
-
-                let player_objects = network.objects.objects_of_player(player_id);
-                network.players.players.remove(&player_id);
-                for object in player_objects {
-                    network.objects.objects.remove(&object);
-                    for player_addr in network.players.players.values() {
-                        info!("{}: Sending despawn message", player_addr);
-                        transport.send(
-                            *player_addr,
-                            &serialize(Message::Despawn(player_id, object.id)),
-                        )
+            NetworkEvent::Disconnected(handle, reason) => {
+                info!("{}: disconnected ({})!", handle, reason);
+                let player_id = match network.players.player_from_socket(*handle) {
+                    Some(player_id) => player_id,
+                    // never completed the handshake, so nothing was spawned for it
+                    None => {
+                        if let Some(pending) = network.players.pending.remove(handle) {
+                            network.players.release_id(pending, time.elapsed());
+                        }
+                        continue;
                     }
+                };
+
+                network.players.players.remove(&player_id);
+                if *reason == DisconnectReason::Requested {
+                    remove_player(player_id, time.elapsed(), &mut network, &mut transport);
+                } else {
+                    // keep the player's objects around in case the client reconnects
+                    network.suspend(player_id, time.elapsed());
                 }
             }
             NetworkEvent::RawMessage(handle, msg) => match msg {
-                Message::NetworkPosition(player_id, pos, object_id) => {
-                    network.players.for_all_except(*player_id, |addr| {
-                        transport.send(
-                            *addr,
-                            &serialize(Message::NetworkPosition(*player_id, *pos, *object_id)),
-                        );
-                    });
-
-                    let net_obj = network
-                        .objects
-                        .objects
-                        .keys()
-                        .find(|net_obj| net_obj.id == *object_id)
-                        .expect("Invalid id sent by client")
-                        .clone();
-
-                    network.objects.objects.insert(
-                        NetworkObject {
-                            id: net_obj.id,
-                            owner: *player_id,
-                            object_type: net_obj.object_type,
-                            is_owned: false
-                        },
-                        *pos,
-                    );
+                Message::NetworkInput { sequence, .. } => {
+                    // Only trust the sender with moving its own player
+                    let player_id = match network.players.player_from_socket(*handle) {
+                        Some(player_id) => player_id,
+                        None => continue,
+                    };
+                    // Clients send one every frame, so a skipped sequence number is a lost input
+                    stats.record_numbered(*handle, *sequence);
+                    for (object, mut queue) in inputs.iter_mut() {
+                        if object.owner == player_id {
+                            queue.push(*sequence, msg.clone());
+                        }
+                    }
                 }
                 Message::ClientAcknowledgement(player_id) => {
-                    let obj_id = NetworkObject::generate_id();
-
-                    let other_clients_message = Message::Spawn(
-                        *player_id,
-                        Vec3 {
-                            x: 1f32,
-                            y: 1f32,
-                            z: 1f32,
-                        },
-                        NetworkObjectType::Player,
-                        obj_id,
-                    );
-
-                    for player_addr in network.players.players.values() {
-                        transport.send(*player_addr, &serialize(other_clients_message));
+                    if network.players.pending.get(handle) != Some(player_id) {
+                        warn!("{}: acknowledged an id it was not assigned: {:?}", handle, player_id);
+                        continue;
                     }
-
-                    network.players.add_player(*player_id, *handle);
-
-                    let message = Message::Spawn(
-                        *player_id,
-                        DEFAULT_SPAWN_POINT,
-                        NetworkObjectType::Player,
-                        obj_id,
-                    );
-
-                    for (network_obj, value) in network.objects.objects.iter() {
-                        transport.send(
-                            *handle,
-                            &serialize(Message::Spawn(
-                                network_obj.owner,
-                                *value,
-                                network_obj.object_type,
-                                network_obj.id,
-                            )),
-                        )
+                    network.players.pending.remove(handle);
+                    join_player(*handle, *player_id, time.elapsed(), &mut network, &mut transport);
+                }
+                Message::ResumeSession {
+                    previous,
+                    session_token,
+                    assigned,
+                } => {
+                    if network.players.pending.get(handle) != Some(assigned) {
+                        warn!("{}: acknowledged an id it was not assigned: {:?}", handle, assigned);
+                        continue;
                     }
+                    network.players.pending.remove(handle);
 
-                    network.objects.objects.insert(
-                        NetworkObject {
-                            id: obj_id,
-                            owner: *player_id,
-                            object_type: NetworkObjectType::Player,
-                            is_owned: false
-                        },
-                        Vec3 {
-                            x: 1f32,
-                            y: 1f32,
-                            z: 1f32,
-                        },
-                    );
-
-                    transport.send(*handle, &serialize(message));
+                    if let Some(new_token) = network.resume(*previous, *session_token) {
+                        info!("{}: resuming session of player {:?}", handle, previous);
+                        network.players.release_id(*assigned, time.elapsed());
+                        resume_player(*handle, *previous, new_token, &mut network, &mut transport);
+                        // The reconnected client numbers its inputs from the start again
+                        for (object, mut queue) in inputs.iter_mut() {
+                            if object.owner == *previous {
+                                queue.restart();
+                            }
+                        }
+                    } else {
+                        join_player(*handle, *assigned, time.elapsed(), &mut network, &mut transport);
+                    }
+                }
+                Message::PlayerName(name) => {
+                    if let Some(player_id) = network.players.player_from_socket(*handle) {
+                        let name = sanitize_player_name(name);
+                        info!("{}: player {:?} is called {:?}", handle, player_id, name);
+                        network.players.names.insert(player_id, name);
+                    }
+                }
+                Message::Throw => {
+                    if let Some(player_id) = network.players.player_from_socket(*handle) {
+                        throw_item(
+                            player_id,
+                            time.elapsed(),
+                            &mut last_throws,
+                            &mut network,
+                            &mut transport,
+                        );
+                    }
                 }
+                // Answered by the ServerPlugin
+                Message::Ping(_) => (),
                 _ => info!("{} sent a message: {:?}", handle, msg),
             },
             NetworkEvent::SendError(err, msg) => {
@@ -157,6 +218,257 @@ fn connection_handler(
             NetworkEvent::RecvError(err) => {
                 error!("NetworkEvent::RecvError: {:?}", err);
             }
+            NetworkEvent::DeliveryFailed(msg) => {
+                error!(
+                    "NetworkEvent::DeliveryFailed (payload [{:?}]) to {}",
+                    msg.payload, msg.destination
+                );
+            }
+            NetworkEvent::MalformedPacket(addr, len) => {
+                warn!("NetworkEvent::MalformedPacket ({} bytes) from {}", len, addr);
+            }
+            NetworkEvent::AuthenticationFailed(addr, len) => {
+                warn!("NetworkEvent::AuthenticationFailed ({} bytes) from {}", len, addr);
+            }
         }
     }
 }
+
+/// Strips control characters and surrounding whitespace from a name a client sent, and cuts it to
+/// MAX_PLAYER_NAME_CHARS.
+fn sanitize_player_name(name: &str) -> String {
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    name.trim().chars().take(MAX_PLAYER_NAME_CHARS).collect()
+}
+
+/// Spawns a fresh player for a client that has completed the handshake, and sends it every
+/// existing object.
+fn join_player(
+    handle: SocketAddr,
+    player_id: PlayerId,
+    now: Duration,
+    network: &mut NetworkGame,
+    transport: &mut Transport,
+) {
+    let obj_id = match network.objects.ids.allocate(now) {
+        Some(obj_id) => obj_id,
+        None => {
+            error!("{}: no object ids left to spawn player {:?} with", handle, player_id);
+            network.players.add_player(player_id, handle);
+            send_world(handle, network, transport);
+            return;
+        }
+    };
+
+    let other_clients_message = serialize(Message::Spawn(
+        player_id,
+        DEFAULT_SPAWN_POINT,
+        NetworkObjectType::Player,
+        obj_id,
+    ));
+
+    for player_addr in network.players.players.values() {
+        transport.send_reliable(*player_addr, &other_clients_message);
+    }
+
+    network.players.add_player(player_id, handle);
+
+    let message = Message::Spawn(
+        player_id,
+        DEFAULT_SPAWN_POINT,
+        NetworkObjectType::Player,
+        obj_id,
+    );
+
+    send_world(handle, network, transport);
+
+    network.objects.objects.insert(
+        NetworkObject {
+            id: obj_id,
+            owner: player_id,
+            object_type: NetworkObjectType::Player,
+            is_owned: false
+        },
+        ObjectState {
+            position: DEFAULT_SPAWN_POINT,
+            ..default()
+        },
+    );
+
+    transport.send_reliable(handle, &serialize(message));
+}
+
+/// Hands a reconnected client back its previous player, whose objects were kept while it was
+/// away, along with the token of its new session, and sends it every object including its own.
+fn resume_player(
+    handle: SocketAddr,
+    player_id: PlayerId,
+    session_token: u64,
+    network: &mut NetworkGame,
+    transport: &mut Transport,
+) {
+    network.players.add_player(player_id, handle);
+    transport.send_reliable(handle, &serialize(Message::SessionResumed(player_id, session_token)));
+    send_world(handle, network, transport);
+}
+
+fn send_world(handle: SocketAddr, network: &NetworkGame, transport: &mut Transport) {
+    for (network_obj, value) in network.objects.objects.iter() {
+        transport.send_reliable(
+            handle,
+            &serialize(Message::Spawn(
+                network_obj.owner,
+                value.position,
+                network_obj.object_type,
+                network_obj.id,
+            )),
+        )
+    }
+}
+
+/// Removes every object owned by a player that has left for good and tells everyone else to
+/// despawn them. The player's ids are released for reuse.
+fn remove_player(
+    player_id: PlayerId,
+    now: Duration,
+    network: &mut NetworkGame,
+    transport: &mut Transport,
+) {
+    network.players.release_id(player_id, now);
+    network.players.names.remove(&player_id);
+    let player_objects = network.objects.objects_of_player(player_id);
+    for object in player_objects {
+        despawn_object(object, now, network, transport);
+    }
+}
+
+/// Removes an object, releases its id for reuse and tells every player to despawn it.
+fn despawn_object(object: NetworkObject, now: Duration, network: &mut NetworkGame, transport: &mut Transport) {
+    network.objects.objects.remove(&object);
+    network.objects.ids.release(object.id, now);
+    for player_addr in network.players.players.values() {
+        info!("{}: Sending despawn message", player_addr);
+        transport.send_reliable(
+            *player_addr,
+            &serialize(Message::Despawn(object.owner, object.id)),
+        )
+    }
+}
+
+/// Speed a thrown item leaves the player's hand at.
+const THROW_SPEED: f32 = 15.;
+/// How long a player has to wait between throws.
+const THROW_COOLDOWN_SECS: f32 = 0.5;
+/// How many of a player's thrown items can be around at once.
+pub const MAX_LIVE_ITEMS_PER_PLAYER: usize = 5;
+
+/// When each player last threw an item.
+#[derive(Resource, Default, Debug)]
+struct LastThrows(HashMap<PlayerId, Duration>);
+
+/// Throws an item in the direction the player is looking. The item is simulated by the server
+/// like the player's own body, and owned by the player. Throws are ignored during the player's
+/// cooldown and while too many of their items are still around, so a client can't flood the
+/// world with items.
+fn throw_item(
+    player_id: PlayerId,
+    now: Duration,
+    last_throws: &mut LastThrows,
+    network: &mut NetworkGame,
+    transport: &mut Transport,
+) {
+    let cooldown = Duration::from_secs_f32(THROW_COOLDOWN_SECS);
+    if let Some(last_throw) = last_throws.0.get(&player_id) {
+        if now.saturating_sub(*last_throw) < cooldown {
+            return;
+        }
+    }
+    let live_items = network
+        .objects
+        .objects
+        .keys()
+        .filter(|object| object.owner == player_id && object.object_type == NetworkObjectType::Item)
+        .count();
+    if live_items >= MAX_LIVE_ITEMS_PER_PLAYER {
+        debug!("Player {:?} already has {} items out", player_id, live_items);
+        return;
+    }
+    let thrower = network
+        .objects
+        .objects
+        .iter()
+        .find(|(object, _)| object.owner == player_id && object.object_type == NetworkObjectType::Player)
+        .map(|(_, state)| *state);
+    let thrower = match thrower {
+        Some(thrower) => thrower,
+        None => return,
+    };
+    let obj_id = match network.objects.ids.allocate(now) {
+        Some(obj_id) => obj_id,
+        None => {
+            warn!("No object ids left for player {:?} to throw an item with", player_id);
+            return;
+        }
+    };
+
+    let direction = Quat::from_euler(EulerRot::YXZ, thrower.yaw, thrower.pitch, 0.) * -Vec3::Z;
+    let state = ObjectState {
+        position: thrower.position + Vec3::Y * 1.5 + direction,
+        velocity: direction * THROW_SPEED,
+        ..default()
+    };
+    network.objects.objects.insert(
+        NetworkObject {
+            id: obj_id,
+            owner: player_id,
+            object_type: NetworkObjectType::Item,
+            is_owned: false
+        },
+        state,
+    );
+
+    last_throws.0.insert(player_id, now);
+
+    let message = serialize(Message::Spawn(player_id, state.position, NetworkObjectType::Item, obj_id));
+    for player_addr in network.players.players.values() {
+        transport.send_reliable(*player_addr, &message);
+    }
+}
+
+/// Removes thrown items once they have been around for long enough.
+fn expire_items(
+    time: Res<Time>,
+    mut items: Query<(&NetworkObject, &mut Lifetime)>,
+    mut transport: ResMut<Transport>,
+    mut network: ResMut<NetworkGame>,
+) {
+    for (object, mut lifetime) in items.iter_mut() {
+        if lifetime.0.tick(time.delta()).just_finished() {
+            let recorded = network.objects.get_mut(object.owner, object.id).map(|(object, _)| *object);
+            if let Some(recorded) = recorded {
+                despawn_object(recorded, time.elapsed(), &mut network, &mut transport);
+            }
+        }
+    }
+}
+
+/// Gives up on players that did not resume their session in time.
+fn expire_suspended_players(
+    time: Res<Time>,
+    mut transport: ResMut<Transport>,
+    mut network: ResMut<NetworkGame>,
+) {
+    let window = Duration::from_secs_f32(SESSION_RESUME_WINDOW_SECS);
+    let expired: Vec<PlayerId> = network
+        .suspended
+        .iter()
+        .filter(|(_, suspended)| time.elapsed() - suspended.since > window)
+        .map(|(player_id, _)| *player_id)
+        .collect();
+
+    for player_id in expired {
+        info!("Player {:?} did not reconnect in time", player_id);
+        network.suspended.remove(&player_id);
+        remove_player(player_id, time.elapsed(), &mut network, &mut transport);
+    }
+}
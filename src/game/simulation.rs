@@ -0,0 +1,534 @@
+/*
+   Server side movement. The server owns a physics body for every player and
+   moves it with the same FpsController the client uses, driven by the inputs
+   the client sends rather than by a keyboard and mouse. Rapier runs headlessly
+   against the playground colliders, and the resulting states are recorded in
+   NetworkGame for the world snapshots sent to every client. Each client is
+   also sent the full state of its own player along with the sequence number
+   of the last input applied to it, so that it can check its prediction.
+
+   Clients send an input every frame at whatever frame rate they run, so
+   inputs are queued per player and each one is applied in sequence order for
+   the frame time it was sent with, like the client applied it. The distance a
+   player's inputs cover during a tick is then handed to Rapier as the body's
+   velocity for the step, so collisions still stop it, and the controller's
+   own velocity is put back afterwards for the next input. A player is only
+   moved for as much time as has passed on the server, so neither long frames
+   nor extra inputs make it any faster.
+*/
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use bevy::asset::AssetPlugin;
+use bevy::core::{TaskPoolPlugin, TypeRegistrationPlugin};
+use bevy::ecs::system::BoxedSystem;
+use bevy::gltf::GltfPlugin;
+use bevy::pbr::StandardMaterial;
+use bevy::prelude::*;
+use bevy::render::mesh::MeshPlugin;
+use bevy::scene::ScenePlugin;
+use bevy_fps_controller::controller::{
+    fps_controller_look, fps_controller_move, FpsController, FpsControllerInput,
+};
+use bevy_rapier3d::prelude::{
+    NoUserData, PhysicsSet, RapierConfiguration, RapierPhysicsPlugin, TimestepMode, Velocity,
+};
+
+use crate::game::entities::{spawn_simulated_item, spawn_simulated_player};
+use crate::networking::clock::ServerTick;
+use crate::networking::components::{NetworkObject, NetworkObjectType, ObjectState};
+use crate::networking::message::{serialize, Message};
+use crate::networking::resources::NetworkGame;
+use crate::networking::world_snapshot::send_world_snapshots;
+use crate::networking::Transport;
+use crate::{respawn, scene_colliders, MainScene, Map};
+
+/// How many inputs are kept for a player before the oldest is dropped.
+const MAX_PENDING_INPUTS: usize = 64;
+/// The longest a single input is applied for, however long the client's frame was.
+const MAX_INPUT_DT: Duration = Duration::from_millis(100);
+/// How much unused time a player may build up for catching up on inputs that arrived late.
+const MAX_INPUT_BUDGET: Duration = Duration::from_millis(250);
+/// The longest physics step, so a stalled server doesn't tunnel bodies through walls.
+const MAX_PHYSICS_DT: f32 = 0.1;
+
+pub struct SimulationPlugin;
+
+/// The inputs received for a simulated player that have not been applied yet.
+#[derive(Component, Default, Debug)]
+pub struct InputQueue {
+    // Sequence number of the latest input applied
+    pub applied: u32,
+    pending: BTreeMap<u32, Message>,
+    // How much time the player may still be moved for
+    budget: Duration,
+    // The controller's velocity, kept while the body is stepped at `stepped` instead
+    velocity: Vec3,
+    stepped: Vec3,
+}
+
+impl InputQueue {
+    /// Queues an input, unless one after it has already been applied.
+    pub fn push(&mut self, sequence: u32, input: Message) {
+        if sequence <= self.applied {
+            return;
+        }
+        self.pending.insert(sequence, input);
+        if self.pending.len() > MAX_PENDING_INPUTS {
+            self.pending.pop_first();
+        }
+    }
+
+    /// Forgets every input, for a client whose sequence numbers have started over.
+    pub fn restart(&mut self) {
+        self.applied = 0;
+        self.pending.clear();
+    }
+
+    /// Makes another tick's worth of time available for applying inputs.
+    fn add_time(&mut self, dt: Duration) {
+        self.budget = (self.budget + dt).min(MAX_INPUT_BUDGET);
+    }
+
+    /// Takes the oldest pending input if there is enough time left to apply it, along with how
+    /// long to apply it for.
+    fn pop_ready(&mut self) -> Option<(Message, Duration)> {
+        let (&sequence, input) = self.pending.first_key_value()?;
+        let dt = match input {
+            Message::NetworkInput { dt, .. } => (*dt).min(MAX_INPUT_DT),
+            _ => Duration::ZERO,
+        };
+        if dt > self.budget {
+            return None;
+        }
+        self.budget -= dt;
+        self.applied = sequence;
+        self.pending.remove(&sequence).map(|input| (input, dt))
+    }
+}
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        // Just enough of bevy to load the playground's meshes without a renderer
+        app.add_plugins((
+            TaskPoolPlugin::default(),
+            TypeRegistrationPlugin,
+            TransformPlugin,
+            HierarchyPlugin,
+            AssetPlugin::default(),
+            MeshPlugin,
+            ScenePlugin,
+        ))
+        .add_asset::<Image>()
+        .add_asset::<StandardMaterial>()
+        .add_asset::<AnimationClip>()
+        .add_plugins(GltfPlugin::default())
+        .add_plugins(RapierPhysicsPlugin::<NoUserData>::default())
+        // Rapier's default caps a step at 1/60s, less than a tick, which would lose movement
+        .insert_resource(RapierConfiguration {
+            timestep_mode: TimestepMode::Variable {
+                max_dt: MAX_PHYSICS_DT,
+                time_scale: 1.,
+                substeps: 1,
+            },
+            ..default()
+        })
+        .init_resource::<Map>()
+        .add_systems(Startup, load_scene)
+        .add_systems(
+            Update,
+            (
+                scene_colliders,
+                sync_simulated_players,
+                respawn.before(simulate_inputs),
+                simulate_inputs,
+                // Sent before the next inputs are applied, so the position is the one physics
+                // moved the player to for the inputs up to the sequence number sent with it
+                record_object_states
+                    .before(simulate_inputs)
+                    .before(send_world_snapshots),
+            ),
+        )
+        .add_systems(
+            PostUpdate,
+            restore_controller_velocities.after(PhysicsSet::Writeback),
+        );
+    }
+}
+
+fn load_scene(mut commands: Commands, assets: Res<AssetServer>, map: Res<Map>) {
+    commands.insert_resource(MainScene {
+        handle: assets.load(&map.0),
+        is_loaded: false,
+        spawn_scene: false,
+    });
+}
+
+/// Overwrites a player's controller input with one its client sent. Returns false,
+/// leaving the input as it was, if the message is not an input or looks in no real direction.
+pub fn apply_network_input(message: &Message, input: &mut FpsControllerInput) -> bool {
+    if let Message::NetworkInput {
+        sequence: _,
+        dt: _,
+        w,
+        s,
+        a,
+        d,
+        jump,
+        crouch,
+        sprint,
+        yaw,
+        pitch,
+    } = *message
+    {
+        // NaN or infinite angles would end up in the physics body
+        if !yaw.is_finite() || !pitch.is_finite() {
+            return false;
+        }
+        let axis = |positive: bool, negative: bool| positive as i8 as f32 - negative as i8 as f32;
+        input.movement = Vec3::new(axis(d, a), 0., axis(w, s));
+        input.jump = jump;
+        input.crouch = crouch;
+        input.sprint = sprint;
+        // Clients can't be trusted to keep their pitch in range
+        input.pitch = pitch.clamp(-std::f32::consts::FRAC_PI_2, std::f32::consts::FRAC_PI_2);
+        input.yaw = yaw;
+        return true;
+    }
+    false
+}
+
+/// Applies every queued input of every player in sequence order, each for its own frame time.
+/// The controller systems move every entity they find, so each player's controller is put back
+/// on its own while its inputs are applied.
+pub fn simulate_inputs(
+    world: &mut World,
+    mut systems: Local<Option<(BoxedSystem, BoxedSystem)>>,
+) {
+    let (look_system, move_system) = systems.get_or_insert_with(|| {
+        let mut look: BoxedSystem = Box::new(IntoSystem::into_system(fps_controller_look));
+        let mut movement: BoxedSystem = Box::new(IntoSystem::into_system(fps_controller_move));
+        look.initialize(world);
+        movement.initialize(world);
+        (look, movement)
+    });
+
+    let time = world.resource::<Time>().clone();
+    let physics_dt = match world.resource::<RapierConfiguration>().timestep_mode {
+        TimestepMode::Variable {
+            max_dt, time_scale, ..
+        } => (time.delta_seconds() * time_scale).min(max_dt),
+        TimestepMode::Fixed { dt, .. } | TimestepMode::Interpolated { dt, .. } => dt,
+    };
+
+    let players: Vec<Entity> = world
+        .query_filtered::<Entity, (With<InputQueue>, With<FpsController>)>()
+        .iter(world)
+        .collect();
+    let controllers: Vec<(Entity, FpsController)> = players
+        .into_iter()
+        .filter_map(|player| Some((player, world.entity_mut(player).take::<FpsController>()?)))
+        .collect();
+
+    let mut simulated = Vec::with_capacity(controllers.len());
+    for (player, controller) in controllers {
+        world.entity_mut(player).insert(controller);
+        let mut queue = std::mem::take(world.get_mut::<InputQueue>(player).unwrap().as_mut());
+        queue.add_time(time.delta());
+
+        let mut distance = Vec3::ZERO;
+        while let Some((input, dt)) = queue.pop_ready() {
+            if !apply_network_input(&input, &mut world.get_mut::<FpsControllerInput>(player).unwrap()) {
+                continue;
+            }
+            // Run the controller as if the client's frame were happening on the server
+            let mut input_time = time.clone();
+            input_time.update_with_instant(time.last_update().unwrap_or(time.startup()) + dt);
+            world.insert_resource(input_time);
+            look_system.run((), world);
+            move_system.run((), world);
+            distance += world.get::<Velocity>(player).unwrap().linvel * dt.as_secs_f32();
+        }
+        world.insert_resource(time.clone());
+
+        let mut velocity = world.get_mut::<Velocity>(player).unwrap();
+        queue.velocity = velocity.linvel;
+        queue.stepped = if physics_dt > 0. { distance / physics_dt } else { Vec3::ZERO };
+        velocity.linvel = queue.stepped;
+        *world.get_mut::<InputQueue>(player).unwrap() = queue;
+
+        simulated.push((player, world.entity_mut(player).take::<FpsController>().unwrap()));
+    }
+    for (player, controller) in simulated {
+        world.entity_mut(player).insert(controller);
+    }
+}
+
+/// Gives each player back the velocity its controller left it with, plus whatever physics changed
+/// about the velocity it was stepped at, such as being stopped by a wall.
+fn restore_controller_velocities(mut players: Query<(&mut Velocity, &InputQueue)>) {
+    for (mut velocity, queue) in players.iter_mut() {
+        velocity.linvel = queue.velocity + (velocity.linvel - queue.stepped);
+    }
+}
+
+/// Spawns a body for every object the server knows of, and despawns the bodies of objects that
+/// have been removed.
+fn sync_simulated_players(
+    mut commands: Commands,
+    network: Res<NetworkGame>,
+    bodies: Query<(Entity, &NetworkObject)>,
+) {
+    let same_object =
+        |a: &NetworkObject, b: &NetworkObject| a.owner == b.owner && a.id == b.id;
+    for (entity, body) in bodies.iter() {
+        if !network.objects.objects.keys().any(|object| same_object(object, body)) {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    for (object, state) in network.objects.objects.iter() {
+        if bodies.iter().any(|(_, body)| same_object(object, body)) {
+            continue;
+        }
+        match object.object_type {
+            NetworkObjectType::Player => {
+                spawn_simulated_player(object.owner, object.id, state.position, &mut commands)
+            }
+            NetworkObjectType::Item => spawn_simulated_item(
+                object.owner,
+                object.id,
+                state.position,
+                state.velocity,
+                &mut commands,
+            ),
+        }
+    }
+}
+
+/// Records the simulated state of each object for the next world snapshot, and so newly joining
+/// clients spawn objects where they currently are. The owner of a player predicts it, so is also
+/// sent the state to check its prediction against.
+#[allow(clippy::type_complexity)]
+fn record_object_states(
+    bodies: Query<(
+        &NetworkObject,
+        &Transform,
+        &Velocity,
+        Option<&FpsControllerInput>,
+        Option<&InputQueue>,
+    )>,
+    tick: Res<ServerTick>,
+    mut network: ResMut<NetworkGame>,
+    mut transport: ResMut<Transport>,
+) {
+    for (body, transform, velocity, input, queue) in bodies.iter() {
+        let (yaw, pitch) = input.map_or((0., 0.), |input| (input.yaw, input.pitch));
+        let state = ObjectState {
+            position: transform.translation,
+            velocity: velocity.linvel,
+            yaw,
+            pitch,
+        };
+        match network.objects.get_mut(body.owner, body.id) {
+            Some((_, recorded)) => *recorded = state,
+            None => continue,
+        }
+
+        let owner_addr = network.players.players.get(&body.owner).copied();
+        if let (Some(owner_addr), Some(queue)) = (owner_addr, queue) {
+            transport.send(
+                owner_addr,
+                &serialize(Message::PlayerState {
+                    sequence: queue.applied,
+                    position: state.position,
+                    velocity: state.velocity,
+                    tick: tick.tick,
+                }),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::time::TimeUpdateStrategy;
+
+    use crate::networking::resources::PlayerId;
+
+    use super::*;
+
+    #[test]
+    fn test_apply_network_input() {
+        let mut input = FpsControllerInput::default();
+        let message = Message::NetworkInput {
+            sequence: 1,
+            dt: Duration::from_millis(16),
+            w: true,
+            s: false,
+            a: true,
+            d: true,
+            jump: true,
+            crouch: false,
+            sprint: false,
+            yaw: 1.,
+            pitch: 10.,
+        };
+
+        assert!(apply_network_input(&message, &mut input));
+        assert_eq!(input.movement, Vec3::new(0., 0., 1.));
+        assert!(input.jump);
+        assert_eq!(input.yaw, 1.);
+        assert_eq!(input.pitch, std::f32::consts::FRAC_PI_2);
+    }
+
+    #[test]
+    fn test_drops_inputs_looking_nowhere() {
+        let input = |yaw: f32, pitch: f32| Message::NetworkInput {
+            sequence: 1,
+            dt: Duration::from_millis(16),
+            w: true,
+            s: false,
+            a: false,
+            d: false,
+            jump: false,
+            crouch: false,
+            sprint: false,
+            yaw,
+            pitch,
+        };
+
+        let mut applied = FpsControllerInput::default();
+        for message in [
+            input(f32::NAN, 0.),
+            input(0., f32::NAN),
+            input(f32::INFINITY, 0.),
+            input(0., f32::NEG_INFINITY),
+        ] {
+            assert!(!apply_network_input(&message, &mut applied));
+        }
+        assert_eq!(applied.movement, Vec3::ZERO);
+        assert_eq!((applied.yaw, applied.pitch), (0., 0.));
+    }
+
+    #[test]
+    fn test_queued_inputs_are_applied_in_order() {
+        let mut queue = InputQueue::default();
+        for sequence in [3, 1, 2] {
+            queue.push(sequence, walk(sequence, 10));
+        }
+        queue.add_time(Duration::from_millis(50));
+
+        let mut sequences = Vec::new();
+        while let Some((Message::NetworkInput { sequence, .. }, _)) = queue.pop_ready() {
+            sequences.push(sequence);
+        }
+        assert_eq!(sequences, vec![1, 2, 3]);
+        assert_eq!(queue.applied, 3);
+
+        // Arrived after a later input was already applied
+        queue.push(2, walk(2, 10));
+        assert!(queue.pending.is_empty());
+    }
+
+    #[test]
+    fn test_inputs_wait_for_time_to_apply_them() {
+        let mut queue = InputQueue::default();
+        queue.push(1, walk(1, 30));
+        queue.push(2, walk(2, 30));
+        queue.push(3, walk(3, 1000));
+
+        queue.add_time(Duration::from_millis(50));
+        assert_eq!(queue.pop_ready().map(|(_, dt)| dt), Some(Duration::from_millis(30)));
+        assert_eq!(queue.pop_ready(), None);
+        assert_eq!(queue.applied, 1);
+
+        // However long the client's frame claims to have been, the input only counts for so long
+        queue.add_time(Duration::from_secs(1));
+        assert_eq!(queue.budget, MAX_INPUT_BUDGET);
+        assert_eq!(queue.pop_ready().map(|(_, dt)| dt), Some(Duration::from_millis(30)));
+        assert_eq!(queue.pop_ready().map(|(_, dt)| dt), Some(MAX_INPUT_DT));
+        assert_eq!(queue.applied, 3);
+    }
+
+    #[test]
+    fn test_pending_inputs_are_bounded() {
+        let mut queue = InputQueue::default();
+        for sequence in 1..=MAX_PENDING_INPUTS as u32 + 10 {
+            queue.push(sequence, walk(sequence, 10));
+        }
+        assert_eq!(queue.pending.len(), MAX_PENDING_INPUTS);
+        assert_eq!(queue.pending.keys().next(), Some(&11));
+
+        queue.restart();
+        queue.push(1, walk(1, 10));
+        assert_eq!(queue.pending.len(), 1);
+    }
+
+    #[test]
+    fn test_every_input_is_simulated_for_its_own_frame_time() {
+        let tick = Duration::from_millis(50);
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, TransformPlugin, HierarchyPlugin))
+            .add_plugins((AssetPlugin::default(), MeshPlugin, ScenePlugin))
+            .add_asset::<StandardMaterial>()
+            .add_plugins(RapierPhysicsPlugin::<NoUserData>::default())
+            .insert_resource(TimeUpdateStrategy::ManualDuration(tick))
+            .add_systems(Startup, |mut commands: Commands| {
+                spawn_simulated_player(PlayerId(1), 1, Vec3::ZERO, &mut commands)
+            })
+            .add_systems(Update, simulate_inputs)
+            .add_systems(
+                PostUpdate,
+                restore_controller_velocities.after(PhysicsSet::Writeback),
+            );
+        app.update();
+
+        let player = app
+            .world
+            .query_filtered::<Entity, With<InputQueue>>()
+            .single(&app.world);
+        let start = app.world.get::<Transform>(player).unwrap().translation;
+        {
+            let mut queue = app.world.get_mut::<InputQueue>(player).unwrap();
+            for sequence in 1..=3 {
+                queue.push(sequence, walk(sequence, 10));
+            }
+            queue.push(4, walk(4, 40));
+        }
+        app.update();
+
+        // Three frames of 10ms fit in the tick, the fourth has to wait for the next one
+        let queue = app.world.get::<InputQueue>(player).unwrap();
+        assert_eq!(queue.applied, 3);
+        assert_eq!(queue.pending.len(), 1);
+        let moved = app.world.get::<Transform>(player).unwrap().translation - start;
+        assert!(Vec2::new(moved.x, moved.z).length() > 0.);
+        // Back to the controller's own velocity once physics has stepped the body
+        assert_eq!(
+            app.world.get::<Velocity>(player).unwrap().linvel,
+            queue.velocity
+        );
+
+        app.update();
+        assert_eq!(app.world.get::<InputQueue>(player).unwrap().applied, 4);
+    }
+
+    // An input walking forward, sent with a frame of `dt_millis`
+    fn walk(sequence: u32, dt_millis: u64) -> Message {
+        Message::NetworkInput {
+            sequence,
+            dt: Duration::from_millis(dt_millis),
+            w: true,
+            s: false,
+            a: false,
+            d: false,
+            jump: false,
+            crouch: false,
+            sprint: false,
+            yaw: 0.,
+            pitch: 0.,
+        }
+    }
+}
@@ -1,9 +1,9 @@
 extern crate core;
 
+mod cli;
 mod game;
 mod networking;
 
-use std::env;
 use std::f32::consts::TAU;
 
 use bevy::{
@@ -15,32 +15,43 @@ use bevy::{
 };
 use bevy_rapier3d::prelude::*;
 
+use clap::Parser;
+
+use crate::cli::{Cli, Config};
 use crate::game::client::main as client_app;
 use crate::game::server::main as server_app;
+use crate::networking::handshake::ConnectionStatus;
 
 use bevy_fps_controller::controller::*;
 
 const SPAWN_POINT: Vec3 = Vec3::new(0.0, 1.0, 0.0);
+/// Scene the level is loaded from unless another map is given.
+const DEFAULT_MAP: &str = "playground.glb";
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let network_flag_maybe = args.get(1);
-    let network_addr_maybe = args.get(2);
-
-    let network_flag = network_flag_maybe.unwrap_or(&"0".to_string()).to_string();
-    let network_addr = network_addr_maybe
-        .unwrap_or(&"127.0.0.1:8082".to_string())
-        .to_string();
-
-    if network_flag == "1" {
-        println!("Attempting to start game server");
-        server_app();
-    } else {
-        client_app(network_addr);
+    let config = match Cli::parse().resolve() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    };
+
+    match config {
+        Config::Server(config) => {
+            println!("Attempting to start game server");
+            server_app(config);
+        }
+        Config::Client(config) => client_app(config),
     }
 }
 
-fn setup(mut commands: Commands, mut window: Query<&mut Window>, assets: Res<AssetServer>) {
+fn setup(
+    mut commands: Commands,
+    mut window: Query<&mut Window>,
+    assets: Res<AssetServer>,
+    map: Res<Map>,
+) {
     let mut window = window.single_mut();
     window.title = String::from("Minimal FPS Controller Example");
     // commands.spawn(Window { title: "Minimal FPS Controller Example".to_string(), ..default() });
@@ -72,28 +83,37 @@ fn setup(mut commands: Commands, mut window: Query<&mut Window>, assets: Res<Ass
     ));
 
     commands.insert_resource(MainScene {
-        handle: assets.load("playground.glb"),
+        handle: assets.load(&map.0),
         is_loaded: false,
+        spawn_scene: true,
     });
 
-    commands.spawn(
-        TextBundle::from_section(
-            "",
-            TextStyle {
-                font: assets.load("fira_mono.ttf"),
-                font_size: 24.0,
-                color: Color::BLACK,
-            },
-        )
+    let text_style = TextStyle {
+        font: assets.load("fira_mono.ttf"),
+        font_size: 24.0,
+        color: Color::BLACK,
+    };
+    commands.spawn((
+        HudText,
+        // The player's movement, then the connection status
+        TextBundle::from_sections([
+            TextSection::new("", text_style.clone()),
+            TextSection::new("", text_style),
+        ])
         .with_style(Style {
             position_type: PositionType::Absolute,
             top: Val::Px(5.0),
             left: Val::Px(5.0),
             ..default()
         }),
-    );
+    ));
 }
 
+/// The text in the corner of the screen showing the local player's movement, as opposed to the
+/// network overlay's labels.
+#[derive(Component)]
+struct HudText;
+
 fn respawn(mut query: Query<(&mut Transform, &mut Velocity)>) {
     for (mut transform, mut velocity) in &mut query {
         if transform.translation.y > -50.0 {
@@ -105,10 +125,22 @@ fn respawn(mut query: Query<(&mut Transform, &mut Velocity)>) {
     }
 }
 
+/// The glTF scene under assets/ that the level is loaded from.
+#[derive(Resource, Clone, Debug)]
+pub struct Map(pub String);
+
+impl Default for Map {
+    fn default() -> Self {
+        Self(DEFAULT_MAP.to_string())
+    }
+}
+
 #[derive(Resource)]
 struct MainScene {
     handle: Handle<Gltf>,
     is_loaded: bool,
+    // Whether the visible scene is spawned along with its colliders; the server only needs colliders
+    spawn_scene: bool,
 }
 
 fn scene_colliders(
@@ -126,10 +158,12 @@ fn scene_colliders(
     let gltf = gltf_assets.get(&main_scene.handle);
 
     if let Some(gltf) = gltf {
-        let scene = gltf.scenes.first().unwrap().clone();
-        commands.spawn(SceneBundle { scene, ..default() });
+        if main_scene.spawn_scene {
+            let scene = gltf.scenes.first().unwrap().clone();
+            commands.spawn(SceneBundle { scene, ..default() });
+        }
         for node in &gltf.nodes {
-            let node = gltf_node_assets.get(&node).unwrap();
+            let node = gltf_node_assets.get(node).unwrap();
             if let Some(gltf_mesh) = node.mesh.clone() {
                 let gltf_mesh = gltf_mesh_assets.get(&gltf_mesh).unwrap();
                 for mesh_primitive in &gltf_mesh.primitives {
@@ -170,8 +204,8 @@ fn manage_cursor(
 }
 
 fn display_text(
-    mut controller_query: Query<(&Transform, &Velocity)>,
-    mut text_query: Query<&mut Text>,
+    mut controller_query: Query<(&Transform, &Velocity), With<LogicalPlayer>>,
+    mut text_query: Query<&mut Text, With<HudText>>,
 ) {
     for (transform, velocity) in &mut controller_query {
         for mut text in &mut text_query {
@@ -188,3 +222,23 @@ fn display_text(
         }
     }
 }
+
+fn display_connection_status(
+    connection_status: Res<ConnectionStatus>,
+    mut text_query: Query<&mut Text, With<HudText>>,
+) {
+    if !connection_status.is_changed() {
+        return;
+    }
+    // Kept in its own section so the movement written every frame doesn't hide it
+    let status = match *connection_status {
+        ConnectionStatus::Rejected(reason) => format!("\nConnection rejected: {}", reason),
+        ConnectionStatus::Disconnected { reason } => format!("\nDisconnected: {}", reason),
+        // The reason stays up while reconnecting, until the connection is back
+        ConnectionStatus::Complete => String::new(),
+        _ => return,
+    };
+    for mut text in &mut text_query {
+        text.sections[1].value = status.clone();
+    }
+}
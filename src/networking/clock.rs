@@ -0,0 +1,195 @@
+/*
+   Keeps every client's idea of time in step with the server.
+
+   The server counts the ticks of its update loop and stamps the state it sends
+   with the tick it was simulated on. Server time is measured in ticks, so a
+   tick's time is just the tick number multiplied by the tick duration.
+
+   Clients regularly send a Ping carrying their own clock, which the server
+   echoes back in a Pong along with its current tick. Half the round trip is
+   added to that tick's time to estimate the server clock, and both the round
+   trip time and the offset between the clocks are smoothed over many pings.
+*/
+
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::networking::handshake::ConnectionStatus;
+use crate::networking::message::{serialize, Message};
+use crate::networking::packet_systems::SocketAddress;
+use crate::networking::{NetworkEvent, Transport};
+
+/// Defines how often clients measure the round trip time to the server.
+const DEFAULT_PING_INTERVAL_SECS: f32 = 1.;
+/// How much each new measurement moves the smoothed round trip time and clock offset.
+const SMOOTHING: f64 = 0.1;
+/// Offsets further than this from the current estimate mean the server clock was reset, for
+/// instance by a server restart, so the estimate is replaced rather than smoothed.
+const MAX_CLOCK_DRIFT_SECS: f64 = 1.;
+
+#[derive(Resource, Debug)]
+pub struct ServerTick {
+    pub tick: u32,
+    pub duration: Duration,
+}
+
+impl ServerTick {
+    pub fn new(duration: Duration) -> Self {
+        Self { tick: 0, duration }
+    }
+}
+
+pub fn advance_tick(mut tick: ResMut<ServerTick>) {
+    tick.tick += 1;
+}
+
+pub fn answer_pings(
+    mut events: EventReader<NetworkEvent>,
+    tick: Res<ServerTick>,
+    mut transport: ResMut<Transport>,
+) {
+    for event in events.iter() {
+        if let NetworkEvent::RawMessage(addr, Message::Ping(client_time)) = event {
+            transport.send(
+                *addr,
+                &serialize(Message::Pong {
+                    client_time: *client_time,
+                    tick: tick.tick,
+                    tick_duration: tick.duration,
+                }),
+            );
+        }
+    }
+}
+
+/// The client's estimate of the server clock.
+#[derive(Resource, Debug, Default)]
+pub struct ServerClock {
+    // Smoothed round trip time to the server
+    pub rtt: Duration,
+    pub tick_duration: Duration,
+    // Seconds to add to the local clock to get the server's, once a Pong has been received
+    offset: Option<f64>,
+}
+
+impl ServerClock {
+    pub fn receive_pong(&mut self, client_time: Duration, tick: u32, tick_duration: Duration, now: Duration) {
+        let rtt = now.saturating_sub(client_time);
+        let server_now = (tick_duration * tick + rtt / 2).as_secs_f64();
+        let offset = server_now - now.as_secs_f64();
+        self.tick_duration = tick_duration;
+
+        match self.offset {
+            Some(current) if (offset - current).abs() <= MAX_CLOCK_DRIFT_SECS => {
+                self.offset = Some(current + (offset - current) * SMOOTHING);
+                let rtt_secs = self.rtt.as_secs_f64();
+                self.rtt =
+                    Duration::from_secs_f64(rtt_secs + (rtt.as_secs_f64() - rtt_secs) * SMOOTHING);
+            }
+            _ => {
+                self.offset = Some(offset);
+                self.rtt = rtt;
+            }
+        }
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.offset.is_some()
+    }
+
+    /// Returns the estimated server time at local time `now`, or `None` before the first Pong.
+    pub fn server_time(&self, now: Duration) -> Option<Duration> {
+        let offset = self.offset?;
+        Some(Duration::from_secs_f64((now.as_secs_f64() + offset).max(0.)))
+    }
+
+    pub fn tick_time(&self, tick: u32) -> Duration {
+        self.tick_duration * tick
+    }
+}
+
+#[derive(Resource)]
+pub struct PingTimer(Timer);
+
+impl Default for PingTimer {
+    fn default() -> Self {
+        let mut timer = Timer::from_seconds(DEFAULT_PING_INTERVAL_SECS, TimerMode::Repeating);
+        // Sync the clock as soon as possible rather than after a full interval.
+        timer.set_elapsed(Duration::from_secs_f32(DEFAULT_PING_INTERVAL_SECS));
+        Self(timer)
+    }
+}
+
+pub fn send_pings(
+    time: Res<Time>,
+    mut timer: ResMut<PingTimer>,
+    remote_addr: Res<SocketAddress>,
+    connection_status: Res<ConnectionStatus>,
+    mut transport: ResMut<Transport>,
+) {
+    if !matches!(*connection_status, ConnectionStatus::Complete) {
+        return;
+    }
+    if !timer.0.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    transport.send(remote_addr.0, &serialize(Message::Ping(time.elapsed())));
+}
+
+pub fn receive_pongs(
+    time: Res<Time>,
+    mut messages: EventReader<Message>,
+    mut clock: ResMut<ServerClock>,
+) {
+    for message in messages.iter() {
+        if let Message::Pong {
+            client_time,
+            tick,
+            tick_duration,
+        } = message
+        {
+            clock.receive_pong(*client_time, *tick, *tick_duration, time.elapsed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn test_estimates_server_time_from_pong() {
+        let mut clock = ServerClock::default();
+        assert_eq!(clock.server_time(Duration::ZERO), None);
+
+        // Pinged at 1s, answered on tick 500 (5s), received at 1.2s
+        clock.receive_pong(Duration::from_secs(1), 500, TICK, Duration::from_millis(1200));
+        assert_eq!(clock.rtt, Duration::from_millis(200));
+        let server_time = clock.server_time(Duration::from_millis(1200)).unwrap();
+        assert!((server_time.as_secs_f64() - 5.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_smooths_rtt() {
+        let mut clock = ServerClock::default();
+        clock.receive_pong(Duration::ZERO, 0, TICK, Duration::from_millis(100));
+        clock.receive_pong(Duration::from_secs(1), 100, TICK, Duration::from_millis(1200));
+
+        assert!(clock.rtt > Duration::from_millis(100));
+        assert!(clock.rtt < Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_resyncs_after_server_restart() {
+        let mut clock = ServerClock::default();
+        clock.receive_pong(Duration::ZERO, 10_000, TICK, Duration::ZERO);
+        clock.receive_pong(Duration::from_secs(1), 0, TICK, Duration::from_secs(1));
+
+        let server_time = clock.server_time(Duration::from_secs(1)).unwrap();
+        assert!(server_time.as_secs_f64() < 1e-6);
+    }
+}
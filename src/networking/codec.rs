@@ -0,0 +1,263 @@
+/*
+   Encodes Messages into the payloads carried by packets.
+
+   CborCodec writes Messages with serde_cbor and is used by default. PackedCodec,
+   used when the `packed-codec` feature is enabled, writes a tag byte followed
+   by a stream of bits: ids, counters and lengths as varints, positions and
+   velocities quantized to a fixed grid within the map bounds, and angles
+   quantized to 16 bits. Both ends must use the same codec, so the codec is
+   part of the build hash. Clients quantize the look angles of their input
+   before predicting with them, so they move the way the server will.
+
+   ConnectRequest and ConnectionRejected are always written with CBOR, so that
+   a client built with the other codec can still be told why it was rejected.
+   An encoded CBOR Message never starts with a byte below 0x60, and PackedCodec
+   keeps its tags below that.
+*/
+
+use crate::networking::message::{DeserializeError, Message};
+
+#[cfg(any(test, feature = "packed-codec"))]
+mod packed;
+#[cfg(any(test, feature = "packed-codec"))]
+pub use packed::PackedCodec;
+
+pub trait Codec {
+    /// Identifies the codec in the build hash.
+    const NAME: &'static str;
+
+    fn encode(message: &Message) -> Vec<u8>;
+    fn decode(bytes: &[u8]) -> Result<Message, DeserializeError>;
+
+    /// The angle a peer decodes when `angle` is sent.
+    fn quantize_angle(angle: f32) -> f32 {
+        angle
+    }
+}
+
+pub struct CborCodec;
+
+impl Codec for CborCodec {
+    const NAME: &'static str = "cbor";
+
+    fn encode(message: &Message) -> Vec<u8> {
+        serde_cbor::to_vec(message).expect("could not serialize message")
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Message, DeserializeError> {
+        serde_cbor::from_slice(bytes).map_err(|err| DeserializeError(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy::prelude::Vec3;
+
+    use super::packed::{FIRST_CBOR_BYTE, POSITION_MIN, POSITION_SCALE};
+    use super::*;
+    use crate::networking::components::{NetworkObjectType, ObjectState};
+    use crate::networking::handshake::RejectReason;
+    use crate::networking::resources::PlayerId;
+
+    #[test]
+    fn test_every_message_round_trips_through_cbor() {
+        for message in every_message() {
+            assert_eq!(CborCodec::decode(&CborCodec::encode(&message)).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_every_message_round_trips_through_packed() {
+        // Every float in these messages lies on the quantization grid
+        for message in every_message() {
+            assert_eq!(PackedCodec::decode(&PackedCodec::encode(&message)).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_quantization_error_is_bounded() {
+        let position = Vec3::new(12.3456, -7.891, 201.01);
+        let message = Message::PlayerState {
+            sequence: 1,
+            position,
+            velocity: Vec3::new(-3.3333, 9.99, 0.001),
+            tick: 1,
+        };
+        let decoded = PackedCodec::decode(&PackedCodec::encode(&message)).unwrap();
+        let Message::PlayerState { position: decoded_position, .. } = decoded else {
+            panic!("decoded as {:?}", decoded)
+        };
+        assert!(position.distance(decoded_position) < 1. / POSITION_SCALE);
+
+        for angle in [0.1, -1.2, 3.1, -3.1] {
+            let input = network_input(angle, angle / 2.);
+            match PackedCodec::decode(&PackedCodec::encode(&input)).unwrap() {
+                Message::NetworkInput { yaw, pitch, .. } => {
+                    assert!((yaw - angle).abs() < 1e-3);
+                    assert!((pitch - angle / 2.).abs() < 1e-3);
+                }
+                other => panic!("decoded as {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_quantized_angles_are_sent_unchanged() {
+        for angle in [0.1, -1.2, 3.1, -3.1, 7.] {
+            let quantized = PackedCodec::quantize_angle(angle);
+            let input = network_input(quantized, quantized);
+            match PackedCodec::decode(&PackedCodec::encode(&input)).unwrap() {
+                Message::NetworkInput { yaw, pitch, .. } => assert_eq!((yaw, pitch), (quantized, quantized)),
+                other => panic!("decoded as {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_positions_are_clamped_to_the_map_bounds() {
+        let message = Message::Spawn(PlayerId(1), Vec3::new(1000., -1000., 0.), NetworkObjectType::Item, 1);
+        match PackedCodec::decode(&PackedCodec::encode(&message)).unwrap() {
+            Message::Spawn(_, position, ..) => {
+                assert!(position.x > 255. && position.x < 256.);
+                assert_eq!(position.y, POSITION_MIN.y);
+            }
+            other => panic!("decoded as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_packed_snapshots_are_smaller() {
+        let state = ObjectState {
+            position: Vec3::new(1.5, 2., -3.25),
+            velocity: Vec3::new(0.5, -9.75, 0.),
+            yaw: 1.,
+            pitch: -0.5,
+        };
+        let message = Message::WorldSnapshot {
+            tick: 10_000,
+            baseline: Some(9_990),
+            changed: (0..20).map(|id| (PlayerId(id), id, state)).collect(),
+            removed: vec![],
+        };
+        assert!(PackedCodec::encode(&message).len() * 2 < CborCodec::encode(&message).len());
+    }
+
+    #[test]
+    fn test_codecs_agree_on_the_handshake() {
+        let request = Message::ConnectRequest {
+            protocol_version: 1,
+            build_hash: 2,
+        };
+        assert_eq!(PackedCodec::decode(&CborCodec::encode(&request)).unwrap(), request);
+        assert_eq!(CborCodec::decode(&PackedCodec::encode(&request)).unwrap(), request);
+    }
+
+    #[test]
+    fn test_packed_garbage_is_an_error() {
+        let encoded = PackedCodec::encode(&Message::Despawn(PlayerId(3), 7));
+        assert!(PackedCodec::decode(&encoded[..encoded.len() - 1]).is_err());
+        assert!(PackedCodec::decode(&[encoded.as_slice(), &[0]].concat()).is_err());
+        assert!(PackedCodec::decode(&[FIRST_CBOR_BYTE - 1]).is_err());
+        assert!(PackedCodec::decode(&[]).is_err());
+    }
+
+    fn network_input(yaw: f32, pitch: f32) -> Message {
+        Message::NetworkInput {
+            sequence: 300,
+            dt: Duration::from_millis(16),
+            w: true,
+            s: false,
+            a: false,
+            d: true,
+            jump: true,
+            crouch: false,
+            sprint: true,
+            yaw,
+            pitch,
+        }
+    }
+
+    fn every_message() -> Vec<Message> {
+        let position = Vec3::new(1.5, 2., -3.25);
+        let velocity = Vec3::new(0.5, -9.75, 0.);
+        let state = ObjectState {
+            position,
+            velocity,
+            ..Default::default()
+        };
+        vec![
+            Message::ConnectRequest {
+                protocol_version: 9,
+                build_hash: u64::MAX,
+            },
+            Message::ConnectionRejected(RejectReason::ProtocolMismatch { server: 9, client: 8 }),
+            Message::ConnectionRejected(RejectReason::BuildMismatch { server: 1, client: 2 }),
+            Message::ConnectionRejected(RejectReason::ServerFull),
+            Message::ConnectChallenge {
+                token: u64::MAX,
+                salt: [0x5a; 16],
+                compression: true,
+                public_key: [0xab; 32],
+            },
+            Message::ChallengeResponse {
+                token: 12345,
+                salt: std::array::from_fn(|i| 255 - i as u8),
+                compression: false,
+                public_key: std::array::from_fn(|i| i as u8),
+            },
+            Message::Disconnect,
+            Message::Spawn(PlayerId(1), position, NetworkObjectType::Player, 2),
+            Message::Spawn(PlayerId(u16::MAX), position, NetworkObjectType::Item, u16::MAX),
+            Message::Despawn(PlayerId(300), 7),
+            Message::Throw,
+            Message::WorldSnapshot {
+                tick: u32::MAX,
+                baseline: None,
+                changed: vec![(PlayerId(1), 1, state), (PlayerId(2), 300, ObjectState::default())],
+                removed: vec![],
+            },
+            Message::WorldSnapshot {
+                tick: 100,
+                baseline: Some(90),
+                changed: vec![],
+                removed: vec![(PlayerId(1), 2)],
+            },
+            Message::SnapshotAck(100),
+            network_input(0., 0.),
+            Message::PlayerState {
+                sequence: 70_000,
+                position,
+                velocity,
+                tick: 5,
+            },
+            Message::Ping(Duration::from_millis(1500)),
+            Message::Pong {
+                client_time: Duration::new(100_000, 999_999_999),
+                tick: 3000,
+                tick_duration: Duration::from_secs_f32(1. / 30.),
+            },
+            Message::ServerAcknowledgement(PlayerId(4), 0xdead_beef),
+            Message::ClientAcknowledgement(PlayerId(4)),
+            Message::ResumeSession {
+                previous: PlayerId(4),
+                session_token: 42,
+                assigned: PlayerId(5),
+            },
+            Message::SessionResumed(PlayerId(4), 42),
+            Message::PlayerName("Zoë".to_string()),
+            Message::ComponentUpdate {
+                owner: PlayerId(1),
+                object: 2,
+                kind: 3,
+                data: vec![0, 1, 255],
+            },
+            Message::ComponentRemoved {
+                owner: PlayerId(1),
+                object: 2,
+                kind: 3,
+            },
+        ]
+    }
+}
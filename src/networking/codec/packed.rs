@@ -0,0 +1,597 @@
+/*
+   PackedCodec and the bit writer and reader it is built on. Compiled only with
+   the `packed-codec` feature, and for the codec tests.
+*/
+
+use std::f32::consts::{PI, TAU};
+use std::time::Duration;
+
+use bevy::prelude::Vec3;
+
+use crate::networking::codec::{CborCodec, Codec};
+use crate::networking::components::{NetworkObjectType, ObjectState};
+use crate::networking::message::{DeserializeError, Message};
+use crate::networking::resources::PlayerId;
+
+/// Lowest first byte of an encoded CBOR Message.
+pub(super) const FIRST_CBOR_BYTE: u8 = 0x60;
+
+/// Corner of the map bounds positions are quantized within. Players below the floor are
+/// respawned before falling out of them.
+pub(super) const POSITION_MIN: Vec3 = Vec3::new(-256., -64., -256.);
+/// Bits per axis of a quantized position, giving a grid of 1/POSITION_SCALE units in the bounds.
+const POSITION_BITS: [u32; 3] = [18, 16, 18];
+/// Fastest speed along each axis a quantized velocity can hold.
+const MAX_SPEED: f32 = 64.;
+const VELOCITY_BITS: u32 = 16;
+/// Number of grid steps per unit for positions and velocities.
+pub(super) const POSITION_SCALE: f32 = 512.;
+/// Largest quantized angle either side of zero. Angles take 16 bits.
+const ANGLE_STEPS: f32 = 32767.;
+const ANGLE_BITS: u32 = 16;
+
+const CONNECT_CHALLENGE: u8 = 0;
+const CHALLENGE_RESPONSE: u8 = 1;
+const DISCONNECT: u8 = 2;
+const SPAWN: u8 = 3;
+const DESPAWN: u8 = 4;
+const THROW: u8 = 5;
+const WORLD_SNAPSHOT: u8 = 6;
+const SNAPSHOT_ACK: u8 = 7;
+const NETWORK_INPUT: u8 = 8;
+const PLAYER_STATE: u8 = 9;
+const PING: u8 = 10;
+const PONG: u8 = 11;
+const SERVER_ACKNOWLEDGEMENT: u8 = 12;
+const CLIENT_ACKNOWLEDGEMENT: u8 = 13;
+const RESUME_SESSION: u8 = 14;
+const SESSION_RESUMED: u8 = 15;
+const COMPONENT_UPDATE: u8 = 16;
+const PLAYER_NAME: u8 = 17;
+const COMPONENT_REMOVED: u8 = 18;
+
+pub struct PackedCodec;
+
+impl Codec for PackedCodec {
+    const NAME: &'static str = "packed";
+
+    fn quantize_angle(angle: f32) -> f32 {
+        angle_from_steps(angle_steps(angle))
+    }
+
+    fn encode(message: &Message) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        match message {
+            Message::ConnectRequest { .. } | Message::ConnectionRejected(_) => {
+                return CborCodec::encode(message)
+            }
+            Message::ConnectChallenge {
+                token,
+                salt,
+                compression,
+                public_key,
+            } => {
+                writer.tag(CONNECT_CHALLENGE);
+                writer.write_bits(*token, 64);
+                writer.write_bytes(salt);
+                writer.write_bool(*compression);
+                writer.write_bytes(public_key);
+            }
+            Message::ChallengeResponse {
+                token,
+                salt,
+                compression,
+                public_key,
+            } => {
+                writer.tag(CHALLENGE_RESPONSE);
+                writer.write_bits(*token, 64);
+                writer.write_bytes(salt);
+                writer.write_bool(*compression);
+                writer.write_bytes(public_key);
+            }
+            Message::Disconnect => writer.tag(DISCONNECT),
+            Message::Spawn(owner, position, object_type, object) => {
+                writer.tag(SPAWN);
+                writer.write_varint(owner.0 as u64);
+                writer.write_position(*position);
+                writer.write_object_type(*object_type);
+                writer.write_varint(*object as u64);
+            }
+            Message::Despawn(owner, object) => {
+                writer.tag(DESPAWN);
+                writer.write_varint(owner.0 as u64);
+                writer.write_varint(*object as u64);
+            }
+            Message::Throw => writer.tag(THROW),
+            Message::WorldSnapshot {
+                tick,
+                baseline,
+                changed,
+                removed,
+            } => {
+                writer.tag(WORLD_SNAPSHOT);
+                writer.write_varint(*tick as u64);
+                writer.write_bool(baseline.is_some());
+                if let Some(baseline) = baseline {
+                    writer.write_varint(*baseline as u64);
+                }
+                writer.write_varint(changed.len() as u64);
+                for (owner, object, state) in changed {
+                    writer.write_varint(owner.0 as u64);
+                    writer.write_varint(*object as u64);
+                    writer.write_object_state(state);
+                }
+                writer.write_varint(removed.len() as u64);
+                for (owner, object) in removed {
+                    writer.write_varint(owner.0 as u64);
+                    writer.write_varint(*object as u64);
+                }
+            }
+            Message::SnapshotAck(tick) => {
+                writer.tag(SNAPSHOT_ACK);
+                writer.write_varint(*tick as u64);
+            }
+            Message::NetworkInput {
+                sequence,
+                dt,
+                w,
+                s,
+                a,
+                d,
+                jump,
+                crouch,
+                sprint,
+                yaw,
+                pitch,
+            } => {
+                writer.tag(NETWORK_INPUT);
+                writer.write_varint(*sequence as u64);
+                writer.write_duration(*dt);
+                for key in [w, s, a, d, jump, crouch, sprint] {
+                    writer.write_bool(*key);
+                }
+                writer.write_angle(*yaw);
+                writer.write_angle(*pitch);
+            }
+            Message::PlayerState {
+                sequence,
+                position,
+                velocity,
+                tick,
+            } => {
+                writer.tag(PLAYER_STATE);
+                writer.write_varint(*sequence as u64);
+                writer.write_position(*position);
+                writer.write_velocity(*velocity);
+                writer.write_varint(*tick as u64);
+            }
+            Message::Ping(client_time) => {
+                writer.tag(PING);
+                writer.write_duration(*client_time);
+            }
+            Message::Pong {
+                client_time,
+                tick,
+                tick_duration,
+            } => {
+                writer.tag(PONG);
+                writer.write_duration(*client_time);
+                writer.write_varint(*tick as u64);
+                writer.write_duration(*tick_duration);
+            }
+            Message::ServerAcknowledgement(player_id, session_token) => {
+                writer.tag(SERVER_ACKNOWLEDGEMENT);
+                writer.write_varint(player_id.0 as u64);
+                writer.write_bits(*session_token, 64);
+            }
+            Message::ClientAcknowledgement(player_id) => {
+                writer.tag(CLIENT_ACKNOWLEDGEMENT);
+                writer.write_varint(player_id.0 as u64);
+            }
+            Message::ResumeSession {
+                previous,
+                session_token,
+                assigned,
+            } => {
+                writer.tag(RESUME_SESSION);
+                writer.write_varint(previous.0 as u64);
+                writer.write_bits(*session_token, 64);
+                writer.write_varint(assigned.0 as u64);
+            }
+            Message::SessionResumed(player_id, session_token) => {
+                writer.tag(SESSION_RESUMED);
+                writer.write_varint(player_id.0 as u64);
+                writer.write_bits(*session_token, 64);
+            }
+            Message::PlayerName(name) => {
+                writer.tag(PLAYER_NAME);
+                writer.write_string(name);
+            }
+            Message::ComponentUpdate {
+                owner,
+                object,
+                kind,
+                data,
+            } => {
+                writer.tag(COMPONENT_UPDATE);
+                writer.write_varint(owner.0 as u64);
+                writer.write_varint(*object as u64);
+                writer.write_varint(*kind as u64);
+                writer.write_varint(data.len() as u64);
+                for byte in data {
+                    writer.write_bits(*byte as u64, 8);
+                }
+            }
+            Message::ComponentRemoved {
+                owner,
+                object,
+                kind,
+            } => {
+                writer.tag(COMPONENT_REMOVED);
+                writer.write_varint(owner.0 as u64);
+                writer.write_varint(*object as u64);
+                writer.write_varint(*kind as u64);
+            }
+        }
+        writer.bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Message, DeserializeError> {
+        let tag = *bytes.first().ok_or_else(|| DeserializeError("empty payload".to_string()))?;
+        if tag >= FIRST_CBOR_BYTE {
+            return CborCodec::decode(bytes);
+        }
+
+        let mut reader = BitReader::new(&bytes[1..]);
+        let message = match tag {
+            CONNECT_CHALLENGE => Message::ConnectChallenge {
+                token: reader.read_bits(64)?,
+                salt: reader.read_bytes()?,
+                compression: reader.read_bool()?,
+                public_key: reader.read_bytes()?,
+            },
+            CHALLENGE_RESPONSE => Message::ChallengeResponse {
+                token: reader.read_bits(64)?,
+                salt: reader.read_bytes()?,
+                compression: reader.read_bool()?,
+                public_key: reader.read_bytes()?,
+            },
+            DISCONNECT => Message::Disconnect,
+            SPAWN => Message::Spawn(
+                reader.read_player_id()?,
+                reader.read_position()?,
+                reader.read_object_type()?,
+                reader.read_u16()?,
+            ),
+            DESPAWN => Message::Despawn(reader.read_player_id()?, reader.read_u16()?),
+            THROW => Message::Throw,
+            WORLD_SNAPSHOT => {
+                let tick = reader.read_u32()?;
+                let baseline = match reader.read_bool()? {
+                    true => Some(reader.read_u32()?),
+                    false => None,
+                };
+                let mut changed = Vec::new();
+                for _ in 0..reader.read_len()? {
+                    changed.push((reader.read_player_id()?, reader.read_u16()?, reader.read_object_state()?));
+                }
+                let mut removed = Vec::new();
+                for _ in 0..reader.read_len()? {
+                    removed.push((reader.read_player_id()?, reader.read_u16()?));
+                }
+                Message::WorldSnapshot {
+                    tick,
+                    baseline,
+                    changed,
+                    removed,
+                }
+            }
+            SNAPSHOT_ACK => Message::SnapshotAck(reader.read_u32()?),
+            NETWORK_INPUT => Message::NetworkInput {
+                sequence: reader.read_u32()?,
+                dt: reader.read_duration()?,
+                w: reader.read_bool()?,
+                s: reader.read_bool()?,
+                a: reader.read_bool()?,
+                d: reader.read_bool()?,
+                jump: reader.read_bool()?,
+                crouch: reader.read_bool()?,
+                sprint: reader.read_bool()?,
+                yaw: reader.read_angle()?,
+                pitch: reader.read_angle()?,
+            },
+            PLAYER_STATE => Message::PlayerState {
+                sequence: reader.read_u32()?,
+                position: reader.read_position()?,
+                velocity: reader.read_velocity()?,
+                tick: reader.read_u32()?,
+            },
+            PING => Message::Ping(reader.read_duration()?),
+            PONG => Message::Pong {
+                client_time: reader.read_duration()?,
+                tick: reader.read_u32()?,
+                tick_duration: reader.read_duration()?,
+            },
+            SERVER_ACKNOWLEDGEMENT => {
+                Message::ServerAcknowledgement(reader.read_player_id()?, reader.read_bits(64)?)
+            }
+            CLIENT_ACKNOWLEDGEMENT => Message::ClientAcknowledgement(reader.read_player_id()?),
+            RESUME_SESSION => Message::ResumeSession {
+                previous: reader.read_player_id()?,
+                session_token: reader.read_bits(64)?,
+                assigned: reader.read_player_id()?,
+            },
+            SESSION_RESUMED => Message::SessionResumed(reader.read_player_id()?, reader.read_bits(64)?),
+            PLAYER_NAME => Message::PlayerName(reader.read_string()?),
+            COMPONENT_UPDATE => {
+                let owner = reader.read_player_id()?;
+                let object = reader.read_u16()?;
+                let kind = reader.read_u16()?;
+                let mut data = Vec::new();
+                for _ in 0..reader.read_len()? {
+                    data.push(reader.read_bits(8)? as u8);
+                }
+                Message::ComponentUpdate {
+                    owner,
+                    object,
+                    kind,
+                    data,
+                }
+            }
+            COMPONENT_REMOVED => Message::ComponentRemoved {
+                owner: reader.read_player_id()?,
+                object: reader.read_u16()?,
+                kind: reader.read_u16()?,
+            },
+            _ => return Err(DeserializeError(format!("unknown message tag {}", tag))),
+        };
+        reader.finish()?;
+        Ok(message)
+    }
+}
+
+/// Quantizes `value` to a whole number of `1 / scale` steps above `min`, clamped to `bits`.
+fn quantize(value: f32, min: f32, scale: f32, bits: u32) -> u64 {
+    let max = (1u64 << bits) - 1;
+    ((value - min) * scale).round().clamp(0., max as f32) as u64
+}
+
+fn dequantize(quantized: u64, min: f32, scale: f32) -> f32 {
+    min + quantized as f32 / scale
+}
+
+/// Quantizes an angle after wrapping it to within half a turn either side of zero.
+fn angle_steps(angle: f32) -> u64 {
+    let wrapped = (angle + PI).rem_euclid(TAU) - PI;
+    let steps = (wrapped / PI * ANGLE_STEPS).round().clamp(-ANGLE_STEPS, ANGLE_STEPS);
+    (steps + ANGLE_STEPS) as u64
+}
+
+fn angle_from_steps(steps: u64) -> f32 {
+    (steps as f32 - ANGLE_STEPS) / ANGLE_STEPS * PI
+}
+
+/// Writes bits most significant first, padding the last byte with zeroes.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitWriter {
+    fn tag(&mut self, tag: u8) {
+        self.bytes.push(tag);
+        self.len += 8;
+    }
+
+    fn write_bits(&mut self, value: u64, bits: u32) {
+        for i in (0..bits).rev() {
+            if self.len.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().unwrap() |= bit << (7 - self.len % 8);
+            self.len += 1;
+        }
+    }
+
+    fn write_bool(&mut self, value: bool) {
+        self.write_bits(value as u64, 1);
+    }
+
+    /// Seven bits at a time, each group followed by a bit saying whether another follows.
+    fn write_varint(&mut self, mut value: u64) {
+        loop {
+            self.write_bits(value & 0x7f, 7);
+            value >>= 7;
+            self.write_bool(value != 0);
+            if value == 0 {
+                break;
+            }
+        }
+    }
+
+    fn write_bytes<const N: usize>(&mut self, bytes: &[u8; N]) {
+        for byte in bytes {
+            self.write_bits(*byte as u64, 8);
+        }
+    }
+
+    fn write_string(&mut self, string: &str) {
+        self.write_varint(string.len() as u64);
+        for byte in string.as_bytes() {
+            self.write_bits(*byte as u64, 8);
+        }
+    }
+
+    fn write_duration(&mut self, duration: Duration) {
+        self.write_varint(duration.as_secs());
+        self.write_bits(duration.subsec_nanos() as u64, 30);
+    }
+
+    fn write_position(&mut self, position: Vec3) {
+        for axis in 0..3 {
+            let bits = POSITION_BITS[axis];
+            self.write_bits(quantize(position[axis], POSITION_MIN[axis], POSITION_SCALE, bits), bits);
+        }
+    }
+
+    fn write_velocity(&mut self, velocity: Vec3) {
+        for axis in 0..3 {
+            self.write_bits(
+                quantize(velocity[axis], -MAX_SPEED, POSITION_SCALE, VELOCITY_BITS),
+                VELOCITY_BITS,
+            );
+        }
+    }
+
+    fn write_angle(&mut self, angle: f32) {
+        self.write_bits(angle_steps(angle), ANGLE_BITS);
+    }
+
+    fn write_object_type(&mut self, object_type: NetworkObjectType) {
+        let value = match object_type {
+            NetworkObjectType::Player => 0,
+            NetworkObjectType::Item => 1,
+        };
+        self.write_bits(value, 2);
+    }
+
+    fn write_object_state(&mut self, state: &ObjectState) {
+        self.write_position(state.position);
+        self.write_velocity(state.velocity);
+        self.write_angle(state.yaw);
+        self.write_angle(state.pitch);
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn read_bits(&mut self, bits: u32) -> Result<u64, DeserializeError> {
+        if self.position + bits as usize > self.bytes.len() * 8 {
+            return Err(DeserializeError("payload is truncated".to_string()));
+        }
+        let mut value = 0;
+        for _ in 0..bits {
+            let bit = (self.bytes[self.position / 8] >> (7 - self.position % 8)) & 1;
+            value = (value << 1) | bit as u64;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    fn read_bool(&mut self) -> Result<bool, DeserializeError> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    fn read_varint(&mut self) -> Result<u64, DeserializeError> {
+        let mut value = 0;
+        for group in 0..10 {
+            value |= self.read_bits(7)? << (group * 7);
+            if !self.read_bool()? {
+                return Ok(value);
+            }
+        }
+        Err(DeserializeError("varint is too long".to_string()))
+    }
+
+    fn read_u16(&mut self) -> Result<u16, DeserializeError> {
+        u16::try_from(self.read_varint()?).map_err(|err| DeserializeError(err.to_string()))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, DeserializeError> {
+        u32::try_from(self.read_varint()?).map_err(|err| DeserializeError(err.to_string()))
+    }
+
+    /// Reads the length of a list, which can't be longer than what is left of the payload.
+    fn read_len(&mut self) -> Result<usize, DeserializeError> {
+        let len = self.read_varint()?;
+        if len > (self.bytes.len() * 8 - self.position) as u64 {
+            return Err(DeserializeError(format!("list of {} is longer than the payload", len)));
+        }
+        Ok(len as usize)
+    }
+
+    fn read_player_id(&mut self) -> Result<PlayerId, DeserializeError> {
+        Ok(PlayerId(self.read_u16()?))
+    }
+
+    fn read_bytes<const N: usize>(&mut self) -> Result<[u8; N], DeserializeError> {
+        let mut bytes = [0; N];
+        for byte in bytes.iter_mut() {
+            *byte = self.read_bits(8)? as u8;
+        }
+        Ok(bytes)
+    }
+
+    fn read_string(&mut self) -> Result<String, DeserializeError> {
+        let mut bytes = Vec::new();
+        for _ in 0..self.read_len()? {
+            bytes.push(self.read_bits(8)? as u8);
+        }
+        String::from_utf8(bytes).map_err(|err| DeserializeError(err.to_string()))
+    }
+
+    fn read_duration(&mut self) -> Result<Duration, DeserializeError> {
+        let secs = self.read_varint()?;
+        let nanos = self.read_bits(30)? as u32;
+        if nanos >= 1_000_000_000 {
+            return Err(DeserializeError(format!("{} nanoseconds is over a second", nanos)));
+        }
+        Ok(Duration::new(secs, nanos))
+    }
+
+    fn read_position(&mut self) -> Result<Vec3, DeserializeError> {
+        let mut position = Vec3::ZERO;
+        for axis in 0..3 {
+            let quantized = self.read_bits(POSITION_BITS[axis])?;
+            position[axis] = dequantize(quantized, POSITION_MIN[axis], POSITION_SCALE);
+        }
+        Ok(position)
+    }
+
+    fn read_velocity(&mut self) -> Result<Vec3, DeserializeError> {
+        let mut velocity = Vec3::ZERO;
+        for axis in 0..3 {
+            velocity[axis] = dequantize(self.read_bits(VELOCITY_BITS)?, -MAX_SPEED, POSITION_SCALE);
+        }
+        Ok(velocity)
+    }
+
+    fn read_angle(&mut self) -> Result<f32, DeserializeError> {
+        Ok(angle_from_steps(self.read_bits(ANGLE_BITS)?))
+    }
+
+    fn read_object_type(&mut self) -> Result<NetworkObjectType, DeserializeError> {
+        match self.read_bits(2)? {
+            0 => Ok(NetworkObjectType::Player),
+            1 => Ok(NetworkObjectType::Item),
+            other => Err(DeserializeError(format!("unknown object type {}", other))),
+        }
+    }
+
+    fn read_object_state(&mut self) -> Result<ObjectState, DeserializeError> {
+        Ok(ObjectState {
+            position: self.read_position()?,
+            velocity: self.read_velocity()?,
+            yaw: self.read_angle()?,
+            pitch: self.read_angle()?,
+        })
+    }
+
+    /// Fails if anything but the padding of the last byte is left unread.
+    fn finish(&self) -> Result<(), DeserializeError> {
+        if self.bytes.len() * 8 - self.position >= 8 {
+            return Err(DeserializeError("payload has trailing bytes".to_string()));
+        }
+        Ok(())
+    }
+}
@@ -1,47 +1,54 @@
+use crate::networking::clock::ServerClock;
 use crate::networking::resources::PlayerId;
 use bevy::ecs::system::Resource;
-use bevy::math::Vec3;
+use bevy::math::{Quat, Vec3};
 use bevy::prelude::{Component, Query, Res, Time, Transform};
 
-use rand::Rng;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::f32::consts::{PI, TAU};
+use std::time::Duration;
+
+use super::id_allocator::IdAllocator;
+
+pub type ObjectId = u16;
 
 #[derive(Component, Debug, Hash, PartialEq, Eq, Clone, Copy)]
 pub struct NetworkObject {
-    pub id: u8,
+    pub id: ObjectId,
     pub owner: PlayerId,
     pub object_type: NetworkObjectType,
     pub is_owned: bool
 }
 
-impl NetworkObject {
-    pub fn generate_id() -> u8 {
-        let mut rng = rand::thread_rng();
-
-        // Generate a random i16 value in the range [-32768, 32767]
-        return rng.gen();
-    }
-}
-
 #[derive(PartialEq, Debug, Serialize, Deserialize, Copy, Clone, Hash, Eq)]
 pub enum NetworkObjectType {
     Player,
+    // Thrown by a player and simulated by the server
+    Item,
 }
 
 #[derive(Resource, Default, Debug)]
 pub struct NetworkObjects {
-    pub objects: HashMap<NetworkObject, Vec3>,
+    pub objects: HashMap<NetworkObject, ObjectState>,
+    pub ids: IdAllocator,
 }
 
 impl NetworkObjects {
+    /// Finds an object by the player that owns it and its id.
+    pub fn get_mut(&mut self, owner: PlayerId, id: ObjectId) -> Option<(&NetworkObject, &mut ObjectState)> {
+        self.objects
+            .iter_mut()
+            .find(|(object, _)| object.owner == owner && object.id == id)
+    }
+
     pub fn objects_of_player(&mut self, id: PlayerId) -> Vec<NetworkObject> {
         let mut net_objs = Vec::new();
 
         for object in self.objects.keys() {
             if object.owner == id {
-                net_objs.push(object.clone());
+                net_objs.push(*object);
             }
         }
 
@@ -51,46 +58,215 @@ impl NetworkObjects {
 
 /*
 Synchronizes the position of a NetworkObject across the network.
+
+Every received state is kept in a short buffer along with the server time it was simulated at.
+Remote objects are rendered a fixed delay behind the estimated server time, between the two
+buffered states either side of that moment, so that uneven packet timing doesn't show up as
+jitter. When no newer state has arrived in time the object carries on at its latest velocity for
+a short while before it stops.
 */
 
-#[derive(Component, Debug)]
+/// How far behind the estimated server time remote objects are rendered.
+const DEFAULT_INTERPOLATION_DELAY_SECS: f32 = 0.1;
+/// How long an object keeps moving past its latest state while packets are late.
+const DEFAULT_MAX_EXTRAPOLATION_SECS: f32 = 0.25;
+/// How many received states are kept per object.
+const SNAPSHOT_BUFFER_LEN: usize = 32;
+
+#[derive(Resource, Debug)]
+pub struct InterpolationSettings {
+    pub delay: Duration,
+    pub max_extrapolation: Duration,
+}
+
+impl Default for InterpolationSettings {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_secs_f32(DEFAULT_INTERPOLATION_DELAY_SECS),
+            max_extrapolation: Duration::from_secs_f32(DEFAULT_MAX_EXTRAPOLATION_SECS),
+        }
+    }
+}
+
+/// The replicated state of a NetworkObject.
+#[derive(PartialEq, Debug, Serialize, Deserialize, Copy, Clone, Default)]
+pub struct ObjectState {
+    pub position: Vec3,
+    pub velocity: Vec3,
+    // Look direction, as in FpsControllerInput
+    pub yaw: f32,
+    pub pitch: f32,
+}
+
+impl ObjectState {
+    /// Blends towards `other`, turning the shortest way round.
+    pub fn lerp(&self, other: &ObjectState, t: f32) -> ObjectState {
+        let yaw_delta = (other.yaw - self.yaw + PI).rem_euclid(TAU) - PI;
+        ObjectState {
+            position: self.position.lerp(other.position, t),
+            velocity: self.velocity.lerp(other.velocity, t),
+            yaw: self.yaw + yaw_delta * t,
+            pitch: self.pitch + (other.pitch - self.pitch) * t,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Snapshot {
+    pub time: Duration,
+    pub state: ObjectState,
+}
+
+#[derive(Component, Debug, Default)]
 pub struct NetworkTransform {
-    pub last_pos: Vec3,
+    snapshots: VecDeque<Snapshot>,
 }
 
 impl NetworkTransform {
-    /*
-    Grab the identified NetworkTransform and update its last_pos. This is generally performed as positions are received
-    from the server.
-     */
-    pub fn update_last_pos(
-        received_player_id: &PlayerId,
-        received_position: &Vec3,
-        networked_objects: &mut Query<(&NetworkObject, &mut NetworkTransform)>,
-    ) {
-        for (networked_object, mut transform) in networked_objects.iter_mut() {
-            if networked_object.owner == *received_player_id {
-                transform.last_pos = *received_position;
+    /// Buffers a received state. States older than the latest one are dropped.
+    pub fn push(&mut self, snapshot: Snapshot) {
+        if let Some(latest) = self.snapshots.back() {
+            if snapshot.time <= latest.time {
+                return;
             }
         }
+        if self.snapshots.len() == SNAPSHOT_BUFFER_LEN {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+    }
+
+    /// Returns the object's state at `render_time`, moving it on at its latest velocity for at
+    /// most `max_extrapolation` past the newest state. Snapshots no longer needed are discarded.
+    pub fn sample(&mut self, render_time: Duration, max_extrapolation: Duration) -> Option<ObjectState> {
+        // Keep exactly one snapshot at or before the render time
+        while self.snapshots.len() > 1 && self.snapshots[1].time <= render_time {
+            self.snapshots.pop_front();
+        }
+
+        let from = *self.snapshots.front()?;
+        if render_time <= from.time {
+            return Some(from.state);
+        }
+        if let Some(to) = self.snapshots.get(1) {
+            let t = (render_time - from.time).as_secs_f32() / (to.time - from.time).as_secs_f32();
+            return Some(from.state.lerp(&to.state, t));
+        }
+
+        // Packets are late
+        let elapsed = (render_time - from.time).min(max_extrapolation);
+        Some(ObjectState {
+            position: from.state.position + from.state.velocity * elapsed.as_secs_f32(),
+            ..from.state
+        })
     }
+
     /*
-    Iterate over all non-owned network transforms and lerp their positions smoothly.
+    Iterate over all non-owned network transforms and move them to where they were a fixed delay behind the
+    estimated server time. Only yaw turns the object; pitch is left for whatever the object holds.
      */
     pub fn sync_network_transforms(
-        mut networked_objects: Query<(&mut Transform, &NetworkTransform)>,
-
+        mut networked_objects: Query<(&mut Transform, &mut NetworkTransform)>,
+        settings: Res<InterpolationSettings>,
+        clock: Res<ServerClock>,
         timer: Res<Time>,
     ) {
-        for (mut transform, network_transform) in networked_objects.iter_mut() {
-            println!("{:?}", network_transform.last_pos);
-            if transform.translation == network_transform.last_pos {
-                continue;
+        let server_time = match clock.server_time(timer.elapsed()) {
+            Some(server_time) => server_time,
+            None => return,
+        };
+        let render_time = server_time.saturating_sub(settings.delay);
+        for (mut transform, mut network_transform) in networked_objects.iter_mut() {
+            if let Some(state) = network_transform.sample(render_time, settings.max_extrapolation) {
+                transform.translation = state.position;
+                transform.rotation = Quat::from_rotation_y(state.yaw);
             }
-            let incremental_adjust = 10f32 * timer.delta_seconds();
-            let old_translation = transform.translation;
-            transform.translation =
-                old_translation.lerp(network_transform.last_pos, incremental_adjust);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interpolates_between_snapshots() {
+        let mut network_transform = transform_with(&[(0, Vec3::ZERO), (100, Vec3::X)]);
+
+        let state = network_transform.sample(millis(50), millis(250)).unwrap();
+        assert_eq!(state.position, Vec3::X * 0.5);
+    }
+
+    #[test]
+    fn test_extrapolation_is_bounded() {
+        let mut network_transform = transform_with(&[(0, Vec3::ZERO), (100, Vec3::X)]);
+
+        let state = network_transform.sample(millis(150), millis(250)).unwrap();
+        assert_eq!(state.position, Vec3::X * 1.5);
+        let state = network_transform.sample(millis(1000), millis(250)).unwrap();
+        assert_eq!(state.position, Vec3::X * 3.5);
+    }
+
+    #[test]
+    fn test_ignores_out_of_order_snapshots() {
+        let mut network_transform = transform_with(&[(0, Vec3::ZERO), (100, Vec3::X), (50, Vec3::Y)]);
+
+        let state = network_transform.sample(millis(100), millis(250)).unwrap();
+        assert_eq!(state.position, Vec3::X);
+    }
+
+    #[test]
+    fn test_objects_are_found_by_owner_and_id() {
+        let mut objects = NetworkObjects::default();
+        for (owner, id) in [(1, 1), (1, 2), (2, 1)] {
+            let object = NetworkObject {
+                id,
+                owner: PlayerId(owner),
+                object_type: NetworkObjectType::Item,
+                is_owned: false,
+            };
+            objects.objects.insert(object, ObjectState::default());
+        }
+
+        let (found, state) = objects.get_mut(PlayerId(2), 1).unwrap();
+        assert_eq!((found.owner, found.id), (PlayerId(2), 1));
+        state.position = Vec3::X;
+        assert_eq!(objects.get_mut(PlayerId(1), 1).unwrap().1.position, Vec3::ZERO);
+        assert!(objects.get_mut(PlayerId(2), 2).is_none());
+    }
+
+    #[test]
+    fn test_yaw_turns_the_short_way() {
+        let from = ObjectState {
+            yaw: PI - 0.1,
+            ..Default::default()
+        };
+        let to = ObjectState {
+            yaw: -PI + 0.1,
+            ..Default::default()
+        };
+
+        let halfway = from.lerp(&to, 0.5);
+        assert!((halfway.yaw.rem_euclid(TAU) - PI).abs() < 1e-5);
+    }
+
+    // Snapshots of an object moving at one unit per 100ms along x
+    fn transform_with(snapshots: &[(u64, Vec3)]) -> NetworkTransform {
+        let mut network_transform = NetworkTransform::default();
+        for (time, position) in snapshots {
+            network_transform.push(Snapshot {
+                time: millis(*time),
+                state: ObjectState {
+                    position: *position,
+                    velocity: Vec3::X * 10.,
+                    ..Default::default()
+                },
+            });
+        }
+        network_transform
+    }
+
+    fn millis(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+}
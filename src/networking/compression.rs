@@ -0,0 +1,185 @@
+/*
+   Optional LZ4 compression of outgoing datagrams.
+
+   Each side says during the handshake whether it accepts compressed
+   datagrams: the server in its ConnectChallenge and the client in its
+   ChallengeResponse. Only a side with compression enabled offers it, and
+   datagrams are only compressed for a peer that offered it too, so both sides
+   must enable it for either to compress. Datagrams shorter than the threshold
+   are sent as they are, as are those that don't get any smaller.
+
+   Received datagrams are decompressed whenever they carry the flag bit, no
+   matter what was negotiated.
+*/
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+use bevy::prelude::*;
+use bytes::Bytes;
+
+use crate::networking::message::Message;
+use crate::networking::packet::{compress, decompress};
+use crate::networking::packet_systems::SocketAddress;
+
+/// Datagrams shorter than this are not worth compressing.
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 128;
+/// How often the bytes saved by compression are logged.
+const DEFAULT_STATS_INTERVAL_SECS: f32 = 30.;
+
+/// Bytes sent and received, before compression and on the wire.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CompressionStats {
+    pub raw_bytes_sent: u64,
+    pub bytes_sent: u64,
+    pub raw_bytes_received: u64,
+    pub bytes_received: u64,
+}
+
+impl CompressionStats {
+    /// Wire bytes sent for every raw byte, 1 meaning nothing was saved.
+    pub fn sent_ratio(&self) -> f32 {
+        ratio(self.bytes_sent, self.raw_bytes_sent)
+    }
+
+    /// Wire bytes received for every raw byte, 1 meaning nothing was saved.
+    pub fn received_ratio(&self) -> f32 {
+        ratio(self.bytes_received, self.raw_bytes_received)
+    }
+}
+
+fn ratio(wire: u64, raw: u64) -> f32 {
+    match raw {
+        0 => 1.,
+        raw => wire as f32 / raw as f32,
+    }
+}
+
+#[derive(Resource)]
+pub struct Compression {
+    /// Whether compressed datagrams are offered to peers and sent to those that accept them.
+    pub enabled: bool,
+    /// Datagrams shorter than this are never compressed.
+    pub threshold: usize,
+    pub stats: CompressionStats,
+    // Peers that said they accept compressed datagrams
+    peers: HashSet<SocketAddr>,
+}
+
+impl Compression {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            stats: CompressionStats::default(),
+            peers: HashSet::new(),
+        }
+    }
+
+    /// Records whether `addr` said it accepts compressed datagrams.
+    pub fn set_peer_accepts(&mut self, addr: SocketAddr, accepts: bool) {
+        if accepts {
+            self.peers.insert(addr);
+        } else {
+            self.peers.remove(&addr);
+        }
+    }
+
+    /// Compresses a datagram bound for `destination`, if both ends agreed to and it is worth it.
+    pub fn compress(&mut self, destination: SocketAddr, datagram: Bytes) -> Bytes {
+        self.stats.raw_bytes_sent += datagram.len() as u64;
+        let compressed = match self.enabled && self.peers.contains(&destination) && datagram.len() >= self.threshold {
+            true => compress(&datagram).unwrap_or(datagram),
+            false => datagram,
+        };
+        self.stats.bytes_sent += compressed.len() as u64;
+        compressed
+    }
+
+    /// Decompresses a received datagram if it is compressed. Returns `None` if it is malformed.
+    pub fn decompress(&mut self, datagram: Bytes) -> Option<Bytes> {
+        self.stats.bytes_received += datagram.len() as u64;
+        let decompressed = decompress(datagram)?;
+        self.stats.raw_bytes_received += decompressed.len() as u64;
+        Some(decompressed)
+    }
+}
+
+/// Records whether the server offered compression in its challenge.
+pub fn receive_compression_offers(
+    mut messages: EventReader<Message>,
+    remote_addr: Res<SocketAddress>,
+    mut compression: ResMut<Compression>,
+) {
+    for message in messages.iter() {
+        if let Message::ConnectChallenge { compression: offered, .. } = message {
+            compression.set_peer_accepts(remote_addr.0, *offered);
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct CompressionStatsTimer(Timer);
+
+impl Default for CompressionStatsTimer {
+    fn default() -> Self {
+        Self(Timer::from_seconds(DEFAULT_STATS_INTERVAL_SECS, TimerMode::Repeating))
+    }
+}
+
+pub fn log_compression_stats(
+    time: Res<Time>,
+    mut timer: ResMut<CompressionStatsTimer>,
+    compression: Res<Compression>,
+) {
+    if !compression.enabled || !timer.0.tick(time.delta()).just_finished() {
+        return;
+    }
+    let stats = compression.stats;
+    info!(
+        "Compression: sent {} bytes for {} raw ({:.0}%), received {} bytes for {} raw ({:.0}%)",
+        stats.bytes_sent,
+        stats.raw_bytes_sent,
+        stats.sent_ratio() * 100.,
+        stats.bytes_received,
+        stats.raw_bytes_received,
+        stats.received_ratio() * 100.,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_compresses_for_peers_that_accept_it() {
+        let addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let datagram = Bytes::from(vec![0; 1000]);
+        let mut compression = Compression::new(true);
+
+        assert_eq!(compression.compress(addr, datagram.clone()), datagram);
+        compression.set_peer_accepts(addr, true);
+        let compressed = compression.compress(addr, datagram.clone());
+        assert!(compressed.len() < datagram.len());
+        assert_eq!(compression.decompress(compressed), Some(datagram.clone()));
+
+        let stats = compression.stats;
+        assert_eq!(stats.raw_bytes_sent, 2000);
+        assert!(stats.sent_ratio() < 1.);
+        assert_eq!(stats.raw_bytes_received, 1000);
+        assert!(stats.received_ratio() < 0.1);
+
+        compression.enabled = false;
+        assert_eq!(compression.compress(addr, datagram.clone()), datagram);
+    }
+
+    #[test]
+    fn test_small_datagrams_are_not_compressed() {
+        let addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let mut compression = Compression::new(true);
+        compression.set_peer_accepts(addr, true);
+
+        let datagram = Bytes::from(vec![0; DEFAULT_COMPRESSION_THRESHOLD - 1]);
+        assert_eq!(compression.compress(addr, datagram.clone()), datagram);
+    }
+}
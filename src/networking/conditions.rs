@@ -0,0 +1,357 @@
+/*
+   Simulates a bad network in front of any socket, to see how the game copes
+   with one.
+
+   A SimulatedSocket wraps another socket and holds on to the datagrams passing
+   through it in either direction. Following the conditions set for that
+   direction, each datagram is dropped, delayed, duplicated or held back until
+   the next one has overtaken it, all at random. Datagrams are let through
+   once their delay has passed, in the order they became due, whenever the
+   socket is used and once every frame.
+
+   Delays are measured on the app's clock rather than the wall clock, and the
+   random choices come from a seeded generator, so a test that steps its apps
+   by a fixed timestep sees the same network on every run.
+
+   Insert NetworkConditions before adding the ServerPlugin or ClientPlugin to
+   put its socket behind a SimulatedSocket. The conditions can be changed
+   through the resource at any time after that.
+*/
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bevy::prelude::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::networking::packet_systems::{Socket, SocketError, SocketLike};
+use crate::networking::NetworkSystem;
+
+/// How long a datagram held back to be reordered waits for another to overtake it before it is
+/// let through anyway.
+const REORDER_HOLD_MILLIS: u64 = 100;
+
+/// What happens to datagrams travelling in one direction.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkConditions {
+    /// Delay added to every datagram.
+    pub latency: Duration,
+    /// Most extra delay added to a datagram at random on top of the latency.
+    pub jitter: Duration,
+    /// Chance of a datagram being dropped, from 0 to 1.
+    pub loss: f64,
+    /// Chance of a datagram arriving twice.
+    pub duplication: f64,
+    /// Chance of a datagram being held back until the next one has overtaken it.
+    pub reordering: f64,
+}
+
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq)]
+pub struct NetworkConditions {
+    /// Applied to datagrams this app sends.
+    pub outgoing: LinkConditions,
+    /// Applied to datagrams this app receives.
+    pub incoming: LinkConditions,
+    /// Seeds the random choices, so that a run can be reproduced.
+    pub seed: u64,
+}
+
+struct Delayed {
+    due: Duration,
+    addr: SocketAddr,
+    datagram: Box<[u8]>,
+}
+
+/// The datagrams travelling in one direction.
+#[derive(Default)]
+struct Link {
+    conditions: LinkConditions,
+    in_flight: Vec<Delayed>,
+    // A datagram waiting for the next one to overtake it
+    held: Option<Delayed>,
+}
+
+impl Link {
+    fn push(&mut self, now: Duration, addr: SocketAddr, datagram: &[u8], rng: &mut StdRng) {
+        let conditions = self.conditions;
+        if rng.gen_bool(conditions.loss) {
+            return;
+        }
+        let copies = match rng.gen_bool(conditions.duplication) {
+            true => 2,
+            false => 1,
+        };
+        for _ in 0..copies {
+            let delayed = Delayed {
+                due: now + conditions.latency + conditions.jitter.mul_f64(rng.gen()),
+                addr,
+                datagram: datagram.into(),
+            };
+            if self.held.is_none() && rng.gen_bool(conditions.reordering) {
+                self.held = Some(delayed);
+                continue;
+            }
+            let due = delayed.due;
+            self.in_flight.push(delayed);
+            if let Some(mut held) = self.held.take() {
+                held.due = held.due.max(due);
+                self.in_flight.push(held);
+            }
+        }
+    }
+
+    /// Takes the datagrams due by `now`, in the order they became due.
+    fn take_due(&mut self, now: Duration) -> Vec<Delayed> {
+        let hold = Duration::from_millis(REORDER_HOLD_MILLIS);
+        if self.held.as_ref().is_some_and(|held| held.due + hold <= now) {
+            self.in_flight.extend(self.held.take());
+        }
+        let (mut due, in_flight): (Vec<Delayed>, Vec<Delayed>) =
+            self.in_flight.drain(..).partition(|delayed| delayed.due <= now);
+        self.in_flight = in_flight;
+        // Stable, so datagrams due at the same time keep the order they were pushed in
+        due.sort_by_key(|delayed| delayed.due);
+        due
+    }
+}
+
+struct Simulation {
+    socket: Box<dyn SocketLike + Send + Sync>,
+    now: Duration,
+    seed: u64,
+    rng: StdRng,
+    outgoing: Link,
+    incoming: Link,
+    // Received datagrams that are due, waiting to be read
+    received: VecDeque<Delayed>,
+}
+
+impl Simulation {
+    fn send_due(&mut self) {
+        for delayed in self.outgoing.take_due(self.now) {
+            // Nothing is left to report the error to by the time a delayed datagram is sent
+            if let Err(e) = self.socket.send_to(&delayed.datagram, delayed.addr) {
+                warn!("{}: could not send delayed datagram: {:?}", delayed.addr, e);
+            }
+        }
+    }
+}
+
+/// A socket that passes datagrams through another under simulated NetworkConditions. Clones share
+/// the same socket.
+#[derive(Resource, Clone)]
+pub struct SimulatedSocket(Arc<Mutex<Simulation>>);
+
+impl SimulatedSocket {
+    pub fn new(socket: Box<dyn SocketLike + Send + Sync>, conditions: NetworkConditions) -> Self {
+        let simulation = Simulation {
+            socket,
+            now: Duration::ZERO,
+            seed: conditions.seed,
+            rng: StdRng::seed_from_u64(conditions.seed),
+            outgoing: Link {
+                conditions: conditions.outgoing,
+                ..Default::default()
+            },
+            incoming: Link {
+                conditions: conditions.incoming,
+                ..Default::default()
+            },
+            received: Default::default(),
+        };
+        Self(Arc::new(Mutex::new(simulation)))
+    }
+
+    /// Moves the simulation on to `now`, sending every datagram that has become due, and applies
+    /// `conditions` to datagrams from now on.
+    pub fn update(&self, now: Duration, conditions: NetworkConditions) {
+        let mut simulation = self.0.lock().unwrap();
+        simulation.now = now;
+        simulation.outgoing.conditions = conditions.outgoing;
+        simulation.incoming.conditions = conditions.incoming;
+        if simulation.seed != conditions.seed {
+            simulation.seed = conditions.seed;
+            simulation.rng = StdRng::seed_from_u64(conditions.seed);
+        }
+        simulation.send_due();
+    }
+}
+
+impl SocketLike for SimulatedSocket {
+    fn peer_addr(&self) -> Result<SocketAddr, SocketError> {
+        self.0.lock().unwrap().socket.peer_addr()
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), SocketError> {
+        let mut simulation = self.0.lock().unwrap();
+        let simulation = &mut *simulation;
+        simulation.send_due();
+        loop {
+            match simulation.socket.recv_from(buf) {
+                Ok((len, addr)) => {
+                    simulation
+                        .incoming
+                        .push(simulation.now, addr, &buf[..len], &mut simulation.rng)
+                }
+                Err(SocketError::NoInput()) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let due = simulation.incoming.take_due(simulation.now);
+        simulation.received.extend(due);
+
+        match simulation.received.pop_front() {
+            Some(delayed) => {
+                let len = delayed.datagram.len().min(buf.len());
+                buf[..len].copy_from_slice(&delayed.datagram[..len]);
+                Ok((len, delayed.addr))
+            }
+            None => Err(SocketError::NoInput()),
+        }
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, SocketError> {
+        let mut simulation = self.0.lock().unwrap();
+        let simulation = &mut *simulation;
+        simulation
+            .outgoing
+            .push(simulation.now, addr, buf, &mut simulation.rng);
+        simulation.send_due();
+        Ok(buf.len())
+    }
+}
+
+/// Puts the app's socket behind a SimulatedSocket if NetworkConditions have been inserted. Called
+/// by the networking plugins once their socket exists.
+pub fn simulate_network_conditions(app: &mut App) {
+    let conditions = match app.world.get_resource::<NetworkConditions>() {
+        Some(conditions) => *conditions,
+        None => return,
+    };
+    let socket = app
+        .world
+        .remove_resource::<Socket>()
+        .expect("no socket to simulate network conditions for");
+    info!("Simulating network conditions: {:?}", conditions);
+    let simulated = SimulatedSocket::new(socket.0, conditions);
+    app.insert_resource(Socket(Box::new(simulated.clone())))
+        .insert_resource(simulated)
+        .add_systems(Update, update_simulated_socket.before(NetworkSystem::Receive));
+}
+
+pub fn update_simulated_socket(
+    time: Res<Time>,
+    conditions: Res<NetworkConditions>,
+    socket: Res<SimulatedSocket>,
+) {
+    socket.update(time.elapsed(), *conditions);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::packet_systems::VirtualNetwork;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// A simulated socket at port 3000 sending to a plain one at port 8080.
+    fn link(outgoing: LinkConditions, seed: u64) -> (SimulatedSocket, impl SocketLike) {
+        let network = VirtualNetwork::default();
+        let conditions = NetworkConditions {
+            outgoing,
+            seed,
+            ..Default::default()
+        };
+        let simulated = SimulatedSocket::new(Box::new(network.bind(addr(3000))), conditions);
+        (simulated, network.bind(addr(8080)))
+    }
+
+    fn received(socket: &impl SocketLike) -> Vec<u8> {
+        let mut buf = [0; 1];
+        let mut received = Vec::new();
+        while let Ok((_, _)) = socket.recv_from(&mut buf) {
+            received.push(buf[0]);
+        }
+        received
+    }
+
+    fn send(socket: &SimulatedSocket, datagrams: std::ops::Range<u8>) {
+        for datagram in datagrams {
+            socket.send_to(&[datagram], addr(8080)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_datagrams_arrive_after_latency() {
+        let conditions = LinkConditions {
+            latency: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let (simulated, server) = link(conditions, 0);
+        let network = NetworkConditions {
+            outgoing: conditions,
+            ..Default::default()
+        };
+
+        send(&simulated, 0..3);
+        simulated.update(Duration::from_millis(99), network);
+        assert!(received(&server).is_empty());
+        simulated.update(Duration::from_millis(100), network);
+        assert_eq!(received(&server), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_loss_duplication_and_reordering() {
+        let (simulated, server) = link(
+            LinkConditions {
+                loss: 1.,
+                ..Default::default()
+            },
+            0,
+        );
+        send(&simulated, 0..10);
+        assert!(received(&server).is_empty());
+
+        let (simulated, server) = link(
+            LinkConditions {
+                duplication: 1.,
+                ..Default::default()
+            },
+            0,
+        );
+        send(&simulated, 0..2);
+        assert_eq!(received(&server), vec![0, 0, 1, 1]);
+
+        // Every datagram that isn't letting a held one through is held back itself
+        let (simulated, server) = link(
+            LinkConditions {
+                reordering: 1.,
+                ..Default::default()
+            },
+            0,
+        );
+        send(&simulated, 0..4);
+        assert_eq!(received(&server), vec![1, 0, 3, 2]);
+    }
+
+    #[test]
+    fn test_same_seed_gives_same_network() {
+        let conditions = LinkConditions {
+            loss: 0.5,
+            ..Default::default()
+        };
+        let run = |seed| {
+            let (simulated, server) = link(conditions, seed);
+            send(&simulated, 0..64);
+            received(&server)
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+}
@@ -0,0 +1,350 @@
+/*
+   Encrypts and authenticates every datagram once the handshake is done.
+
+   The server keeps one X25519 key pair for as long as it runs, and the client
+   makes a new one for every connection attempt. The server's public key is
+   sent in its ConnectChallenge and the client's in its ChallengeResponse, so
+   both ends work out the same shared secret without it ever being sent. A
+   ChaCha20-Poly1305 key is derived from it for each direction, the same way
+   NaCl's crypto_box does, salted with a random value the server picks for each
+   challenge. Replaying a captured ChallengeResponse can therefore never bring
+   back the keys of an earlier session, and the server refuses one whose
+   challenge has already been answered anyway.
+
+   The key exchange is not authenticated: neither side can tell whose public
+   key it was sent, and clients don't pin the server's key. This keeps out
+   anyone who can only see or forge traffic, but someone on the path between
+   client and server can still run a handshake with each and relay between them.
+
+   Every datagram is sealed with a nonce made from a counter its sender
+   increments, and the counter travels in the datagram's header, which is
+   authenticated along with it. Receivers remember which of the most recent
+   counters they have seen, so captured datagrams can't be replayed.
+
+   The server encrypts everything it sends a client as soon as the challenge
+   is answered. The client only starts encrypting once a datagram from the
+   server decrypts with the session's keys, which tells it the server has them
+   too; until then it keeps answering the challenge in the clear. From then on
+   both sides drop datagrams that fail to decrypt, as well as plaintext other
+   than the start of a new handshake.
+*/
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use bevy::prelude::*;
+use bytes::{BufMut, Bytes, BytesMut};
+use chacha20::cipher::consts::U10;
+use chacha20::hchacha;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce, Tag};
+use rand::rngs::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::networking::events::{DisconnectReason, NetworkEvent};
+use crate::networking::message::Message;
+use crate::networking::packet::{encrypted_counter, encrypted_header, ENCRYPTED_HEADER_LEN};
+use crate::networking::packet_systems::SocketAddress;
+
+/// Bytes taken up by the authentication tag at the end of an encrypted datagram.
+const TAG_LEN: usize = 16;
+/// Bytes an encrypted datagram takes up on top of the datagram it seals.
+pub const ENCRYPTION_OVERHEAD: usize = ENCRYPTED_HEADER_LEN + TAG_LEN;
+/// How far behind the latest counter received a datagram may fall and still be accepted, since
+/// datagrams can arrive out of order.
+const REPLAY_WINDOW_LEN: u64 = 64;
+
+/// Inputs the key of each direction is derived from the shared secret with.
+const CLIENT_KEY_CONTEXT: &[u8; 16] = b"catch-em:client>";
+const SERVER_KEY_CONTEXT: &[u8; 16] = b"catch-em:server>";
+
+/// The counters of recently received datagrams.
+#[derive(Default)]
+struct ReplayWindow {
+    // Highest counter received so far
+    latest: Option<u64>,
+    // Bit n is set once the counter n below the latest has been received
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn is_new(&self, counter: u64) -> bool {
+        match self.latest {
+            Some(latest) if counter <= latest => {
+                latest - counter < REPLAY_WINDOW_LEN && self.seen & (1 << (latest - counter)) == 0
+            }
+            _ => true,
+        }
+    }
+
+    fn record(&mut self, counter: u64) {
+        match self.latest {
+            Some(latest) if counter <= latest => self.seen |= 1 << (latest - counter),
+            Some(latest) if counter - latest < REPLAY_WINDOW_LEN => {
+                self.seen = (self.seen << (counter - latest)) | 1;
+                self.latest = Some(counter);
+            }
+            _ => {
+                self.seen = 1;
+                self.latest = Some(counter);
+            }
+        }
+    }
+}
+
+struct Session {
+    sending: ChaCha20Poly1305,
+    receiving: ChaCha20Poly1305,
+    next_counter: u64,
+    received: ReplayWindow,
+    // Whether the peer is known to have the keys, so that datagrams sent to it are encrypted
+    confirmed: bool,
+}
+
+#[derive(Resource)]
+pub struct Encryption {
+    secret: StaticSecret,
+    public_key: PublicKey,
+    sessions: HashMap<SocketAddr, Session>,
+}
+
+impl Default for Encryption {
+    fn default() -> Self {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        Self {
+            public_key: PublicKey::from(&secret),
+            secret,
+            sessions: HashMap::new(),
+        }
+    }
+}
+
+impl Encryption {
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public_key.to_bytes()
+    }
+
+    /// Replaces the key pair and forgets every session, for a client starting a new connection.
+    pub fn regenerate(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Starts a session with a client from the public key and salt in its ChallengeResponse,
+    /// replacing any session it had before. The client derived its keys before sending it, so
+    /// the session is confirmed straight away. Returns false if the key can't be used.
+    pub fn accept_client(&mut self, addr: SocketAddr, client_key: [u8; 32], salt: [u8; 16]) -> bool {
+        self.start_session(addr, client_key, salt, SERVER_KEY_CONTEXT, CLIENT_KEY_CONTEXT, true)
+    }
+
+    /// Starts a session with the server from the public key and salt in its ConnectChallenge.
+    /// Nothing is encrypted until the server shows it has the keys too. Returns false if the key
+    /// can't be used.
+    pub fn accept_server(&mut self, addr: SocketAddr, server_key: [u8; 32], salt: [u8; 16]) -> bool {
+        self.start_session(addr, server_key, salt, CLIENT_KEY_CONTEXT, SERVER_KEY_CONTEXT, false)
+    }
+
+    fn start_session(
+        &mut self,
+        addr: SocketAddr,
+        peer_key: [u8; 32],
+        salt: [u8; 16],
+        sending_context: &[u8; 16],
+        receiving_context: &[u8; 16],
+        confirmed: bool,
+    ) -> bool {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(peer_key));
+        // A low order public key would make the shared secret predictable
+        if !shared.was_contributory() {
+            return false;
+        }
+        let salted = hchacha::<U10>(shared.as_bytes().into(), &salt.into());
+        let cipher =
+            |context: &[u8; 16]| ChaCha20Poly1305::new(&hchacha::<U10>(&salted, context.into()));
+        self.sessions.insert(
+            addr,
+            Session {
+                sending: cipher(sending_context),
+                receiving: cipher(receiving_context),
+                next_counter: 0,
+                received: ReplayWindow::default(),
+                confirmed,
+            },
+        );
+        true
+    }
+
+    pub fn has_session(&self, addr: SocketAddr) -> bool {
+        self.sessions.contains_key(&addr)
+    }
+
+    /// Whether datagrams exchanged with `addr` are encrypted.
+    pub fn is_confirmed(&self, addr: SocketAddr) -> bool {
+        self.sessions.get(&addr).is_some_and(|session| session.confirmed)
+    }
+
+    pub fn forget(&mut self, addr: SocketAddr) {
+        self.sessions.remove(&addr);
+    }
+
+    /// Encrypts a datagram bound for `destination` if it has a confirmed session, otherwise
+    /// returns it as it is.
+    pub fn encrypt(&mut self, destination: SocketAddr, datagram: Bytes) -> Bytes {
+        let session = match self.sessions.get_mut(&destination) {
+            Some(session) if session.confirmed => session,
+            _ => return datagram,
+        };
+        let counter = session.next_counter;
+        session.next_counter += 1;
+
+        let header = encrypted_header(counter);
+        let mut buf = BytesMut::with_capacity(datagram.len() + ENCRYPTION_OVERHEAD);
+        buf.put_slice(&header);
+        buf.put_slice(&datagram);
+        let tag = session
+            .sending
+            .encrypt_in_place_detached(&nonce(counter), &header, &mut buf[ENCRYPTED_HEADER_LEN..])
+            .expect("datagram too large to encrypt");
+        buf.put_slice(&tag);
+        buf.freeze()
+    }
+
+    /// Decrypts a datagram from `source`, confirming its session. Returns `None` if the datagram
+    /// is not encrypted, if there is no session with `source`, or if the datagram was forged,
+    /// tampered with or replayed.
+    pub fn decrypt(&mut self, source: SocketAddr, datagram: Bytes) -> Option<Bytes> {
+        let counter = encrypted_counter(&datagram)?;
+        let session = self.sessions.get_mut(&source)?;
+        if datagram.len() < ENCRYPTION_OVERHEAD || !session.received.is_new(counter) {
+            return None;
+        }
+
+        let (header, sealed) = datagram.split_at(ENCRYPTED_HEADER_LEN);
+        let (sealed, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+        let mut buf = sealed.to_vec();
+        session
+            .receiving
+            .decrypt_in_place_detached(&nonce(counter), header, &mut buf, Tag::from_slice(tag))
+            .ok()?;
+        session.received.record(counter);
+        session.confirmed = true;
+        Some(Bytes::from(buf))
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+/// Starts the session with the server from the public key in the first challenge it answers.
+pub fn receive_key_exchange(
+    mut messages: EventReader<Message>,
+    remote_addr: Res<SocketAddress>,
+    mut encryption: ResMut<Encryption>,
+) {
+    for message in messages.iter() {
+        if let Message::ConnectChallenge { public_key, salt, .. } = message {
+            if encryption.has_session(remote_addr.0) {
+                continue;
+            }
+            if !encryption.accept_server(remote_addr.0, *public_key, *salt) {
+                warn!("Server sent an unusable public key");
+            }
+        }
+    }
+}
+
+/// Forgets the session of every client that disconnects, so it has to exchange keys again. A
+/// client that restarted its connection already has the session of its new handshake.
+pub fn forget_disconnected_sessions(
+    mut events: EventReader<NetworkEvent>,
+    mut encryption: ResMut<Encryption>,
+) {
+    for event in events.iter() {
+        match event {
+            NetworkEvent::Disconnected(_, DisconnectReason::Restarted) => (),
+            NetworkEvent::Disconnected(addr, _) => encryption.forget(*addr),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SALT: [u8; 16] = [1; 16];
+
+    fn connect() -> (Encryption, Encryption, SocketAddr, SocketAddr) {
+        let client_addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let server_addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let mut client = Encryption::default();
+        let mut server = Encryption::default();
+        assert!(client.accept_server(server_addr, server.public_key(), SALT));
+        assert!(server.accept_client(client_addr, client.public_key(), SALT));
+        (client, server, client_addr, server_addr)
+    }
+
+    #[test]
+    fn test_client_encrypts_once_server_has_keys() {
+        let (mut client, mut server, client_addr, server_addr) = connect();
+        let datagram = Bytes::from_static(b"\x00hello");
+
+        // The client keeps sending in the clear until it hears from the server
+        assert_eq!(client.encrypt(server_addr, datagram.clone()), datagram);
+        let sealed = server.encrypt(client_addr, datagram.clone());
+        assert_eq!(sealed.len(), datagram.len() + ENCRYPTION_OVERHEAD);
+        assert_eq!(client.decrypt(server_addr, sealed), Some(datagram.clone()));
+
+        let sealed = client.encrypt(server_addr, datagram.clone());
+        assert_ne!(sealed, datagram);
+        assert_eq!(server.decrypt(client_addr, sealed), Some(datagram));
+    }
+
+    #[test]
+    fn test_rejects_tampered_and_replayed_datagrams() {
+        let (mut client, mut server, client_addr, server_addr) = connect();
+        let sealed = server.encrypt(client_addr, Bytes::from_static(b"\x00hello"));
+
+        let mut tampered = sealed.to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(client.decrypt(server_addr, Bytes::from(tampered)), None);
+        assert!(client.decrypt(server_addr, sealed.clone()).is_some());
+        assert_eq!(client.decrypt(server_addr, sealed), None);
+
+        // A session with another key can't read it either
+        let mut other = Encryption::default();
+        assert!(other.accept_server(server_addr, server.public_key(), SALT));
+        let sealed = server.encrypt(client_addr, Bytes::from_static(b"\x00hello"));
+        assert_eq!(other.decrypt(server_addr, sealed), None);
+    }
+
+    #[test]
+    fn test_salt_makes_sessions_with_the_same_keys_differ() {
+        let (mut client, mut server, client_addr, server_addr) = connect();
+        let datagram = Bytes::from_static(b"\x00hello");
+        let earlier = server.encrypt(client_addr, datagram.clone());
+
+        // The same public keys answering another challenge don't give the same keys, so counters
+        // starting over don't reuse any nonce
+        assert!(server.accept_client(client_addr, client.public_key(), [7; 16]));
+        let later = server.encrypt(client_addr, datagram.clone());
+        assert_ne!(earlier, later);
+        assert_eq!(client.decrypt(server_addr, later), None);
+        assert_eq!(client.decrypt(server_addr, earlier), Some(datagram));
+    }
+
+    #[test]
+    fn test_replay_window_accepts_late_datagrams_once() {
+        let mut window = ReplayWindow::default();
+        window.record(10);
+        assert!(window.is_new(7));
+        window.record(7);
+        assert!(!window.is_new(7));
+        assert!(!window.is_new(10));
+        window.record(100);
+        assert!(window.is_new(99));
+        assert!(!window.is_new(100 - REPLAY_WINDOW_LEN));
+    }
+}
@@ -1,5 +1,5 @@
-use std;
-use std::{io, net::SocketAddr};
+use std::fmt;
+use std::net::SocketAddr;
 
 use crate::networking::message::Message;
 use bevy::ecs::event::Event;
@@ -7,16 +7,53 @@ use crate::networking::packet_systems::SocketError;
 
 use super::raw_message::RawMessage;
 
+/// Why a connection ended.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DisconnectReason {
+    /// The peer sent a Disconnect message, e.g. because it is shutting down
+    Requested,
+    /// Nothing was received from the peer within the idle timeout
+    TimedOut,
+    /// The socket reported that the peer is unreachable
+    ConnectionReset,
+    /// The peer sent too many malformed packets
+    Blocked,
+    /// The peer started a new handshake over the existing connection
+    Restarted,
+    /// A reliable message ran out of attempts, so nothing after it could be delivered in order
+    Unacknowledged,
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::Requested => write!(f, "closed by the remote end"),
+            DisconnectReason::TimedOut => write!(f, "timed out"),
+            DisconnectReason::ConnectionReset => write!(f, "connection reset"),
+            DisconnectReason::Blocked => write!(f, "too many malformed packets"),
+            DisconnectReason::Restarted => write!(f, "connection restarted"),
+            DisconnectReason::Unacknowledged => write!(f, "reliable message never acknowledged"),
+        }
+    }
+}
+
 #[derive(Event)]
 pub enum NetworkEvent {
     // A message was received from a client
     RawMessage(SocketAddr, Message),
     // A new client has connected to us
     Connected(SocketAddr),
-    // A client has disconnected from us
-    Disconnected(SocketAddr),
+    // A client has disconnected from us, or we from the server
+    Disconnected(SocketAddr, DisconnectReason),
     // An error occurred while receiving a message
     RecvError(SocketError),
     // An error occurred while sending a message
     SendError(SocketError, RawMessage),
+    // A reliable message was never acknowledged by its destination
+    DeliveryFailed(RawMessage),
+    // A datagram or message that could not be decoded, with its size in bytes
+    MalformedPacket(SocketAddr, usize),
+    // A datagram that failed to decrypt, or arrived unencrypted when it had to be encrypted, with
+    // its size in bytes
+    AuthenticationFailed(SocketAddr, usize),
 }
@@ -0,0 +1,214 @@
+/*
+   Splits encoded packets that do not fit in a single datagram into numbered
+   fragments, and joins them back together on the receiving side.
+
+   Every fragment carries the id of the group it belongs to, its index within
+   the group and the total number of fragments, so they can arrive in any
+   order. Groups that are still incomplete after the reassembly timeout are
+   dropped; reliable packets are resent in full by their channel.
+*/
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bevy::ecs::system::Resource;
+use bytes::{Bytes, BytesMut};
+
+use super::packet::{Packet, FRAGMENT_HEADER_LEN};
+
+/// Default largest datagram that will be sent. Chosen to stay under typical path MTUs once
+/// IP and UDP headers are added.
+pub const DEFAULT_MTU: usize = 1200;
+/// The largest datagram the receive systems will accept.
+pub const MAX_DATAGRAM_SIZE: usize = 65_507;
+/// How long the fragments of an incomplete group are kept around.
+const DEFAULT_REASSEMBLY_TIMEOUT_SECS: f32 = 1.;
+
+struct PartialGroup {
+    fragments: Vec<Option<Bytes>>,
+    received: usize,
+    first_seen: Duration,
+}
+
+#[derive(Resource)]
+pub struct Fragmenter {
+    pub mtu: usize,
+    pub reassembly_timeout: Duration,
+    next_group: u16,
+    incomplete: HashMap<(SocketAddr, u16), PartialGroup>,
+}
+
+impl Fragmenter {
+    pub fn new(mtu: usize) -> Self {
+        assert!(mtu > FRAGMENT_HEADER_LEN, "MTU must leave room for the fragment header");
+        Self {
+            mtu,
+            reassembly_timeout: Duration::from_secs_f32(DEFAULT_REASSEMBLY_TIMEOUT_SECS),
+            next_group: 0,
+            incomplete: Default::default(),
+        }
+    }
+
+    /// The longest encoded packet that can be sent, split into as many fragments as the header
+    /// can count.
+    pub fn max_packet_len(&self) -> usize {
+        u8::MAX as usize * (self.mtu - FRAGMENT_HEADER_LEN)
+    }
+
+    /// Returns the datagrams needed to send an encoded packet: the packet itself if it fits within
+    /// the MTU, otherwise its fragments. Returns `None` if it needs more fragments than the header
+    /// can count.
+    pub fn split(&mut self, encoded: Bytes) -> Option<Vec<Bytes>> {
+        if encoded.len() <= self.mtu {
+            return Some(vec![encoded]);
+        }
+
+        let chunk_len = self.mtu - FRAGMENT_HEADER_LEN;
+        let count = u8::try_from(encoded.len().div_ceil(chunk_len)).ok()?;
+        let group = self.next_group;
+        self.next_group = group.wrapping_add(1);
+
+        let datagrams = (0..count)
+            .map(|index| {
+                let start = index as usize * chunk_len;
+                let end = (start + chunk_len).min(encoded.len());
+                Packet::Fragment {
+                    group,
+                    index,
+                    count,
+                    payload: encoded.slice(start..end),
+                }
+                .encode()
+            })
+            .collect();
+        Some(datagrams)
+    }
+
+    /// Stores a received fragment and returns the encoded packet once every fragment of its
+    /// group has arrived.
+    pub fn reassemble(
+        &mut self,
+        source: SocketAddr,
+        group: u16,
+        index: u8,
+        count: u8,
+        payload: Bytes,
+        now: Duration,
+    ) -> Option<Bytes> {
+        if index >= count {
+            return None;
+        }
+
+        let partial = self
+            .incomplete
+            .entry((source, group))
+            .or_insert_with(|| PartialGroup {
+                fragments: vec![None; count as usize],
+                received: 0,
+                first_seen: now,
+            });
+        if partial.fragments.len() != count as usize {
+            // Disagrees with the fragments seen so far, so the group can never be completed.
+            self.incomplete.remove(&(source, group));
+            return None;
+        }
+
+        let slot = &mut partial.fragments[index as usize];
+        if slot.is_none() {
+            *slot = Some(payload);
+            partial.received += 1;
+        }
+        if partial.received < partial.fragments.len() {
+            return None;
+        }
+
+        let partial = self.incomplete.remove(&(source, group))?;
+        let mut packet = BytesMut::new();
+        for fragment in partial.fragments.into_iter().flatten() {
+            packet.extend_from_slice(&fragment);
+        }
+        Some(packet.freeze())
+    }
+
+    /// Drops every group that has been waiting on fragments for longer than the timeout.
+    pub fn expire(&mut self, now: Duration) {
+        let timeout = self.reassembly_timeout;
+        self.incomplete
+            .retain(|_, partial| now - partial.first_seen <= timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_packets_are_not_fragmented() {
+        let mut fragmenter = Fragmenter::new(64);
+        let encoded = Packet::Unreliable(Bytes::from_static(b"test")).encode();
+
+        assert_eq!(fragmenter.split(encoded.clone()), Some(vec![encoded]));
+    }
+
+    #[test]
+    fn test_reassembles_out_of_order_fragments() {
+        let mut fragmenter = Fragmenter::new(64);
+        let encoded = Packet::Unreliable(Bytes::from(vec![7; 500])).encode();
+
+        let mut datagrams = fragmenter.split(encoded.clone()).unwrap();
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|datagram| datagram.len() <= 64));
+        datagrams.reverse();
+
+        let mut reassembled = None;
+        for datagram in datagrams {
+            assert!(reassembled.is_none());
+            reassembled = receive(&mut fragmenter, datagram, Duration::ZERO);
+        }
+        assert_eq!(reassembled, Some(encoded));
+    }
+
+    #[test]
+    fn test_incomplete_groups_expire() {
+        let mut fragmenter = Fragmenter::new(64);
+        let encoded = Packet::Unreliable(Bytes::from(vec![7; 500])).encode();
+
+        let datagrams = fragmenter.split(encoded).unwrap();
+        let (last, rest) = datagrams.split_last().unwrap();
+        for datagram in rest {
+            receive(&mut fragmenter, datagram.clone(), Duration::ZERO);
+        }
+
+        let later = fragmenter.reassembly_timeout * 2;
+        fragmenter.expire(later);
+        assert_eq!(receive(&mut fragmenter, last.clone(), later), None);
+    }
+
+    #[test]
+    fn test_rejects_packets_needing_too_many_fragments() {
+        let mut fragmenter = Fragmenter::new(FRAGMENT_HEADER_LEN + 1);
+
+        assert_eq!(fragmenter.split(Bytes::from(vec![0; 300])), None);
+
+        let max = fragmenter.max_packet_len();
+        assert!(fragmenter.split(Bytes::from(vec![0; max])).is_some());
+        assert_eq!(fragmenter.split(Bytes::from(vec![0; max + 1])), None);
+    }
+
+    fn receive(fragmenter: &mut Fragmenter, datagram: Bytes, now: Duration) -> Option<Bytes> {
+        match Packet::decode(datagram) {
+            Some(Packet::Fragment {
+                group,
+                index,
+                count,
+                payload,
+            }) => fragmenter.reassemble(test_addr(), group, index, count, payload, now),
+            other => panic!("expected a fragment, got {:?}", other),
+        }
+    }
+
+    fn test_addr() -> SocketAddr {
+        "127.0.0.1:3000".parse().unwrap()
+    }
+}
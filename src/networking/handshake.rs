@@ -1,74 +1,612 @@
 /*
    This controls how the server and client decide initial network details once
    the server receives the initial connection. These details are:
+       - Whether the client speaks the same protocol and runs the same build
        - PlayerId for the newly connected client
 
+   The handshake runs in the following steps:
+       1. The client repeatedly sends a ConnectRequest carrying its protocol
+          version and build hash.
+       2. The server rejects mismatched clients with a reason, and answers the
+          rest with a ConnectChallenge made of a random salt and a token derived
+          from it, their address and the current time, which goes stale after a
+          short while.
+       3. The client repeatedly echoes the token and salt back in a
+          ChallengeResponse, which the server only accepts once. Both of these
+          messages also say whether their sender accepts compressed datagrams,
+          and carry its public key for the key exchange everything after this
+          step is encrypted with.
+       4. Once the token checks out the server considers the address connected
+          and sends the client its PlayerId and a random session token, which
+          the client acknowledges before telling the server the name of its
+          player.
+
+   A client that loses its connection keeps retrying the handshake with an
+   increasing delay. If it still holds a session token from its last connection
+   it answers step 4 with ResumeSession instead, and the server hands it back
+   its previous PlayerId, with a new token, if that player has not been
+   cleaned up yet. Only the token of the session that was suspended is
+   accepted, and it is forgotten along with the player, so an id that has
+   since been given to someone else can't be taken over with an old token.
+
+   Because the challenge only reaches the real owner of an address, a spoofed
+   source address can never get past step 3, and the server keeps no state for
+   an address until it does. The server answers steps 1 and 3 in the clear even
+   for an address it considers connected, since its client may have lost track
+   of the connection and started over, but only replaces the connection once a
+   new challenge has been answered.
+
    The client cannot receive any other server communication until this handshake
    is completed.
 */
 
-use crate::networking::message::Message::{ClientAcknowledgement, ServerAcknowledgement};
-use crate::networking::message::{serialize, Message};
-use crate::networking::packet_systems::Socket;
-use crate::networking::Transport;
+use crate::networking::codec::Codec;
+use crate::networking::compression::Compression;
+use crate::networking::encryption::Encryption;
+use crate::networking::events::DisconnectReason;
+use crate::networking::components::NetworkObject;
+use crate::networking::message::Message::{
+    ChallengeResponse, ClientAcknowledgement, ConnectChallenge, ConnectionRejected,
+    ResumeSession, ServerAcknowledgement,
+};
+use crate::networking::message::{serialize, Message, WireCodec};
+use crate::networking::packet_systems::{Socket, SocketAddress};
+use crate::networking::prediction::Prediction;
+use crate::networking::world_snapshot::ClientSnapshots;
+use crate::networking::reliable::ReliableChannels;
+use crate::networking::{NetworkResource, Transport};
 use bevy::ecs::system::Resource;
-use bevy::prelude::{EventReader, Res, ResMut};
+use bevy::prelude::{
+    debug, error, info, Commands, DetectChanges, Entity, EventReader, Query, Res, ResMut, Time,
+    Timer, TimerMode, With,
+};
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
 
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::BuildHasher;
 use std::net::SocketAddr;
+use std::time::Duration;
 use crate::networking::resources::{PlayerId, Players};
 
+/// Bumped whenever the wire format or `Message` enum changes incompatibly.
+pub const PROTOCOL_VERSION: u16 = 15;
+
+/// Identifies the build of the game, so that clients and servers built from different sources
+/// refuse each other even when the protocol version was not bumped. The source files are hashed
+/// by build.rs.
+pub fn build_hash() -> u64 {
+    // FNV-1a, so the hash is stable across builds and platforms.
+    let build = concat!(
+        env!("CARGO_PKG_NAME"),
+        "@",
+        env!("CARGO_PKG_VERSION"),
+        "+",
+        env!("CATCH_EM_SOURCE_HASH")
+    );
+    // Builds using another codec can't understand anything past the handshake
+    let codec = WireCodec::NAME;
+    build.bytes().chain(codec.bytes()).fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[derive(PartialEq, Debug, Serialize, Deserialize, Copy, Clone)]
+pub enum RejectReason {
+    ProtocolMismatch { server: u16, client: u16 },
+    BuildMismatch { server: u64, client: u64 },
+    ServerFull,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::ProtocolMismatch { server, client } => write!(
+                f,
+                "protocol version mismatch (server {}, client {})",
+                server, client
+            ),
+            RejectReason::BuildMismatch { server, client } => write!(
+                f,
+                "game build mismatch (server {:016x}, client {:016x})",
+                server, client
+            ),
+            RejectReason::ServerFull => write!(f, "server is full"),
+        }
+    }
+}
+
 #[derive(Resource, Debug)]
 pub enum ConnectionStatus {
-    Initial,      // Client has just sent connection to server
+    Initial,      // Client is sending connect requests to the server
+    Challenged(u64, [u8; 16]), // Client is echoing the server's challenge token and salt
     Complete,     // Client has sent server acknowledgement
+    Rejected(RejectReason), // Server refused the connection
+    Disconnected { reason: DisconnectReason }, // Connection to the server was lost or closed
+}
+
+/// Defines how often the client repeats its connect request or challenge response
+/// until the server answers.
+const DEFAULT_CONNECT_RESEND_SECS: f32 = 0.5;
+
+#[derive(Resource)]
+pub struct ConnectRequestTimer(Timer);
+
+impl Default for ConnectRequestTimer {
+    fn default() -> Self {
+        let mut timer = Timer::from_seconds(DEFAULT_CONNECT_RESEND_SECS, TimerMode::Repeating);
+        // Send the first request on the first frame rather than after a full interval.
+        timer.set_elapsed(Duration::from_secs_f32(DEFAULT_CONNECT_RESEND_SECS));
+        Self(timer)
+    }
+}
+
+/// Keeps sending the server whichever handshake message the client is waiting on an answer to.
+/// These are sent unreliably, since the server keeps no per-address state to acknowledge them with.
+pub fn send_connect_requests(
+    time: Res<Time>,
+    mut timer: ResMut<ConnectRequestTimer>,
+    remote_addr: Res<SocketAddress>,
+    connection_status: Res<ConnectionStatus>,
+    compression: Res<Compression>,
+    encryption: Res<Encryption>,
+    mut transport: ResMut<Transport>,
+) {
+    if !timer.0.tick(time.delta()).just_finished() {
+        return;
+    }
+    let message = match *connection_status {
+        ConnectionStatus::Initial => Message::ConnectRequest {
+            protocol_version: PROTOCOL_VERSION,
+            build_hash: build_hash(),
+        },
+        ConnectionStatus::Challenged(token, salt) => ChallengeResponse {
+            token,
+            salt,
+            compression: compression.enabled,
+            public_key: encryption.public_key(),
+        },
+        _ => return,
+    };
+
+    transport.send(remote_addr.0, &serialize(message));
+}
+
+/// The name the client's player goes by.
+#[derive(Resource)]
+pub struct LocalPlayerName(pub String);
+
+/// Tells the server the name of the player whenever a handshake completes. Runs after the
+/// acknowledgement completing it is queued, so that the server has a player to name by then.
+pub fn send_player_name(
+    connection_status: Res<ConnectionStatus>,
+    name: Res<LocalPlayerName>,
+    remote_addr: Res<SocketAddress>,
+    mut transport: ResMut<Transport>,
+) {
+    if connection_status.is_changed() && matches!(*connection_status, ConnectionStatus::Complete) {
+        transport.send_reliable(remote_addr.0, &serialize(Message::PlayerName(name.0.clone())));
+    }
 }
 
+/// Challenge tokens are stamped with the period they were handed out in, and are accepted during
+/// that period and the next one.
+const CHALLENGE_PERIOD_SECS: u64 = 10;
+
+/// Server side secret used to derive the challenge token for each address, along with the
+/// challenges that have already been answered.
+#[derive(Resource, Default)]
+pub struct ConnectionChallenges {
+    secret: RandomState,
+    // Tokens whose ChallengeResponse was accepted, with when, kept until they would be stale
+    answered: HashMap<u64, Duration>,
+}
+
+impl ConnectionChallenges {
+    /// Makes a new challenge for `addr`, returning its token and the salt of the session's keys.
+    pub fn challenge(&self, addr: SocketAddr, now: Duration) -> (u64, [u8; 16]) {
+        let salt = rand::random();
+        (self.token_in_period(addr, salt, now.as_secs() / CHALLENGE_PERIOD_SECS), salt)
+    }
+
+    /// Whether `token` was handed out to `addr` along with `salt` recently enough to still be
+    /// accepted.
+    pub fn is_valid_token(&self, addr: SocketAddr, token: u64, salt: [u8; 16], now: Duration) -> bool {
+        let period = now.as_secs() / CHALLENGE_PERIOD_SECS;
+        token == self.token_in_period(addr, salt, period)
+            || period > 0 && token == self.token_in_period(addr, salt, period - 1)
+    }
+
+    /// Accepts the answer to a challenge if its token is valid and was not answered before, so
+    /// a replayed ChallengeResponse can't start the same session twice.
+    pub fn answer(&mut self, addr: SocketAddr, token: u64, salt: [u8; 16], now: Duration) -> bool {
+        let lifetime = Duration::from_secs(2 * CHALLENGE_PERIOD_SECS);
+        self.answered.retain(|_, answered_at| now.saturating_sub(*answered_at) < lifetime);
+        self.is_valid_token(addr, token, salt, now) && self.answered.insert(token, now).is_none()
+    }
+
+    fn token_in_period(&self, addr: SocketAddr, salt: [u8; 16], period: u64) -> u64 {
+        self.secret.hash_one(("challenge", addr, salt, period))
+    }
+}
+
+/// The player id and session token of the client's latest connection, used to resume it after
+/// reconnecting.
+#[derive(Resource, Default, Debug)]
+pub struct Session(pub Option<(PlayerId, u64)>);
+
+/// Delay before the first reconnection attempt, doubled after every failed attempt.
+const RECONNECT_BASE_DELAY_SECS: f32 = 1.;
+/// Upper bound on the delay between reconnection attempts.
+const RECONNECT_MAX_DELAY_SECS: f32 = 30.;
+
+#[derive(Resource, Default)]
+pub struct Reconnect {
+    attempts: u32,
+    timer: Option<Timer>,
+}
+
+impl Reconnect {
+    pub fn delay(attempts: u32) -> Duration {
+        let delay = RECONNECT_BASE_DELAY_SECS * 2f32.powi(attempts.min(16) as i32);
+        Duration::from_secs_f32(delay.min(RECONNECT_MAX_DELAY_SECS))
+    }
+}
+
+/// Once the client has lost its connection, waits out the backoff delay and then starts the
+/// handshake over again with a new key pair. Every networked entity is despawned first, since the
+/// server resends the whole world when the handshake completes. The local player's prediction and
+/// the world snapshots are forgotten too, since input numbers and the server's ticks may both start
+/// over.
+#[allow(clippy::too_many_arguments)]
+pub fn reconnect_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    remote_addr: Res<SocketAddress>,
+    mut reconnect: ResMut<Reconnect>,
+    mut connection_status: ResMut<ConnectionStatus>,
+    mut connect_timer: ResMut<ConnectRequestTimer>,
+    mut net: ResMut<NetworkResource>,
+    mut reliable: ResMut<ReliableChannels>,
+    mut encryption: ResMut<Encryption>,
+    mut prediction: ResMut<Prediction>,
+    mut snapshots: ResMut<ClientSnapshots>,
+    networked_entities: Query<Entity, With<NetworkObject>>,
+) {
+    match *connection_status {
+        ConnectionStatus::Complete => {
+            reconnect.attempts = 0;
+            return;
+        }
+        ConnectionStatus::Disconnected { .. } => (),
+        _ => return,
+    }
+
+    let attempts = reconnect.attempts;
+    let timer = reconnect
+        .timer
+        .get_or_insert_with(|| Timer::new(Reconnect::delay(attempts), TimerMode::Once));
+    if !timer.tick(time.delta()).finished() {
+        return;
+    }
+
+    info!("Reconnecting to server (attempt {})", attempts + 1);
+    reconnect.timer = None;
+    reconnect.attempts += 1;
+    for entity in networked_entities.iter() {
+        commands.entity(entity).despawn();
+    }
+    reliable.remove(&remote_addr.0);
+    encryption.regenerate();
+    prediction.reset();
+    snapshots.reset();
+    net.connections.insert(remote_addr.0, time.elapsed());
+    *connect_timer = ConnectRequestTimer::default();
+    *connection_status = ConnectionStatus::Initial;
+}
+
+/// Handles a message from an address the server does not consider connected yet, or that sent it
+/// in the clear to start over. Only ConnectRequest and ChallengeResponse are answered, in the
+/// clear. Returns true once the address has echoed a new challenge token along with a usable
+/// public key, meaning it should now be treated as connected with a new session.
+pub fn handle_unconnected_message(
+    addr: SocketAddr,
+    message: &Message,
+    now: Duration,
+    challenges: &mut ConnectionChallenges,
+    compression: &mut Compression,
+    encryption: &mut Encryption,
+    transport: &mut Transport,
+) -> bool {
+    match message {
+        Message::ConnectRequest {
+            protocol_version,
+            build_hash,
+        } => {
+            let reply = match check_client_version(*protocol_version, *build_hash) {
+                Ok(()) => {
+                    let (token, salt) = challenges.challenge(addr, now);
+                    ConnectChallenge {
+                        token,
+                        salt,
+                        compression: compression.enabled,
+                        public_key: encryption.public_key(),
+                    }
+                }
+                Err(reason) => {
+                    info!("{}: rejecting connection: {}", addr, reason);
+                    ConnectionRejected(reason)
+                }
+            };
+            transport.send_unencrypted(addr, &serialize(reply));
+            false
+        }
+        ChallengeResponse {
+            token,
+            salt,
+            compression: accepts,
+            public_key,
+        } => {
+            if !challenges.answer(addr, *token, *salt, now) {
+                return false;
+            }
+            if !encryption.accept_client(addr, *public_key, *salt) {
+                info!("{}: rejecting connection: unusable public key", addr);
+                return false;
+            }
+            compression.set_peer_accepts(addr, *accepts);
+            true
+        }
+        _ => {
+            debug!("{}: ignoring {:?} from unconnected address", addr, message);
+            false
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn listen_handshake_events(
     mut messages: EventReader<Message>,
     socket: Res<Socket>,
     mut transport: ResMut<Transport>,
     mut local_player_id: ResMut<PlayerId>,
     mut connection_status: ResMut<ConnectionStatus>,
+    mut session: ResMut<Session>,
+    compression: Res<Compression>,
+    encryption: Res<Encryption>,
 ) {
     for message in messages.iter() {
         match message {
-            ServerAcknowledgement(id) => client_handshake(
+            ConnectChallenge { token, salt, .. } => {
+                if let ConnectionStatus::Initial = *connection_status {
+                    *connection_status = ConnectionStatus::Challenged(*token, *salt);
+                    transport.send(
+                        socket
+                            .peer_addr()
+                            .expect("Socket address could not be found"),
+                        &serialize(ChallengeResponse {
+                            token: *token,
+                            salt: *salt,
+                            compression: compression.enabled,
+                            public_key: encryption.public_key(),
+                        }),
+                    );
+                }
+            }
+            ServerAcknowledgement(id, session_token) => client_handshake(
                 id,
+                *session_token,
                 &socket,
                 &mut transport,
                 &mut local_player_id,
                 &mut connection_status,
+                &mut session,
             ),
+            ConnectionRejected(reason) => {
+                error!("Server rejected connection: {}", reason);
+                *connection_status = ConnectionStatus::Rejected(*reason);
+            }
             _ => (),
         }
     }
 }
 
-pub fn server_handshake(handle: &SocketAddr, transport: &mut ResMut<Transport>) {
-    // Generate player id for client
-    let player_id: PlayerId = Players::generate_id();
-    // Send client this id
-    let message = Message::ServerAcknowledgement(player_id);
+/// Checks that a connecting client can talk to this server.
+pub fn check_client_version(protocol_version: u16, build_hash: u64) -> Result<(), RejectReason> {
+    if protocol_version != PROTOCOL_VERSION {
+        return Err(RejectReason::ProtocolMismatch {
+            server: PROTOCOL_VERSION,
+            client: protocol_version,
+        });
+    }
+    if build_hash != self::build_hash() {
+        return Err(RejectReason::BuildMismatch {
+            server: self::build_hash(),
+            client: build_hash,
+        });
+    }
+    Ok(())
+}
+
+pub fn server_handshake(
+    handle: &SocketAddr,
+    players: &mut Players,
+    now: Duration,
+    transport: &mut ResMut<Transport>,
+) {
+    // Reserve player id for client
+    let message = match players.assign_id(*handle, now) {
+        // Send client this id, along with the token it can later resume the session with
+        Some(player_id) => {
+            Message::ServerAcknowledgement(player_id, players.session_tokens[&player_id])
+        }
+        None => {
+            info!("{}: rejecting connection: {}", handle, RejectReason::ServerFull);
+            ConnectionRejected(RejectReason::ServerFull)
+        }
+    };
 
-    transport.send(*handle, &serialize(message));
+    transport.send_reliable(*handle, &serialize(message));
 }
 
 fn client_handshake(
     assigned_player_id: &PlayerId,
+    session_token: u64,
     socket: &Res<Socket>,
     transport: &mut ResMut<Transport>,
     local_player_id: &mut ResMut<PlayerId>,
     connection_status: &mut ResMut<ConnectionStatus>,
+    session: &mut ResMut<Session>,
 ) {
     **local_player_id = *assigned_player_id;
     **connection_status = ConnectionStatus::Complete;
     println!("Doing client handshake");
-    let message = ClientAcknowledgement(*assigned_player_id);
+    let message = match session.0.replace((*assigned_player_id, session_token)) {
+        Some((previous, previous_token)) => ResumeSession {
+            previous,
+            session_token: previous_token,
+            assigned: *assigned_player_id,
+        },
+        None => ClientAcknowledgement(*assigned_player_id),
+    };
 
-    transport.send(
+    transport.send_reliable(
         socket
             .peer_addr()
             .expect("Socket address could not be found"),
         &serialize(message),
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::raw_message::Delivery;
+
+    #[test]
+    fn test_challenge_flow() {
+        let mut challenges = ConnectionChallenges::default();
+        let mut compression = Compression::new(true);
+        let mut encryption = Encryption::default();
+        let client = Encryption::default();
+        let mut transport = Transport::new();
+        let addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let request = Message::ConnectRequest {
+            protocol_version: PROTOCOL_VERSION,
+            build_hash: build_hash(),
+        };
+
+        let mut handle = |message: &Message, transport: &mut Transport| {
+            handle_unconnected_message(
+                addr,
+                message,
+                Duration::ZERO,
+                &mut challenges,
+                &mut compression,
+                &mut encryption,
+                transport,
+            )
+        };
+
+        let mut challenge = |transport: &mut Transport| {
+            assert!(!handle(&request, transport));
+            let sent = transport.drain_messages_to_send(|_| true);
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].delivery, Delivery::Unencrypted);
+            match crate::networking::message::deserialize(sent[0].payload.clone()) {
+                Ok(ConnectChallenge { token, salt, compression: true, .. }) => (token, salt),
+                other => panic!("expected a challenge, got {:?}", other),
+            }
+        };
+        let (unusable_token, unusable_salt) = challenge(&mut transport);
+        let (token, salt) = challenge(&mut transport);
+
+        let response = |token, salt, public_key| ChallengeResponse {
+            token,
+            salt,
+            compression: true,
+            public_key,
+        };
+        assert!(!handle(&response(!token, salt, client.public_key()), &mut transport));
+        assert!(!handle(&response(token, [0; 16], client.public_key()), &mut transport));
+        // An all zero key is of low order, so it would make the shared secret predictable
+        assert!(!handle(&response(unusable_token, unusable_salt, [0; 32]), &mut transport));
+        assert!(handle(&response(token, salt, client.public_key()), &mut transport));
+        // A replayed response can't start the session over
+        assert!(!handle(&response(token, salt, client.public_key()), &mut transport));
+        assert!(encryption.is_confirmed(addr));
+    }
+
+    #[test]
+    fn test_every_challenge_has_its_own_salt() {
+        let challenges = ConnectionChallenges::default();
+        let addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+
+        let (first, first_salt) = challenges.challenge(addr, Duration::ZERO);
+        let (second, second_salt) = challenges.challenge(addr, Duration::ZERO);
+        assert_ne!(first, second);
+        assert_ne!(first_salt, second_salt);
+        assert!(challenges.is_valid_token(addr, first, first_salt, Duration::ZERO));
+        assert!(!challenges.is_valid_token(addr, first, second_salt, Duration::ZERO));
+    }
+
+    #[test]
+    fn test_reconnect_delay_backs_off() {
+        assert_eq!(Reconnect::delay(0), Duration::from_secs(1));
+        assert_eq!(Reconnect::delay(1), Duration::from_secs(2));
+        assert_eq!(Reconnect::delay(3), Duration::from_secs(8));
+        assert_eq!(Reconnect::delay(100), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_challenge_is_bound_to_address() {
+        let challenges = ConnectionChallenges::default();
+        let addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let spoofed: SocketAddr = "127.0.0.1:3001".parse().unwrap();
+
+        let (token, salt) = challenges.challenge(addr, Duration::ZERO);
+        assert!(challenges.is_valid_token(addr, token, salt, Duration::ZERO));
+        assert!(!challenges.is_valid_token(spoofed, token, salt, Duration::ZERO));
+    }
+
+    #[test]
+    fn test_challenge_goes_stale() {
+        let challenges = ConnectionChallenges::default();
+        let addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let period = Duration::from_secs(CHALLENGE_PERIOD_SECS);
+
+        let (token, salt) = challenges.challenge(addr, period - Duration::from_millis(1));
+        assert!(challenges.is_valid_token(addr, token, salt, period));
+        assert!(challenges.is_valid_token(addr, token, salt, period * 2 - Duration::from_millis(1)));
+        assert!(!challenges.is_valid_token(addr, token, salt, period * 2));
+    }
+
+    #[test]
+    fn test_accepts_matching_client() {
+        assert_eq!(check_client_version(PROTOCOL_VERSION, build_hash()), Ok(()));
+    }
+
+    #[test]
+    fn test_rejects_protocol_mismatch() {
+        assert_eq!(
+            check_client_version(PROTOCOL_VERSION + 1, build_hash()),
+            Err(RejectReason::ProtocolMismatch {
+                server: PROTOCOL_VERSION,
+                client: PROTOCOL_VERSION + 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_rejects_build_mismatch() {
+        assert_eq!(
+            check_client_version(PROTOCOL_VERSION, !build_hash()),
+            Err(RejectReason::BuildMismatch {
+                server: build_hash(),
+                client: !build_hash(),
+            })
+        );
+    }
+}
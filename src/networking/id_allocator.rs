@@ -0,0 +1,120 @@
+/*
+   Hands out the ids the server assigns to players and network objects.
+
+   Ids are never handed out twice while in use. Once released, an id waits out
+   a cooldown before it is reused, so that messages still in flight for the
+   previous holder (such as reliable resends) can't be mistaken for messages
+   about the new one. Released ids are reused oldest first.
+
+   Id 0 is never allocated, so clients can use it to mean "not assigned yet".
+*/
+
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+
+/// How long a released id is held back before it can be allocated again.
+const DEFAULT_RECYCLE_COOLDOWN_SECS: f32 = 10.;
+
+#[derive(Debug)]
+pub struct IdAllocator {
+    pub cooldown: Duration,
+    // Lowest id that has never been allocated, or None once every id has been
+    next_fresh: Option<u16>,
+    // Ids released so far along with when, oldest first
+    released: VecDeque<(u16, Duration)>,
+    in_use: HashSet<u16>,
+}
+
+impl Default for IdAllocator {
+    fn default() -> Self {
+        Self::new(Duration::from_secs_f32(DEFAULT_RECYCLE_COOLDOWN_SECS))
+    }
+}
+
+impl IdAllocator {
+    pub fn new(cooldown: Duration) -> Self {
+        Self {
+            cooldown,
+            next_fresh: Some(1),
+            released: Default::default(),
+            in_use: Default::default(),
+        }
+    }
+
+    /// Returns an id that is not currently in use, or `None` if every id is either in use or
+    /// still cooling down.
+    pub fn allocate(&mut self, now: Duration) -> Option<u16> {
+        let id = match self.released.front() {
+            Some((id, released_at)) if now.saturating_sub(*released_at) >= self.cooldown => {
+                let id = *id;
+                self.released.pop_front();
+                id
+            }
+            _ => {
+                let id = self.next_fresh?;
+                self.next_fresh = id.checked_add(1);
+                id
+            }
+        };
+
+        self.in_use.insert(id);
+        Some(id)
+    }
+
+    /// Makes an id available again once the cooldown has passed. Ids that aren't in use are
+    /// ignored, so releasing twice is harmless.
+    pub fn release(&mut self, id: u16, now: Duration) {
+        if self.in_use.remove(&id) {
+            self.released.push_back((id, now));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_collisions_across_many_spawns() {
+        let mut allocator = IdAllocator::new(Duration::from_secs(1));
+        let mut live = HashSet::new();
+        let mut now = Duration::ZERO;
+
+        for spawn in 0..50_000u32 {
+            now += Duration::from_millis(10);
+            let id = allocator.allocate(now).expect("ran out of ids");
+            assert_ne!(id, 0);
+            assert!(live.insert(id), "id {} handed out twice", id);
+
+            // Keep a churning population of players coming and going
+            if spawn % 3 != 0 {
+                let despawned = *live.iter().next().unwrap();
+                live.remove(&despawned);
+                allocator.release(despawned, now);
+            }
+        }
+    }
+
+    #[test]
+    fn test_released_ids_wait_out_the_cooldown() {
+        let mut allocator = IdAllocator::new(Duration::from_secs(5));
+
+        let first = allocator.allocate(Duration::ZERO).unwrap();
+        allocator.release(first, Duration::ZERO);
+        assert_ne!(allocator.allocate(Duration::from_secs(1)), Some(first));
+        assert_eq!(allocator.allocate(Duration::from_secs(5)), Some(first));
+    }
+
+    #[test]
+    fn test_exhaustion() {
+        let mut allocator = IdAllocator::new(Duration::from_secs(5));
+        for _ in 0..u16::MAX {
+            assert!(allocator.allocate(Duration::ZERO).is_some());
+        }
+        assert_eq!(allocator.allocate(Duration::ZERO), None);
+
+        allocator.release(7, Duration::ZERO);
+        assert_eq!(allocator.allocate(Duration::ZERO), None);
+        assert_eq!(allocator.allocate(Duration::from_secs(5)), Some(7));
+    }
+}
@@ -2,27 +2,139 @@ use crate::networking::resources::PlayerId;
 use bevy::ecs::event::Event;
 use bevy::prelude::Vec3;
 use bytes::Bytes;
+use std::fmt;
+use std::time::Duration;
 
-use crate::networking::components::NetworkObjectType;
+#[cfg(not(feature = "packed-codec"))]
+use crate::networking::codec::CborCodec;
+use crate::networking::codec::Codec;
+#[cfg(feature = "packed-codec")]
+use crate::networking::codec::PackedCodec;
+use crate::networking::components::{NetworkObjectType, ObjectId, ObjectState};
+use crate::networking::handshake::RejectReason;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
 
-#[derive(PartialEq, Debug, Serialize, Deserialize, Event, Copy, Clone)]
+#[derive(PartialEq, Debug, Serialize, Deserialize, Event, Clone)]
 pub enum Message {
-    Spawn(PlayerId, Vec3, NetworkObjectType, u8),
-    Despawn(PlayerId, u8),
-    NetworkPosition(PlayerId, Vec3, u8),
-    NetworkInput { w: bool, s: bool, a: bool, d: bool },
+    // Opens the client->server handshake. Its name and fields must not change between protocol
+    // versions, so that mismatched clients can still be told why they were rejected.
+    ConnectRequest { protocol_version: u16, build_hash: u64 },
+    // Sent instead of ConnectChallenge when the server refuses a client
+    ConnectionRejected(RejectReason),
+    // Token and salt the client must echo back to prove it owns its source address
+    // Both also say whether the sender accepts compressed datagrams, and carry the sender's public
+    // key for the key exchange, whose keys are salted with the salt. See encryption.rs.
+    ConnectChallenge { token: u64, salt: [u8; 16], compression: bool, public_key: [u8; 32] },
+    ChallengeResponse { token: u64, salt: [u8; 16], compression: bool, public_key: [u8; 32] },
+    // Sent by either side as it shuts down, so the other can clean up without waiting for a timeout
+    Disconnect,
+    Spawn(PlayerId, Vec3, NetworkObjectType, ObjectId),
+    Despawn(PlayerId, ObjectId),
+    // Asks the server to throw an item from the sender's player
+    Throw,
+    // The objects that changed on a server tick since the baseline tick, or every object when
+    // there is no baseline. See world_snapshot.rs.
+    WorldSnapshot {
+        tick: u32,
+        baseline: Option<u32>,
+        changed: Vec<(PlayerId, ObjectId, ObjectState)>,
+        removed: Vec<(PlayerId, ObjectId)>,
+    },
+    // Sent by the client for every WorldSnapshot it decodes
+    SnapshotAck(u32),
+    // Sent by the client every frame, with how long the frame lasted. The server moves the
+    // client's player by each one in order, for the same length of time.
+    NetworkInput {
+        sequence: u32,
+        dt: Duration,
+        w: bool,
+        s: bool,
+        a: bool,
+        d: bool,
+        jump: bool,
+        crouch: bool,
+        sprint: bool,
+        yaw: f32,
+        pitch: f32,
+    },
+    // Sent by the server to each client with the state of its own player after the latest
+    // NetworkInput it applied, for the client to check its prediction against
+    PlayerState { sequence: u32, position: Vec3, velocity: Vec3, tick: u32 },
+    // Time sync: the client sends its own clock, which the server echoes back with its tick
+    Ping(Duration),
+    Pong { client_time: Duration, tick: u32, tick_duration: Duration },
     // Used in initial server->client handshake to pass network info to client
-    ServerAcknowledgement(PlayerId),
+    ServerAcknowledgement(PlayerId, u64),
     ClientAcknowledgement(PlayerId),
+    // Sent instead of ClientAcknowledgement by a reconnecting client, asking for its previous
+    // player back. The server falls back to the assigned id if the session can't be resumed.
+    ResumeSession { previous: PlayerId, session_token: u64, assigned: PlayerId },
+    SessionResumed(PlayerId, u64),
+    // Sent by the client once the handshake completes, with the name its player goes by
+    PlayerName(String),
+    // The serialized value of a replicated component of an object, see replication.rs
+    ComponentUpdate {
+        owner: PlayerId,
+        object: ObjectId,
+        kind: u16,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    // A replicated component was removed from an object that is still around
+    ComponentRemoved { owner: PlayerId, object: ObjectId, kind: u16 },
 }
 
+/// A payload that could not be decoded into a `Message`.
+#[derive(Debug)]
+pub struct DeserializeError(pub String);
+
+impl fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "could not deserialize message: {}", self.0)
+    }
+}
+
+/// The codec messages are sent with, chosen by the `packed-codec` feature.
+#[cfg(not(feature = "packed-codec"))]
+pub type WireCodec = CborCodec;
+#[cfg(feature = "packed-codec")]
+pub type WireCodec = PackedCodec;
+
 pub fn serialize(message: Message) -> Bytes {
-    let x: Vec<u8> = serde_cbor::to_vec(&message).expect("Ahhh");
-    Bytes::from_iter(x)
+    Bytes::from(WireCodec::encode(&message))
 }
 
-pub fn deserialize(bytes: Bytes) -> Message {
-    serde_cbor::from_slice(&bytes).expect("Deserialization failed")
+pub fn deserialize(bytes: Bytes) -> Result<Message, DeserializeError> {
+    WireCodec::decode(&bytes)
+}
+
+/// The angle the other end reads when `angle` is sent, so both can work from the same value.
+pub fn quantize_angle(angle: f32) -> f32 {
+    WireCodec::quantize_angle(angle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let message = Message::Despawn(PlayerId(3), 7);
+
+        assert_eq!(deserialize(serialize(message.clone())).unwrap(), message);
+    }
+
+    #[test]
+    fn test_ids_wider_than_a_byte_round_trip() {
+        let message = Message::Despawn(PlayerId(u16::MAX), 300);
+
+        assert_eq!(deserialize(serialize(message.clone())).unwrap(), message);
+    }
+
+    #[test]
+    fn test_garbage_is_an_error() {
+        assert!(deserialize(Bytes::from_static(&[0xff, 0x00, 0x13])).is_err());
+        assert!(deserialize(Bytes::new()).is_err());
+    }
 }
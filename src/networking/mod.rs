@@ -1,12 +1,24 @@
+pub mod clock;
+pub mod codec;
+pub mod compression;
 pub mod components;
+pub mod conditions;
+pub mod encryption;
 pub mod events;
+pub mod fragmentation;
 pub mod handshake;
+pub mod id_allocator;
 pub mod message;
+pub mod packet;
 pub mod packet_systems;
+pub mod prediction;
 pub mod resources;
 pub mod raw_message;
+pub mod reliable;
+pub mod replication;
 pub mod send_input;
-pub mod send_player_position;
+pub mod stats;
+pub mod world_snapshot;
 
 mod transport;
 
@@ -18,26 +30,103 @@ pub use self::events::NetworkEvent;
 pub use self::transport::Transport;
 
 use bevy::prelude::*;
-use crate::networking::components::{NetworkObject, NetworkTransform};
-use crate::networking::handshake::{ConnectionStatus, listen_handshake_events};
+use bevy_fps_controller::controller::{fps_controller_input, fps_controller_look};
+use bevy_rapier3d::prelude::PhysicsSet;
+use crate::networking::clock::{
+    advance_tick, answer_pings, receive_pongs, send_pings, PingTimer, ServerClock, ServerTick,
+};
+use crate::networking::compression::{
+    log_compression_stats, receive_compression_offers, Compression, CompressionStatsTimer,
+};
+use crate::networking::conditions::simulate_network_conditions;
+use crate::networking::components::{
+    InterpolationSettings, NetworkObject, NetworkObjectType, NetworkTransform,
+};
+use crate::networking::encryption::{
+    forget_disconnected_sessions, receive_key_exchange, Encryption, ENCRYPTION_OVERHEAD,
+};
+use crate::networking::fragmentation::Fragmenter;
+use crate::networking::handshake::{
+    listen_handshake_events, reconnect_system, send_connect_requests, send_player_name,
+    ConnectRequestTimer, ConnectionChallenges, ConnectionStatus, LocalPlayerName, Reconnect,
+    Session,
+};
 use crate::networking::message::Message;
-use crate::networking::message::Message::{Despawn, NetworkPosition, Spawn};
-use crate::networking::packet_systems::{auto_heartbeat_system, Socket, SocketAddress, SocketLive};
+use crate::networking::message::Message::{
+    Despawn, PlayerState, SessionResumed, Spawn,
+};
+use crate::networking::packet_systems::{Socket, SocketAddress, SocketLive};
+use crate::networking::prediction::{
+    reconcile_prediction, record_predicted_position, Prediction, ServerState,
+};
+use crate::networking::reliable::ReliableChannels;
+use crate::networking::replication::{apply_component_updates, ReplicationRegistry};
+use crate::networking::world_snapshot::{
+    receive_snapshot_acks, receive_world_snapshots, send_world_snapshots, ClientSnapshots, ServerSnapshots,
+};
 use crate::networking::resources::{NetworkGame, PlayerId};
-use crate::networking::send_player_position::sync_network_transforms;
+use crate::networking::send_input::{send_player_input, send_throw_requests};
+use crate::networking::stats::{record_ping_rtt, update_network_stats, NetworkStats};
 
 /// Defines how many times a client automatically sends a heartbeat packet.
 /// This should be no more than half of idle_timeout.
 const DEFAULT_HEARTBEAT_TICK_RATE_SECS: f32 = 2.;
-/// Defines how long the server will wait until it sends
+/// Defines how long either side will wait to hear from the other until it sends
 /// NetworkEvent::Disconnected
 const DEFAULT_IDLE_TIMEOUT_SECS: f32 = 5.;
+/// Defines how many malformed packets the server accepts from a connected
+/// address within the malformed packet window before ignoring it for a while
+const DEFAULT_MAX_MALFORMED_PACKETS: u32 = 10;
+/// Defines how long malformed packets from an address are counted together
+const DEFAULT_MALFORMED_WINDOW_SECS: f32 = 10.;
+/// Defines how long a blocked address is ignored for
+const DEFAULT_BLOCK_SECS: f32 = 60.;
 
 #[derive(Resource)]
 pub struct NetworkResource {
     // Hashmap of each live connection and their last known packet activity
     pub connections: HashMap<SocketAddr, Duration>,
     pub idle_timeout: Duration,
+    // Number of malformed packets received from each connected address in its current window,
+    // and when that window started
+    pub malformed_packets: HashMap<SocketAddr, (u32, Duration)>,
+    pub max_malformed_packets: u32,
+    pub malformed_window: Duration,
+    // Addresses whose packets are dropped without being read, and when they were blocked
+    pub blocked: HashMap<SocketAddr, Duration>,
+    pub block_duration: Duration,
+}
+
+impl NetworkResource {
+    /// Counts a malformed packet against `addr`, blocking it once it has sent more than
+    /// `max_malformed_packets` within `malformed_window`. Returns true if the address has just
+    /// been blocked. Only packets that authenticated as coming from a connected address should
+    /// be counted, so that spoofed packets can't get someone else blocked.
+    pub fn record_malformed_packet(&mut self, addr: SocketAddr, now: Duration) -> bool {
+        let (count, window_start) = self.malformed_packets.entry(addr).or_insert((0, now));
+        if now.saturating_sub(*window_start) > self.malformed_window {
+            *count = 0;
+            *window_start = now;
+        }
+        *count += 1;
+        if *count <= self.max_malformed_packets {
+            return false;
+        }
+        self.malformed_packets.remove(&addr);
+        self.blocked.insert(addr, now).is_none()
+    }
+
+    /// Whether packets from `addr` are to be dropped unread, lifting its block once it is over.
+    pub fn is_blocked(&mut self, addr: SocketAddr, now: Duration) -> bool {
+        match self.blocked.get(&addr) {
+            Some(blocked_at) if now.saturating_sub(*blocked_at) < self.block_duration => true,
+            Some(_) => {
+                self.blocked.remove(&addr);
+                false
+            }
+            None => false,
+        }
+    }
 }
 
 impl Default for NetworkResource {
@@ -45,47 +134,97 @@ impl Default for NetworkResource {
         Self {
             connections: Default::default(),
             idle_timeout: Duration::from_secs_f32(DEFAULT_IDLE_TIMEOUT_SECS),
+            malformed_packets: Default::default(),
+            max_malformed_packets: DEFAULT_MAX_MALFORMED_PACKETS,
+            malformed_window: Duration::from_secs_f32(DEFAULT_MALFORMED_WINDOW_SECS),
+            blocked: Default::default(),
+            block_duration: Duration::from_secs_f32(DEFAULT_BLOCK_SECS),
         }
     }
 }
 
 /// Label for network related systems.
-#[derive(Clone, Hash, Debug, PartialEq, Eq)]
+#[derive(SystemSet, Clone, Hash, Debug, PartialEq, Eq)]
 pub enum NetworkSystem {
     Receive,
     Send,
 }
 
 /// Label for server specific systems.
-#[derive(Clone, Hash, Debug, PartialEq, Eq)]
+#[derive(SystemSet, Clone, Hash, Debug, PartialEq, Eq)]
 pub enum ServerSystem {
     IdleTimeout,
+    Heartbeat,
 }
 
 /// Label for client specific systems.
-#[derive(Clone, Hash, Debug, PartialEq, Eq)]
+#[derive(SystemSet, Clone, Hash, Debug, PartialEq, Eq)]
 pub enum ClientSystem {
+    IdleTimeout,
     Heartbeat,
 }
 
-pub struct ServerPlugin(pub String);
+pub struct ServerPlugin {
+    /// Address the server socket is bound to.
+    pub listen_addr: String,
+    /// Largest datagram sent; larger packets are fragmented.
+    pub mtu: usize,
+    /// Time between updates of the server loop, which state sent to clients is stamped in.
+    pub tick_duration: Duration,
+    /// Whether large datagrams are compressed for clients that accept it.
+    pub compression: bool,
+}
 
 impl Plugin for ServerPlugin {
     fn build(&self, app: &mut App) {
-        let socket = UdpSocket::bind(self.0.as_str()).expect("could not bind socket");
-        socket
-            .set_nonblocking(true)
-            .expect("could not set socket to be nonblocking");
-        socket
-            .set_read_timeout(Some(Duration::from_secs(5)))
-            .expect("could not set read timeout");
+        // A socket inserted before the plugin, such as an in-memory one in tests, is used instead
+        if !app.world.contains_resource::<Socket>() {
+            let socket = UdpSocket::bind(self.listen_addr.as_str()).expect("could not bind socket");
+            socket
+                .set_nonblocking(true)
+                .expect("could not set socket to be nonblocking");
+            socket
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .expect("could not set read timeout");
+            app.insert_resource(Socket(Box::new(SocketLive(socket))));
+        }
+        simulate_network_conditions(app);
 
         app.insert_resource(NetworkResource::default())
             .insert_resource(transport::Transport::new())
+            .insert_resource(ReliableChannels::default())
+            .insert_resource(fragmenter_for(self.mtu))
+            .insert_resource(ConnectionChallenges::default())
+            .insert_resource(Encryption::default())
+            .add_systems(
+                Update,
+                forget_disconnected_sessions
+                    .after(NetworkSystem::Receive)
+                    .before(NetworkSystem::Send),
+            )
+            .insert_resource(Compression::new(self.compression))
+            .insert_resource(CompressionStatsTimer::default())
+            .add_systems(Update, log_compression_stats)
+            .insert_resource(NetworkStats::default())
+            .add_systems(Update, update_network_stats.after(NetworkSystem::Send))
+            .insert_resource(ServerTick::new(self.tick_duration))
+            .add_systems(First, advance_tick)
+            .insert_resource(ServerSnapshots::default())
+            .add_systems(
+                Update,
+                (answer_pings, receive_snapshot_acks, send_world_snapshots).before(NetworkSystem::Send),
+            )
             .add_event::<events::NetworkEvent>()
-            .add_systems(Update, packet_systems::server_recv_packet_system)
-            .add_systems(Update, packet_systems::send_packet_system)
-            .add_systems(Update, packet_systems::idle_timeout_system).insert_resource(Socket(Box::new(SocketLive(socket))))
+            .configure_set(Update, NetworkSystem::Receive.before(NetworkSystem::Send))
+            .add_systems(Update, packet_systems::server_recv_packet_system.in_set(NetworkSystem::Receive))
+            .add_systems(Update, packet_systems::send_packet_system.in_set(NetworkSystem::Send))
+            .add_systems(Update, packet_systems::idle_timeout_system.in_set(ServerSystem::IdleTimeout))
+            .insert_resource(HeartbeatTimer(Timer::from_seconds(
+                DEFAULT_HEARTBEAT_TICK_RATE_SECS,
+                TimerMode::Repeating,
+            )))
+            .add_systems(Update, packet_systems::auto_heartbeat_system.in_set(ServerSystem::Heartbeat))
+            .add_systems(Last, packet_systems::disconnect_on_exit_system)
             .insert_resource(NetworkGame::default());
     }
 }
@@ -93,46 +232,137 @@ impl Plugin for ServerPlugin {
 #[derive(Resource)]
 pub struct HeartbeatTimer(Timer);
 
-pub struct ClientPlugin(pub String, pub String);
+pub struct ClientPlugin {
+    /// Address of the server to connect to.
+    pub server_addr: String,
+    /// Local address the client socket is bound to.
+    pub bind_addr: String,
+    /// Largest datagram sent; larger packets are fragmented.
+    pub mtu: usize,
+    /// Whether large datagrams are compressed if the server accepts it.
+    pub compression: bool,
+    /// Name the server knows the player by.
+    pub player_name: String,
+}
 
 impl Plugin for ClientPlugin {
     fn build(&self, app: &mut App) {
-        let remote_addr: SocketAddr = self.0.parse().expect("could not parse addr");
-        let socket = UdpSocket::bind(self.1.clone()).expect("could not bind socket");
-        socket
-            .connect(remote_addr)
-            .expect("could not connect to server");
-        socket
-            .set_nonblocking(true)
-            .expect("could not set socket to be nonblocking");
-
-        app.insert_resource(transport::Transport::new())
+        let remote_addr: SocketAddr = self.server_addr.parse().expect("could not parse addr");
+        // A socket inserted before the plugin, such as an in-memory one in tests, is used instead
+        if !app.world.contains_resource::<Socket>() {
+            let socket = UdpSocket::bind(self.bind_addr.clone()).expect("could not bind socket");
+            socket
+                .connect(remote_addr)
+                .expect("could not connect to server");
+            socket
+                .set_nonblocking(true)
+                .expect("could not set socket to be nonblocking");
+            app.insert_resource(Socket(Box::new(SocketLive(socket))));
+        }
+        simulate_network_conditions(app);
+
+        // The server is tracked like any other connection so that the client notices when it
+        // goes quiet.
+        let mut net = NetworkResource::default();
+        net.connections.insert(remote_addr, Duration::ZERO);
+
+        app.insert_resource(net)
+            .insert_resource(transport::Transport::new())
+            .insert_resource(ReliableChannels::default())
+            .insert_resource(fragmenter_for(self.mtu))
             .insert_resource(HeartbeatTimer(Timer::from_seconds(
                 DEFAULT_HEARTBEAT_TICK_RATE_SECS,
-                Default::default(),
+                TimerMode::Repeating,
             )))
+            .insert_resource(Compression::new(self.compression))
+            .insert_resource(CompressionStatsTimer::default())
+            .add_systems(Update, (receive_compression_offers, log_compression_stats))
+            .insert_resource(NetworkStats::default())
+            .add_systems(Update, update_network_stats.after(NetworkSystem::Send))
+            .insert_resource(Encryption::default())
+            .add_systems(Update, receive_key_exchange.before(NetworkSystem::Send))
             .insert_resource(SocketAddress(remote_addr))
-            .insert_resource(Socket(Box::new(SocketLive(socket))))
             .add_event::<events::NetworkEvent>()
             .add_event::<message::Message>()
-            .add_systems(Update, packet_systems::client_recv_packet_system)
-            .add_systems(Update, packet_systems::send_packet_system)
-            .add_systems(Update, packet_systems::auto_heartbeat_system)
+            .insert_resource(ConnectRequestTimer::default())
+            .insert_resource(Session::default())
+            .insert_resource(LocalPlayerName(self.player_name.clone()))
+            .add_systems(
+                Update,
+                send_player_name
+                    .after(listen_events)
+                    .before(NetworkSystem::Send),
+            )
+            .insert_resource(Reconnect::default())
+            .insert_resource(ServerClock::default())
+            .insert_resource(PingTimer::default())
+            .add_systems(Update, send_pings.in_set(ClientSystem::Heartbeat))
+            .add_systems(Update, (receive_pongs, record_ping_rtt).chain())
+            .add_systems(Update, send_connect_requests.in_set(ClientSystem::Heartbeat))
+            .add_systems(Update, reconnect_system.in_set(ClientSystem::Heartbeat))
+            .configure_set(Update, NetworkSystem::Receive.before(NetworkSystem::Send))
+            .add_systems(Update, packet_systems::client_recv_packet_system.in_set(NetworkSystem::Receive))
+            .add_systems(Update, packet_systems::send_packet_system.in_set(NetworkSystem::Send))
+            .add_systems(Update, packet_systems::auto_heartbeat_system.in_set(ClientSystem::Heartbeat))
+            .add_systems(Update, packet_systems::idle_timeout_system.in_set(ClientSystem::IdleTimeout))
+            .add_systems(Last, packet_systems::disconnect_on_exit_system)
             .add_systems(Update, client_connection_handler)
+            .insert_resource(InterpolationSettings::default())
             .add_systems(Update, NetworkTransform::sync_network_transforms)
-            .add_systems(Update, auto_heartbeat_system)
-            .add_systems(Update, sync_network_transforms)
-            .add_systems(Update, listen_events)
+            .insert_resource(Prediction::default())
+            .add_systems(Update, send_throw_requests.before(NetworkSystem::Send))
+            .add_systems(
+                Update,
+                send_player_input
+                    .after(fps_controller_input)
+                    .before(fps_controller_look)
+                    .before(NetworkSystem::Send),
+            )
+            .add_systems(
+                Update,
+                reconcile_prediction
+                    .after(listen_events)
+                    .before(fps_controller_input),
+            )
+            .add_systems(PostUpdate, record_predicted_position.after(PhysicsSet::Writeback))
+            .init_resource::<ReplicationRegistry>()
+            .insert_resource(ClientSnapshots::default())
+            // Components and states are applied once the objects spawned by listen_events exist
+            .add_systems(
+                Update,
+                (
+                    listen_events,
+                    apply_deferred,
+                    (apply_component_updates, receive_world_snapshots),
+                )
+                    .chain(),
+            )
             .insert_resource(PlayerId(0));
     }
 }
 
-fn client_connection_handler(mut events: EventReader<NetworkEvent>, mut messages: EventWriter<Message>) {
+/// Makes the fragmenter for a plugin's MTU, leaving room for encryption in every datagram.
+fn fragmenter_for(mtu: usize) -> Fragmenter {
+    let fragment_mtu = mtu.checked_sub(ENCRYPTION_OVERHEAD).unwrap_or_else(|| {
+        panic!("MTU of {} does not leave room for the {} bytes of encryption", mtu, ENCRYPTION_OVERHEAD)
+    });
+    Fragmenter::new(fragment_mtu)
+}
+
+fn client_connection_handler(
+    mut events: EventReader<NetworkEvent>,
+    mut messages: EventWriter<Message>,
+    mut connection_status: ResMut<ConnectionStatus>,
+) {
     for event in events.iter() {
         match event {
             NetworkEvent::RawMessage(_, msg) => {
                 info!("server sent a message: {:?}", msg);
-                messages.send(*msg);
+                messages.send(msg.clone());
+            }
+            NetworkEvent::Disconnected(_, reason) => {
+                error!("Disconnected from server: {}", reason);
+                *connection_status = ConnectionStatus::Disconnected { reason: *reason };
             }
             NetworkEvent::SendError(err, msg) => {
                 error!(
@@ -143,12 +373,25 @@ fn client_connection_handler(mut events: EventReader<NetworkEvent>, mut messages
             NetworkEvent::RecvError(err) => {
                 error!("NetworkEvent::RecvError: {:?}", err);
             }
+            NetworkEvent::DeliveryFailed(msg) => {
+                error!(
+                    "NetworkEvent::DeliveryFailed (payload [{:?}]) to {}",
+                    msg.payload, msg.destination
+                );
+            }
+            NetworkEvent::MalformedPacket(addr, len) => {
+                warn!("NetworkEvent::MalformedPacket ({} bytes) from {}", len, addr);
+            }
+            NetworkEvent::AuthenticationFailed(addr, len) => {
+                warn!("NetworkEvent::AuthenticationFailed ({} bytes) from {}", len, addr);
+            }
             // discard irrelevant events
             _ => {}
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn listen_game_events(
     mut commands: Commands,
     mut messages: EventReader<Message>,
@@ -156,14 +399,18 @@ fn listen_game_events(
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     mut networked_entities: Query<(&NetworkObject, Entity)>,
-    mut networked_objects: Query<(&NetworkObject, &mut NetworkTransform)>,
-    _timer: Res<Time>,
+    mut session: ResMut<Session>,
+    mut prediction: ResMut<Prediction>,
 ) {
     for message in messages.iter() {
         println!("{:?}", message);
         match message {
             // TODO: Pass these functions into the ClientPlugin
-            Spawn(id, pos, object_type, object_id) if (*id == *local_player_id) => {
+            // Only the local player is simulated here; everything else, including objects the
+            // local player owns, is a facade for the server's simulation
+            Spawn(id, pos, object_type @ NetworkObjectType::Player, object_id)
+                if (*id == *local_player_id) =>
+            {
                 crate::game::client::spawn_network_object(object_type, *object_id, *id, *pos, &mut commands);
                 *local_player_id = *id;
             }
@@ -176,22 +423,35 @@ fn listen_game_events(
                 &mut meshes,
                 &mut materials,
             ),
-            NetworkPosition(received_player_id, pos, _object_id) => {
-                NetworkTransform::update_last_pos(received_player_id, pos, &mut networked_objects);
-            }
-            Despawn(_, object_id) => {
+            Despawn(owner, object_id) => {
                 for (object, entity) in networked_entities.iter_mut() {
-                    if object.id == *object_id {
+                    if object.owner == *owner && object.id == *object_id {
                         commands.entity(entity).despawn();
                     }
                 }
             }
+            PlayerState {
+                sequence,
+                position,
+                velocity,
+                ..
+            } => prediction.receive_state(ServerState {
+                sequence: *sequence,
+                position: *position,
+                velocity: *velocity,
+            }),
+            SessionResumed(id, session_token) => {
+                info!("Resumed previous session as player {:?}", id);
+                *local_player_id = *id;
+                session.0 = Some((*id, *session_token));
+            }
 
             _ => (),
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn listen_events(
     socket: Res<Socket>,
     commands: Commands,
@@ -200,19 +460,25 @@ fn listen_events(
     local_player_id: ResMut<PlayerId>,
     meshes: ResMut<Assets<Mesh>>,
     materials: ResMut<Assets<StandardMaterial>>,
-    networked_objects: Query<(&NetworkObject, &mut NetworkTransform)>,
     networked_entities: Query<(&NetworkObject, Entity)>,
-    timer: Res<Time>,
     connection_status: ResMut<ConnectionStatus>,
+    session: ResMut<Session>,
+    prediction: ResMut<Prediction>,
+    compression: Res<Compression>,
+    encryption: Res<Encryption>,
 ) {
     match *connection_status {
-        ConnectionStatus::Initial => listen_handshake_events(
+        ConnectionStatus::Initial | ConnectionStatus::Challenged(..) => listen_handshake_events(
             messages,
             socket,
             transport,
             local_player_id,
             connection_status,
+            session,
+            compression,
+            encryption,
         ),
+        ConnectionStatus::Rejected(_) | ConnectionStatus::Disconnected { .. } => (),
         _ => listen_game_events(
             commands,
             messages,
@@ -220,8 +486,53 @@ fn listen_events(
             meshes,
             materials,
             networked_entities,
-            networked_objects,
-            timer,
+            session,
+            prediction,
         ),
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_addr() -> SocketAddr {
+        "127.0.0.1:3000".parse().unwrap()
+    }
+
+    #[test]
+    fn test_malformed_packets_are_counted_within_window() {
+        let mut net = NetworkResource::default();
+        let addr = test_addr();
+        let spacing = net.malformed_window / 2;
+
+        // Spread out enough that no window ever holds more than two of them
+        for packet in 0..net.max_malformed_packets * 2 {
+            assert!(!net.record_malformed_packet(addr, spacing * (packet + 1) + spacing / 2));
+        }
+        assert!(!net.is_blocked(addr, net.malformed_window * 100));
+
+        let now = net.malformed_window * 200;
+        let blocked = (0..=net.max_malformed_packets).any(|_| net.record_malformed_packet(addr, now));
+        assert!(blocked);
+        assert!(net.is_blocked(addr, now));
+    }
+
+    #[test]
+    #[should_panic(expected = "does not leave room for the")]
+    fn test_rejects_mtu_smaller_than_encryption_overhead() {
+        fragmenter_for(ENCRYPTION_OVERHEAD - 1);
+    }
+
+    #[test]
+    fn test_blocks_expire() {
+        let mut net = NetworkResource::default();
+        let addr = test_addr();
+        for _ in 0..=net.max_malformed_packets {
+            net.record_malformed_packet(addr, Duration::ZERO);
+        }
+
+        assert!(net.is_blocked(addr, net.block_duration / 2));
+        assert!(!net.is_blocked(addr, net.block_duration));
+        assert!(net.blocked.is_empty());
+    }
+}
@@ -0,0 +1,327 @@
+/*
+   Wire framing for every datagram exchanged between client and server.
+
+   The first byte of a datagram identifies what follows it:
+       - Unreliable: a serialized Message, sent once
+       - Reliable:   a u16 sequence number followed by a serialized Message
+       - Ack:        the u16 sequence number of a received reliable packet
+       - Fragment:   a u16 group id, u8 index and u8 count followed by one
+                     piece of an encoded packet too large for a single datagram
+       - Batch:      several encoded packets bound for the same address, each
+                     preceded by its u16 length, so that many small messages
+                     share one datagram
+
+   An unreliable packet with an empty payload is a heartbeat.
+
+   The top bit of the first byte flags a compressed datagram. Everything after
+   its first byte is then LZ4 compressed, prefixed with its uncompressed length
+   as a little endian u32.
+
+   Once the handshake has agreed on keys, datagrams are encrypted after being
+   compressed. An encrypted datagram has its own first byte, followed by the
+   u64 counter its nonce is made from and the sealed datagram, authentication
+   tag included. See encryption.rs.
+*/
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+const UNRELIABLE: u8 = 0;
+const RELIABLE: u8 = 1;
+const ACK: u8 = 2;
+const FRAGMENT: u8 = 3;
+const BATCH: u8 = 4;
+const ENCRYPTED: u8 = 5;
+const COMPRESSED: u8 = 0x80;
+
+/// Bytes taken up by the header of a reliable packet before its payload.
+pub const RELIABLE_HEADER_LEN: usize = 3;
+/// Bytes taken up by the header of a fragment before its piece of the packet.
+pub const FRAGMENT_HEADER_LEN: usize = 5;
+/// Largest decompressed datagram accepted, so a forged length can't make the receiver allocate
+/// more than any datagram could hold.
+const MAX_DECOMPRESSED_LEN: usize = 65_507;
+
+/// Compresses an encoded datagram, setting the flag bit in its header. Returns `None` if it does
+/// not get any smaller.
+pub fn compress(datagram: &[u8]) -> Option<Bytes> {
+    let (kind, body) = datagram.split_first()?;
+    let compressed = lz4_flex::block::compress_prepend_size(body);
+    if compressed.len() + 1 >= datagram.len() {
+        return None;
+    }
+    let mut buf = BytesMut::with_capacity(compressed.len() + 1);
+    buf.put_u8(kind | COMPRESSED);
+    buf.put_slice(&compressed);
+    Some(buf.freeze())
+}
+
+/// Reverses `compress`, passing datagrams without the flag bit through untouched. Returns `None`
+/// if the compressed data is malformed.
+pub fn decompress(datagram: Bytes) -> Option<Bytes> {
+    let (kind, body) = datagram.split_first()?;
+    if kind & COMPRESSED == 0 {
+        return Some(datagram);
+    }
+    let len = u32::from_le_bytes(body.get(..4)?.try_into().ok()?) as usize;
+    if len > MAX_DECOMPRESSED_LEN {
+        return None;
+    }
+    let decompressed = lz4_flex::block::decompress(&body[4..], len).ok()?;
+    let mut buf = BytesMut::with_capacity(decompressed.len() + 1);
+    buf.put_u8(kind & !COMPRESSED);
+    buf.put_slice(&decompressed);
+    Some(buf.freeze())
+}
+
+/// Bytes taken up by the header of an encrypted datagram before the sealed datagram.
+pub const ENCRYPTED_HEADER_LEN: usize = 9;
+
+/// The header of an encrypted datagram whose nonce is made from `counter`.
+pub fn encrypted_header(counter: u64) -> [u8; ENCRYPTED_HEADER_LEN] {
+    let mut header = [ENCRYPTED; ENCRYPTED_HEADER_LEN];
+    header[1..].copy_from_slice(&counter.to_be_bytes());
+    header
+}
+
+pub fn is_encrypted(datagram: &[u8]) -> bool {
+    datagram.first() == Some(&ENCRYPTED)
+}
+
+/// The nonce counter of an encrypted datagram. Returns `None` if it is not encrypted or its header
+/// is truncated.
+pub fn encrypted_counter(datagram: &[u8]) -> Option<u64> {
+    match is_encrypted(datagram) {
+        true => Some(u64::from_be_bytes(datagram.get(1..ENCRYPTED_HEADER_LEN)?.try_into().ok()?)),
+        false => None,
+    }
+}
+
+/// Bytes taken up by the header of a batch, and by the length before each packet in it.
+const BATCH_HEADER_LEN: usize = 1;
+const BATCH_ENTRY_HEADER_LEN: usize = 2;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Packet {
+    Unreliable(Bytes),
+    Reliable { sequence: u16, payload: Bytes },
+    Ack(u16),
+    Fragment { group: u16, index: u8, count: u8, payload: Bytes },
+    // Encoded packets, none of them batches themselves
+    Batch(Vec<Bytes>),
+}
+
+impl Packet {
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        match self {
+            Packet::Unreliable(payload) => {
+                buf.put_u8(UNRELIABLE);
+                buf.put_slice(payload);
+            }
+            Packet::Reliable { sequence, payload } => {
+                buf.put_u8(RELIABLE);
+                buf.put_u16(*sequence);
+                buf.put_slice(payload);
+            }
+            Packet::Ack(sequence) => {
+                buf.put_u8(ACK);
+                buf.put_u16(*sequence);
+            }
+            Packet::Fragment {
+                group,
+                index,
+                count,
+                payload,
+            } => {
+                buf.put_u8(FRAGMENT);
+                buf.put_u16(*group);
+                buf.put_u8(*index);
+                buf.put_u8(*count);
+                buf.put_slice(payload);
+            }
+            Packet::Batch(packets) => {
+                buf.put_u8(BATCH);
+                for packet in packets {
+                    buf.put_u16(packet.len() as u16);
+                    buf.put_slice(packet);
+                }
+            }
+        }
+        buf.freeze()
+    }
+
+    /// Groups encoded packets into as few datagrams as possible, batching packets together while
+    /// they fit within the MTU. Packets too large to share a datagram are left on their own, and
+    /// so are batches of one. Each datagram is returned with the tags of the packets it carries.
+    pub fn batch<T>(packets: Vec<(Bytes, T)>, mtu: usize) -> Vec<(Bytes, Vec<T>)> {
+        let mut datagrams = Vec::new();
+        let mut batch: Vec<(Bytes, T)> = Vec::new();
+        let mut batch_len = BATCH_HEADER_LEN;
+        for (packet, tag) in packets {
+            let entry_len = BATCH_ENTRY_HEADER_LEN + packet.len();
+            if batch_len + entry_len > mtu && !batch.is_empty() {
+                datagrams.push(Self::finish_batch(std::mem::take(&mut batch)));
+                batch_len = BATCH_HEADER_LEN;
+            }
+            batch_len += entry_len;
+            batch.push((packet, tag));
+        }
+        if !batch.is_empty() {
+            datagrams.push(Self::finish_batch(batch));
+        }
+        datagrams
+    }
+
+    fn finish_batch<T>(batch: Vec<(Bytes, T)>) -> (Bytes, Vec<T>) {
+        let (packets, tags): (Vec<Bytes>, Vec<T>) = batch.into_iter().unzip();
+        match <[Bytes; 1]>::try_from(packets) {
+            Ok([packet]) => (packet, tags),
+            Err(packets) => (Packet::Batch(packets).encode(), tags),
+        }
+    }
+
+    /// Parses a received datagram. Returns `None` if the header is unknown or truncated.
+    pub fn decode(bytes: Bytes) -> Option<Packet> {
+        let kind = *bytes.first()?;
+        match kind {
+            UNRELIABLE => Some(Packet::Unreliable(bytes.slice(1..))),
+            RELIABLE if bytes.len() >= RELIABLE_HEADER_LEN => Some(Packet::Reliable {
+                sequence: u16::from_be_bytes([bytes[1], bytes[2]]),
+                payload: bytes.slice(RELIABLE_HEADER_LEN..),
+            }),
+            ACK if bytes.len() == 3 => Some(Packet::Ack(u16::from_be_bytes([bytes[1], bytes[2]]))),
+            FRAGMENT if bytes.len() >= FRAGMENT_HEADER_LEN => Some(Packet::Fragment {
+                group: u16::from_be_bytes([bytes[1], bytes[2]]),
+                index: bytes[3],
+                count: bytes[4],
+                payload: bytes.slice(FRAGMENT_HEADER_LEN..),
+            }),
+            BATCH => {
+                let mut packets = Vec::new();
+                let mut rest = bytes.slice(BATCH_HEADER_LEN..);
+                while !rest.is_empty() {
+                    if rest.len() < BATCH_ENTRY_HEADER_LEN {
+                        return None;
+                    }
+                    let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                    let end = BATCH_ENTRY_HEADER_LEN + len;
+                    if rest.len() < end {
+                        return None;
+                    }
+                    packets.push(rest.slice(BATCH_ENTRY_HEADER_LEN..end));
+                    rest = rest.slice(end..);
+                }
+                match packets.is_empty() {
+                    true => None,
+                    false => Some(Packet::Batch(packets)),
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let packets = [
+            Packet::Unreliable(Bytes::new()),
+            Packet::Unreliable(Bytes::from_static(b"test")),
+            Packet::Reliable {
+                sequence: 513,
+                payload: Bytes::from_static(b"test"),
+            },
+            Packet::Ack(u16::MAX),
+            Packet::Fragment {
+                group: 7,
+                index: 1,
+                count: 3,
+                payload: Bytes::from_static(b"test"),
+            },
+            Packet::Batch(vec![
+                Packet::Unreliable(Bytes::from_static(b"test")).encode(),
+                Packet::Ack(7).encode(),
+            ]),
+        ];
+
+        for packet in packets {
+            assert_eq!(Packet::decode(packet.encode()), Some(packet));
+        }
+    }
+
+    #[test]
+    fn test_decode_rejects_truncated_headers() {
+        assert_eq!(Packet::decode(Bytes::new()), None);
+        assert_eq!(Packet::decode(Bytes::from_static(&[RELIABLE, 0])), None);
+        assert_eq!(Packet::decode(Bytes::from_static(&[ACK])), None);
+        assert_eq!(Packet::decode(Bytes::from_static(&[FRAGMENT, 0, 0, 1])), None);
+        assert_eq!(Packet::decode(Bytes::from_static(&[42])), None);
+        assert_eq!(Packet::decode(Bytes::from_static(&[BATCH])), None);
+        assert_eq!(Packet::decode(Bytes::from_static(&[BATCH, 0, 2, ACK])), None);
+    }
+
+    #[test]
+    fn test_batches_fit_within_the_mtu() {
+        let small = Packet::Unreliable(Bytes::from(vec![0; 97])).encode();
+        let large = Packet::Unreliable(Bytes::from(vec![0; 2000])).encode();
+        let mut packets: Vec<(Bytes, usize)> = (0..25).map(|i| (small.clone(), i)).collect();
+        packets.push((large.clone(), 25));
+
+        let datagrams = Packet::batch(packets, 1200);
+        // Eleven small packets fit in each batch, and the large one goes on its own
+        assert_eq!(datagrams.len(), 4);
+        for (datagram, _) in &datagrams[..3] {
+            assert!(datagram.len() <= 1200);
+        }
+        assert_eq!(datagrams[3], (large, vec![25]));
+
+        let mut unbatched = Vec::new();
+        for (datagram, _) in &datagrams[..3] {
+            match Packet::decode(datagram.clone()) {
+                Some(Packet::Batch(packets)) => unbatched.extend(packets),
+                Some(_) => unbatched.push(datagram.clone()),
+                None => panic!("undecodable datagram"),
+            }
+        }
+        assert_eq!(unbatched, vec![small; 25]);
+        let tags: Vec<usize> = datagrams.into_iter().flat_map(|(_, tags)| tags).collect();
+        assert_eq!(tags, (0..26).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_compression_round_trip() {
+        let packet = Packet::Reliable {
+            sequence: 3,
+            payload: Bytes::from(b"position position position position position".repeat(10)),
+        }
+        .encode();
+
+        let compressed = compress(&packet).unwrap();
+        assert!(compressed.len() < packet.len());
+        assert_eq!(compressed[0], RELIABLE | COMPRESSED);
+        assert_eq!(decompress(compressed), Some(packet));
+    }
+
+    #[test]
+    fn test_incompressible_datagrams_are_left_alone() {
+        let ack = Packet::Ack(3).encode();
+        assert_eq!(compress(&ack), None);
+        assert_eq!(decompress(ack.clone()), Some(ack));
+    }
+
+    #[test]
+    fn test_decompress_rejects_malformed_data() {
+        assert_eq!(decompress(Bytes::from_static(&[UNRELIABLE | COMPRESSED, 1])), None);
+        assert_eq!(decompress(Bytes::from_static(&[UNRELIABLE | COMPRESSED, 0xff, 0xff, 0xff, 0xff, 0])), None);
+        assert_eq!(decompress(Bytes::from_static(&[UNRELIABLE | COMPRESSED, 8, 0, 0, 0, 0xf0])), None);
+    }
+
+    #[test]
+    fn test_lone_packets_are_not_batched() {
+        let ack = Packet::Ack(3).encode();
+        assert_eq!(Packet::batch(vec![(ack.clone(), ())], 1200), vec![(ack, vec![()])]);
+    }
+}
@@ -1,23 +1,31 @@
-use std::io::{Error, ErrorKind};
-use std::{
-    io,
-    net::{SocketAddr, UdpSocket},
-};
-use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::collections::HashMap;
+use std::time::Duration;
 
 use crate::networking::message::deserialize;
 use crate::networking::HeartbeatTimer;
+use bevy::app::AppExit;
 use bevy::prelude::*;
 use bytes::Bytes;
 use crate::networking::packet_systems::SocketError::NoInput;
 
-use super::{events::NetworkEvent, transport::Transport, NetworkResource};
+use super::{events::DisconnectReason, events::NetworkEvent, transport::Transport, NetworkResource};
+use super::message::{serialize, Message};
+use super::compression::Compression;
+use super::encryption::Encryption;
+use super::fragmentation::{Fragmenter, MAX_DATAGRAM_SIZE};
+use super::handshake::{handle_unconnected_message, ConnectionChallenges};
+use super::packet::{is_encrypted, Packet, RELIABLE_HEADER_LEN};
+use super::raw_message::{Delivery, RawMessage};
+use super::reliable::ReliableChannels;
+use super::stats::NetworkStats;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum SocketError {
     ConnectionReset(),
     NoInput(),
-    Other(ErrorKind)
+    Other(#[allow(dead_code)] ErrorKind)
 }
 
 pub trait SocketLike {
@@ -34,41 +42,103 @@ pub struct SocketLive(
 
 impl SocketLike for SocketLive {
     fn peer_addr(&self) -> Result<SocketAddr, SocketError> {
-        return self.0.peer_addr().map_err(|err| match err.kind() {
+        self.0.peer_addr().map_err(|err| match err.kind() {
             ErrorKind::ConnectionReset => NoInput(),
             kind => SocketError::Other(kind)
-        });
+        })
     }
 
     fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), SocketError> {
-        return self.0.recv_from(buf).map_err(|err| match err.kind() {
+        self.0.recv_from(buf).map_err(|err| match err.kind() {
             ErrorKind::WouldBlock => SocketError::NoInput(),
-            ErrorKind::ConnectionReset => SocketError::ConnectionReset(),
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused => SocketError::ConnectionReset(),
             _ => SocketError::Other(err.kind())
-        });
+        })
     }
 
     fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, SocketError> {
-        return self.0.send_to(buf, addr).map_err(|err| SocketError::Other(err.kind()));
+        self.0.send_to(buf, addr).map_err(|err| SocketError::Other(err.kind()))
     }
 }
 
-pub struct SocketTest(SocketAddr, HashMap<SocketAddr, VecDeque<(usize, Box<[u8]>, SocketAddr)>>);
+/// Datagrams waiting to be read by an in-memory socket, each with the address it came from.
+#[cfg(test)]
+type DatagramQueue = std::collections::VecDeque<(Box<[u8]>, SocketAddr)>;
 
-impl SocketTest {
+/// An in-memory network that SocketTest endpoints send each other datagrams over, so whole server
+/// and client apps can talk to each other in a test without binding real ports. Clones share the
+/// same network.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct VirtualNetwork(std::sync::Arc<std::sync::Mutex<HashMap<SocketAddr, DatagramQueue>>>);
+
+#[cfg(test)]
+impl VirtualNetwork {
+    /// Binds an endpoint that can exchange datagrams with any other on the network, like a server
+    /// socket.
+    pub fn bind(&self, addr: SocketAddr) -> SocketTest {
+        let mut queues = self.0.lock().unwrap();
+        assert!(!queues.contains_key(&addr), "{} is already bound", addr);
+        queues.insert(addr, DatagramQueue::new());
+        SocketTest {
+            addr,
+            peer: None,
+            network: self.clone(),
+        }
+    }
+
+    /// Binds an endpoint that only exchanges datagrams with `peer`, like a connected client socket.
+    pub fn connect(&self, addr: SocketAddr, peer: SocketAddr) -> SocketTest {
+        let mut socket = self.bind(addr);
+        socket.peer = Some(peer);
+        socket
+    }
+}
+
+/// One endpoint of a VirtualNetwork. Datagrams sent to addresses nobody is bound to are lost, as
+/// they would be over UDP, and the endpoint's address is freed once it is dropped.
+#[cfg(test)]
+pub struct SocketTest {
+    addr: SocketAddr,
+    peer: Option<SocketAddr>,
+    network: VirtualNetwork,
+}
+
+#[cfg(test)]
+impl SocketLike for SocketTest {
     fn peer_addr(&self) -> Result<SocketAddr, SocketError> {
-        return Ok(self.0);
+        self.peer.ok_or(SocketError::Other(ErrorKind::NotConnected))
     }
 
-    fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, SocketAddr), SocketError> {
-        let mut data: &mut VecDeque<(usize, Box<[u8]>, SocketAddr)> = self.1.get_mut(&self.0).expect("No queue initialized for in memory socket at: {}");
-        match VecDeque::pop_front(data) {
-            Some((size, bytes, addr)) => {
-                buf[..size].copy_from_slice(bytes.as_ref());
-                return Ok((size, addr));
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), SocketError> {
+        let mut queues = self.network.0.lock().unwrap();
+        let queue = queues.get_mut(&self.addr).expect("in-memory socket is not bound");
+        while let Some((datagram, from)) = queue.pop_front() {
+            // A connected socket only reads from its peer
+            if self.peer.is_some_and(|peer| peer != from) {
+                continue;
             }
+            // Like UDP, whatever doesn't fit in the buffer is lost
+            let len = datagram.len().min(buf.len());
+            buf[..len].copy_from_slice(&datagram[..len]);
+            return Ok((len, from));
+        }
+        Err(SocketError::NoInput())
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, SocketError> {
+        if let Some(queue) = self.network.0.lock().unwrap().get_mut(&addr) {
+            queue.push_back((buf.into(), self.addr));
+        }
+        Ok(buf.len())
+    }
+}
 
-            None => Err(SocketError::NoInput())
+#[cfg(test)]
+impl Drop for SocketTest {
+    fn drop(&mut self) {
+        if let Ok(mut queues) = self.network.0.lock() {
+            queues.remove(&self.addr);
         }
     }
 }
@@ -78,43 +148,269 @@ pub struct Socket(pub Box<dyn SocketLike + Send + Sync>);
 
 impl Socket {
     pub fn peer_addr(&self) -> Result<SocketAddr, SocketError> {
-        return self.0.peer_addr();
+        self.0.peer_addr()
     }
     pub fn recv_from(&self, buf: &mut [u8]) ->  Result<(usize, SocketAddr), SocketError> {
-        return self.0.recv_from(buf);
+        self.0.recv_from(buf)
     }
 
     pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, SocketError> {
-        return self.0.send_to(buf, addr);
+        self.0.send_to(buf, addr)
     }
 }
 
 #[derive(Resource)]
 pub struct SocketAddress(pub SocketAddr);
 
-pub fn client_recv_packet_system(socket: Res<Socket>, mut events: EventWriter<NetworkEvent>) {
+/// Forgets all state kept for a connection and reports it as disconnected.
+fn disconnect(
+    addr: SocketAddr,
+    reason: DisconnectReason,
+    net: &mut NetworkResource,
+    reliable: &mut ReliableChannels,
+    events: &mut EventWriter<NetworkEvent>,
+) {
+    if net.connections.remove(&addr).is_some() {
+        net.malformed_packets.remove(&addr);
+        reliable.remove(&addr);
+        events.send(NetworkEvent::Disconnected(addr, reason));
+    }
+}
+
+/// Decodes a message payload and sends it on as a NetworkEvent, or reports it as malformed.
+/// Returns false if the payload could not be decoded.
+fn handle_payload(
+    address: SocketAddr,
+    payload: Bytes,
+    net: &mut NetworkResource,
+    reliable: &mut ReliableChannels,
+    events: &mut EventWriter<NetworkEvent>,
+) -> bool {
+    let len = payload.len();
+    match deserialize(payload) {
+        Ok(Message::Disconnect) => {
+            disconnect(address, DisconnectReason::Requested, net, reliable, events);
+            true
+        }
+        Ok(message) => {
+            events.send(NetworkEvent::RawMessage(address, message));
+            true
+        }
+        Err(err) => {
+            warn!("{}: discarding malformed message of {} bytes: {}", address, len, err);
+            events.send(NetworkEvent::MalformedPacket(address, len));
+            false
+        }
+    }
+}
+
+/// Unwraps a received datagram, reassembling fragments and acknowledging and reordering reliable
+/// packets, and sends a NetworkEvent for every message that is ready to be handled. Returns false
+/// if any part of the datagram was malformed.
+fn handle_datagram(
+    now: Duration,
+    address: SocketAddr,
+    datagram: Bytes,
+    net: &mut NetworkResource,
+    reliable: &mut ReliableChannels,
+    fragmenter: &mut Fragmenter,
+    events: &mut EventWriter<NetworkEvent>,
+) -> bool {
+    let len = datagram.len();
+    let packet = match Packet::decode(datagram) {
+        Some(packet) => packet,
+        None => {
+            warn!("{}: discarding packet of {} bytes with unknown header", address, len);
+            events.send(NetworkEvent::MalformedPacket(address, len));
+            return false;
+        }
+    };
+
+    match packet {
+        Packet::Unreliable(payload) if payload.is_empty() => {
+            debug!("{}: received heartbeat packet", address);
+            // discard without sending a NetworkEvent
+            true
+        }
+        Packet::Unreliable(payload) => {
+            debug!("received payload {:?} from {}", payload, address);
+            handle_payload(address, payload, net, reliable, events)
+        }
+        Packet::Reliable { sequence, payload } => {
+            debug!("received reliable payload {} {:?} from {}", sequence, payload, address);
+            let mut well_formed = true;
+            for payload in reliable.receive(address, sequence, payload) {
+                well_formed &= handle_payload(address, payload, net, reliable, events);
+            }
+            well_formed
+        }
+        Packet::Ack(sequence) => {
+            reliable.acknowledge(address, sequence, now);
+            true
+        }
+        Packet::Fragment {
+            group,
+            index,
+            count,
+            payload,
+        } => match fragmenter.reassemble(address, group, index, count, payload, now) {
+            Some(packet) if matches!(Packet::decode(packet.clone()), Some(Packet::Fragment { .. })) => {
+                warn!("{}: discarding nested fragment", address);
+                events.send(NetworkEvent::MalformedPacket(address, packet.len()));
+                false
+            }
+            Some(packet) => {
+                handle_datagram(now, address, packet, net, reliable, fragmenter, events)
+            }
+            None => true,
+        },
+        Packet::Batch(packets) => {
+            let mut well_formed = true;
+            for packet in packets {
+                if matches!(Packet::decode(packet.clone()), Some(Packet::Batch(_))) {
+                    warn!("{}: discarding nested batch", address);
+                    events.send(NetworkEvent::MalformedPacket(address, packet.len()));
+                    well_formed = false;
+                    continue;
+                }
+                well_formed &= handle_datagram(now, address, packet, net, reliable, fragmenter, events);
+            }
+            well_formed
+        }
+    }
+}
+
+/// The packets carried by a datagram: the packets of a batch, or the datagram's own packet.
+fn unbatch(datagram: Bytes) -> Option<Vec<Packet>> {
+    match Packet::decode(datagram)? {
+        Packet::Batch(packets) => packets.into_iter().map(Packet::decode).collect(),
+        packet => Some(vec![packet]),
+    }
+}
+
+/// Whether a datagram holds nothing but heartbeats and the messages a client sends in the clear
+/// while it is going through the handshake.
+fn is_handshake(datagram: &Bytes) -> bool {
+    unbatch(datagram.clone()).is_some_and(|packets| {
+        packets.into_iter().all(|packet| match packet {
+            Packet::Unreliable(payload) if payload.is_empty() => true,
+            Packet::Unreliable(payload) => matches!(
+                deserialize(payload),
+                Ok(Message::ConnectRequest { .. } | Message::ChallengeResponse { .. })
+            ),
+            _ => false,
+        })
+    })
+}
+
+/// Handles a datagram from an address that has not answered its connection challenge yet. Only
+/// unreliable handshake messages are read from such addresses, so stray or spoofed traffic never
+/// creates any per-connection state. Returns `None` if the datagram was malformed, otherwise
+/// whether the address has now completed its challenge.
+#[allow(clippy::too_many_arguments)]
+fn handle_unconnected_datagram(
+    address: SocketAddr,
+    datagram: Bytes,
+    now: Duration,
+    challenges: &mut ConnectionChallenges,
+    compression: &mut Compression,
+    encryption: &mut Encryption,
+    transport: &mut Transport,
+    events: &mut EventWriter<NetworkEvent>,
+) -> Option<bool> {
+    let len = datagram.len();
+    let mut messages = Vec::new();
+    let packets = unbatch(datagram);
+    // Heartbeats, and reliable packets from a client that hasn't noticed it is no longer
+    // connected, are ignored rather than held against the address
+    for packet in packets.iter().flatten() {
+        match packet {
+            Packet::Unreliable(payload) if payload.is_empty() => (),
+            Packet::Unreliable(payload) => messages.push(deserialize(payload.clone()).ok()),
+            _ => (),
+        }
+    }
+
+    if packets.is_none() || messages.contains(&None) {
+        warn!("{}: discarding malformed packet of {} bytes", address, len);
+        events.send(NetworkEvent::MalformedPacket(address, len));
+        return None;
+    }
+    let mut challenge_completed = false;
+    for message in messages.into_iter().flatten() {
+        challenge_completed |=
+            handle_unconnected_message(address, &message, now, challenges, compression, encryption, transport);
+    }
+    Some(challenge_completed)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn client_recv_packet_system(
+    time: Res<Time>,
+    socket: Res<Socket>,
+    mut events: EventWriter<NetworkEvent>,
+    mut net: ResMut<NetworkResource>,
+    mut reliable: ResMut<ReliableChannels>,
+    mut fragmenter: ResMut<Fragmenter>,
+    mut compression: ResMut<Compression>,
+    mut encryption: ResMut<Encryption>,
+    mut stats: ResMut<NetworkStats>,
+) {
+    fragmenter.expire(time.elapsed());
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
     loop {
-        let mut buf = [0; 512];
         match socket.recv_from(&mut buf) {
             Ok((recv_len, address)) => {
-                let payload = Bytes::copy_from_slice(&buf[..recv_len]);
-                if payload.len() == 0 {
-                    debug!("{}: received heartbeat packet", address);
-                    // discard without sending a NetworkEvent
+                if !net.connections.contains_key(&address) {
+                    // already disconnected from the server
                     continue;
                 }
-                debug!("received payload {:?} from {}", payload, address);
-                let message = deserialize(payload);
-                events.send(NetworkEvent::RawMessage(address, message));
+                let datagram = Bytes::copy_from_slice(&buf[..recv_len]);
+                // The server answers the challenge in the clear, and encrypts everything after it
+                let datagram = match encryption.is_confirmed(address) || is_encrypted(&datagram) {
+                    true => match encryption.decrypt(address, datagram) {
+                        Some(datagram) => datagram,
+                        None => {
+                            warn!("{}: discarding datagram of {} bytes that failed to authenticate", address, recv_len);
+                            events.send(NetworkEvent::AuthenticationFailed(address, recv_len));
+                            continue;
+                        }
+                    },
+                    false => datagram,
+                };
+                net.connections.insert(address, time.elapsed());
+                // Only counted once authenticated, like on the server, so forged datagrams aren't
+                stats.record_received(address, recv_len);
+                let payload = match compression.decompress(datagram) {
+                    Some(payload) => payload,
+                    None => {
+                        warn!("{}: discarding datagram of {} bytes that failed to decompress", address, recv_len);
+                        events.send(NetworkEvent::MalformedPacket(address, recv_len));
+                        continue;
+                    }
+                };
+                handle_datagram(
+                    time.elapsed(),
+                    address,
+                    payload,
+                    &mut net,
+                    &mut reliable,
+                    &mut fragmenter,
+                    &mut events,
+                );
             }
             Err(e) => {
                 match e {
                     SocketError::NoInput() => (),
-                    SocketError::ConnectionReset() => events.send(NetworkEvent::Disconnected(
+                    SocketError::ConnectionReset() => disconnect(
                         socket
                             .peer_addr()
                             .expect("No peer address for some reason"),
-                    )),
+                        DisconnectReason::ConnectionReset,
+                        &mut net,
+                        &mut reliable,
+                        &mut events,
+                    ),
                     _ => events.send(NetworkEvent::RecvError(e))
                 }
                 // break loop when no messages are left to read this frame
@@ -124,38 +420,136 @@ pub fn client_recv_packet_system(socket: Res<Socket>, mut events: EventWriter<Ne
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn server_recv_packet_system(
     time: Res<Time>,
     socket: Res<Socket>,
     mut events: EventWriter<NetworkEvent>,
     mut net: ResMut<NetworkResource>,
+    mut reliable: ResMut<ReliableChannels>,
+    mut fragmenter: ResMut<Fragmenter>,
+    mut transport: ResMut<Transport>,
+    mut challenges: ResMut<ConnectionChallenges>,
+    mut compression: ResMut<Compression>,
+    mut encryption: ResMut<Encryption>,
+    mut stats: ResMut<NetworkStats>,
 ) {
+    fragmenter.expire(time.elapsed());
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
     loop {
-        let mut buf = [0; 512];
         match socket.recv_from(&mut buf) {
             Ok((recv_len, address)) => {
-                let payload = Bytes::copy_from_slice(&buf[..recv_len]);
-                if net.connections.insert(address, time.elapsed()).is_none() {
-                    // connection established
-                    events.send(NetworkEvent::Connected(address));
+                if net.is_blocked(address, time.elapsed()) {
+                    continue;
+                }
+                let datagram = Bytes::copy_from_slice(&buf[..recv_len]);
+                let is_connected = net.connections.contains_key(&address);
+                let was_encrypted = is_encrypted(&datagram);
+                let datagram = match (is_connected, was_encrypted) {
+                    (true, true) => match encryption.decrypt(address, datagram) {
+                        Some(datagram) => datagram,
+                        None => {
+                            warn!("{}: discarding datagram of {} bytes that failed to authenticate", address, recv_len);
+                            events.send(NetworkEvent::AuthenticationFailed(address, recv_len));
+                            continue;
+                        }
+                    },
+                    // only connected addresses have a session to decrypt with
+                    (false, true) => continue,
+                    (_, false) => datagram,
+                };
+                let payload = match compression.decompress(datagram) {
+                    Some(payload) => payload,
+                    None => {
+                        warn!("{}: discarding datagram of {} bytes that failed to decompress", address, recv_len);
+                        events.send(NetworkEvent::MalformedPacket(address, recv_len));
+                        // Only what authenticated is held against an address
+                        let authenticated = is_connected && was_encrypted;
+                        if authenticated && net.record_malformed_packet(address, time.elapsed()) {
+                            warn!("{}: too many malformed packets, blocking address", address);
+                            disconnect(
+                                address,
+                                DisconnectReason::Blocked,
+                                &mut net,
+                                &mut reliable,
+                                &mut events,
+                            );
+                        }
+                        continue;
+                    }
+                };
+                if is_connected && !was_encrypted && !is_handshake(&payload) {
+                    warn!("{}: discarding unencrypted datagram of {} bytes", address, recv_len);
+                    events.send(NetworkEvent::AuthenticationFailed(address, recv_len));
+                    continue;
                 }
-                if payload.len() == 0 {
-                    debug!("{}: received heartbeat packet", address);
-                    // discard without sending a NetworkEvent
+                if !is_connected || !was_encrypted {
+                    // Plaintext from a connected address could be its client starting over, but
+                    // also anyone spoofing it, so the connection is kept until a new challenge is
+                    // answered
+                    let challenge_completed = handle_unconnected_datagram(
+                        address,
+                        payload,
+                        time.elapsed(),
+                        &mut challenges,
+                        &mut compression,
+                        &mut encryption,
+                        &mut transport,
+                        &mut events,
+                    );
+                    // A malformed datagram from an unconnected address could have been sent by
+                    // anyone, so it is not held against the address
+                    if challenge_completed == Some(true) {
+                        if is_connected {
+                            // the client lost track of the connection and started over
+                            disconnect(
+                                address,
+                                DisconnectReason::Restarted,
+                                &mut net,
+                                &mut reliable,
+                                &mut events,
+                            );
+                        }
+                        // connection established
+                        net.connections.insert(address, time.elapsed());
+                        events.send(NetworkEvent::Connected(address));
+                    }
                     continue;
                 }
-                debug!("received payload {:?} from {}", payload, address);
-                let message = deserialize(payload);
-                events.send(NetworkEvent::RawMessage(address, message));
+                net.connections.insert(address, time.elapsed());
+                stats.record_received(address, recv_len);
+                let well_formed = handle_datagram(
+                    time.elapsed(),
+                    address,
+                    payload,
+                    &mut net,
+                    &mut reliable,
+                    &mut fragmenter,
+                    &mut events,
+                );
+                if !well_formed && net.record_malformed_packet(address, time.elapsed()) {
+                    warn!("{}: too many malformed packets, blocking address", address);
+                    disconnect(
+                        address,
+                        DisconnectReason::Blocked,
+                        &mut net,
+                        &mut reliable,
+                        &mut events,
+                    );
+                }
             }
             Err(e) => {
                 match e {
                     SocketError::NoInput() => (),
-                    SocketError::ConnectionReset() => events.send(NetworkEvent::Disconnected(
+                    SocketError::ConnectionReset() => disconnect(
                         socket
                             .peer_addr()
                             .expect("No peer address for some reason"),
-                    )),
+                        DisconnectReason::ConnectionReset,
+                        &mut net,
+                        &mut reliable,
+                        &mut events,
+                    ),
                     _ => events.send(NetworkEvent::RecvError(e))
                 }
                 // break loop when no messages are left to read this frame
@@ -165,15 +559,116 @@ pub fn server_recv_packet_system(
     }
 }
 
+/// Sends an encoded packet, split into fragments if it does not fit within the MTU, compressing
+/// each datagram if the destination accepts it and then encrypting it once there is a session,
+/// unless `encryption` is `None`.
+fn send_packet(
+    socket: &Socket,
+    fragmenter: &mut Fragmenter,
+    compression: &mut Compression,
+    mut encryption: Option<&mut Encryption>,
+    stats: &mut NetworkStats,
+    destination: SocketAddr,
+    encoded: Bytes,
+) -> Result<(), SocketError> {
+    let datagrams = fragmenter
+        .split(encoded)
+        .ok_or(SocketError::Other(ErrorKind::InvalidInput))?;
+    for datagram in datagrams {
+        let datagram = compression.compress(destination, datagram);
+        let datagram = match encryption.as_deref_mut() {
+            Some(encryption) => encryption.encrypt(destination, datagram),
+            None => datagram,
+        };
+        socket.send_to(&datagram, destination)?;
+        stats.record_sent(destination, datagram.len());
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn send_packet_system(
+    time: Res<Time>,
     socket: Res<Socket>,
     mut events: EventWriter<NetworkEvent>,
+    mut net: ResMut<NetworkResource>,
     mut transport: ResMut<Transport>,
+    mut reliable: ResMut<ReliableChannels>,
+    mut fragmenter: ResMut<Fragmenter>,
+    mut compression: ResMut<Compression>,
+    mut encryption: ResMut<Encryption>,
+    mut stats: ResMut<NetworkStats>,
 ) {
-    let messages = transport.drain_messages_to_send(|_| true);
-    for message in messages {
-        if let Err(e) = socket.send_to(&message.payload, message.destination) {
-            events.send(NetworkEvent::SendError(e, message))
+    // Every packet bound for an address is batched into as few datagrams as possible, each
+    // alongside the message reported should sending it fail. Packets sent in the clear are kept
+    // apart from the rest.
+    let mut outgoing: HashMap<(SocketAddr, bool), Vec<(Bytes, RawMessage)>> = HashMap::new();
+    let mut queue = |destination: SocketAddr, packet: Packet, message: RawMessage| {
+        let encrypted = message.delivery != Delivery::Unencrypted;
+        outgoing.entry((destination, encrypted)).or_default().push((packet.encode(), message));
+    };
+
+    for (destination, ack) in reliable.take_acks() {
+        let message = RawMessage::new(destination, &ack.encode(), Delivery::Unreliable);
+        queue(destination, ack, message);
+    }
+
+    let (resends, failures) = reliable.collect_resends(time.elapsed());
+    for (destination, packet) in resends {
+        stats.record_reliable_sent(destination, true);
+        let message = RawMessage::new(destination, &packet.encode(), Delivery::ReliableOrdered);
+        queue(destination, packet, message);
+    }
+    for message in failures {
+        let destination = message.destination;
+        events.send(NetworkEvent::DeliveryFailed(message));
+        // The peer would hold back every later reliable message waiting for this one, so the
+        // connection is dropped and both ends start their channels over on the next handshake
+        disconnect(
+            destination,
+            DisconnectReason::Unacknowledged,
+            &mut net,
+            &mut reliable,
+            &mut events,
+        );
+    }
+
+    if transport.has_messages() {
+        let reliable_messages =
+            transport.drain_messages_to_send(|m| m.delivery == Delivery::ReliableOrdered);
+        for message in reliable_messages {
+            // Rejected before it takes a sequence number, which the peer would wait on forever
+            if RELIABLE_HEADER_LEN + message.payload.len() > fragmenter.max_packet_len() {
+                events.send(NetworkEvent::SendError(SocketError::Other(ErrorKind::InvalidInput), message));
+                continue;
+            }
+            let packet = reliable.queue(message.destination, message.payload.clone(), time.elapsed());
+            stats.record_reliable_sent(message.destination, false);
+            queue(message.destination, packet, message);
+        }
+
+        let messages = transport.drain_messages_to_send(|_| true);
+        for message in messages {
+            let packet = Packet::Unreliable(message.payload.clone());
+            queue(message.destination, packet, message);
+        }
+    }
+
+    for ((destination, encrypted), packets) in outgoing {
+        for (datagram, messages) in Packet::batch(packets, fragmenter.mtu) {
+            if let Err(e) = send_packet(
+                &socket,
+                &mut fragmenter,
+                &mut compression,
+                encrypted.then_some(&mut *encryption),
+                &mut stats,
+                destination,
+                datagram,
+            ) {
+                for message in messages {
+                    events.send(NetworkEvent::SendError(e, message))
+                }
+            }
         }
     }
 }
@@ -181,26 +676,256 @@ pub fn send_packet_system(
 pub fn idle_timeout_system(
     time: Res<Time>,
     mut net: ResMut<NetworkResource>,
+    mut reliable: ResMut<ReliableChannels>,
     mut events: EventWriter<NetworkEvent>,
 ) {
-    let idle_timeout = net.idle_timeout.clone();
+    let idle_timeout = net.idle_timeout;
     net.connections.retain(|addr, last_update| {
         let reached_idle_timeout = time.elapsed() - *last_update > idle_timeout;
         if reached_idle_timeout {
             println!("Reached idle timeout");
-            events.send(NetworkEvent::Disconnected(*addr));
+            reliable.remove(addr);
+            events.send(NetworkEvent::Disconnected(*addr, DisconnectReason::TimedOut));
         }
         !reached_idle_timeout
     });
 }
 
+/// Sends a heartbeat to every live connection, so that the other end does not time us out while
+/// there is nothing else to send.
 pub fn auto_heartbeat_system(
     time: Res<Time>,
     mut timer: ResMut<HeartbeatTimer>,
-    remote_addr: Res<SocketAddress>,
+    net: Res<NetworkResource>,
     mut transport: ResMut<Transport>,
 ) {
     if timer.0.tick(time.delta()).just_finished() {
-        transport.send(remote_addr.0, Default::default());
+        for addr in net.connections.keys() {
+            transport.send(*addr, &[]);
+        }
+    }
+}
+
+/// Tells every live connection that we are going away once the app is exiting. This runs after
+/// `send_packet_system` has had its last chance to run, so it writes to the socket directly.
+pub fn disconnect_on_exit_system(
+    mut exit: EventReader<AppExit>,
+    socket: Res<Socket>,
+    net: Res<NetworkResource>,
+    mut encryption: ResMut<Encryption>,
+) {
+    if exit.iter().next().is_none() {
+        return;
+    }
+    let datagram = Packet::Unreliable(serialize(Message::Disconnect)).encode();
+    for addr in net.connections.keys() {
+        info!("{}: sending disconnect", addr);
+        if let Err(e) = socket.send_to(&encryption.encrypt(*addr, datagram.clone()), *addr) {
+            error!("{}: could not send disconnect: {:?}", addr, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::fragmentation::DEFAULT_MTU;
+    use crate::networking::handshake::{build_hash, PROTOCOL_VERSION};
+    use crate::networking::ServerPlugin;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// A server listening on port 8080 of `network`.
+    fn server_app(network: &VirtualNetwork) -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(Socket(Box::new(network.bind(addr(8080)))))
+            .add_plugins(ServerPlugin {
+                listen_addr: addr(8080).to_string(),
+                mtu: DEFAULT_MTU,
+                tick_duration: Duration::from_millis(50),
+                compression: false,
+            });
+        app
+    }
+
+    #[test]
+    fn test_in_memory_sockets_route_by_address() {
+        let network = VirtualNetwork::default();
+        let server = network.bind(addr(8080));
+        let client = network.connect(addr(3000), addr(8080));
+        let stranger = network.bind(addr(3001));
+        let mut buf = [0; 16];
+
+        client.send_to(b"hello", addr(8080)).unwrap();
+        assert_eq!(server.recv_from(&mut buf).unwrap(), (5, addr(3000)));
+        assert_eq!(&buf[..5], b"hello");
+        assert!(matches!(server.recv_from(&mut buf), Err(SocketError::NoInput())));
+
+        // A connected socket only reads from its peer
+        stranger.send_to(b"spam", addr(3000)).unwrap();
+        server.send_to(b"hi", addr(3000)).unwrap();
+        assert_eq!(client.recv_from(&mut buf).unwrap(), (2, addr(8080)));
+        assert!(matches!(client.recv_from(&mut buf), Err(SocketError::NoInput())));
+        assert_eq!(client.peer_addr().unwrap(), addr(8080));
+        assert!(server.peer_addr().is_err());
+
+        // Datagrams sent after a socket is dropped never reach the next one bound to its address
+        drop(client);
+        server.send_to(b"bye", addr(3000)).unwrap();
+        let client = network.connect(addr(3000), addr(8080));
+        assert!(matches!(client.recv_from(&mut buf), Err(SocketError::NoInput())));
+    }
+
+    #[test]
+    fn test_rejects_reliable_message_too_large_to_fragment() {
+        let network = VirtualNetwork::default();
+        let _client = network.connect(addr(3000), addr(8080));
+        let mut app = server_app(&network);
+
+        let too_large = vec![0; app.world.resource::<Fragmenter>().max_packet_len()];
+        app.world.resource_mut::<Transport>().send_reliable(addr(3000), &too_large);
+        app.update();
+
+        let events = app.world.resource::<Events<NetworkEvent>>();
+        let errors = events
+            .iter_current_update_events()
+            .filter(|event| {
+                matches!(event, NetworkEvent::SendError(_, message) if message.payload.len() == too_large.len())
+            })
+            .count();
+        assert_eq!(errors, 1);
+        // Never queued, so it is not resent either
+        let mut reliable = app.world.resource_mut::<ReliableChannels>();
+        assert!(reliable.collect_resends(Duration::from_secs(60)).0.is_empty());
+    }
+
+    #[test]
+    fn test_ignores_heartbeats_and_reliable_packets_from_unconnected_addresses() {
+        let network = VirtualNetwork::default();
+        let client = network.connect(addr(3000), addr(8080));
+        let mut app = server_app(&network);
+
+        let reliable = Packet::Reliable {
+            sequence: 3,
+            payload: serialize(Message::Throw),
+        };
+        for packet in [Packet::Unreliable(Bytes::new()), reliable, Packet::Ack(1)] {
+            client.send_to(&packet.encode(), addr(8080)).unwrap();
+        }
+        client.send_to(b"\xff garbage", addr(8080)).unwrap();
+        app.update();
+
+        let events = app.world.resource::<Events<NetworkEvent>>();
+        let malformed = events
+            .iter_current_update_events()
+            .filter(|event| matches!(event, NetworkEvent::MalformedPacket(..)))
+            .count();
+        assert_eq!(malformed, 1);
+    }
+
+    /// Sends a message to the server in the clear, as a client does during the handshake.
+    fn send_in_the_clear(client: &SocketTest, message: Message) {
+        client
+            .send_to(&Packet::Unreliable(serialize(message)).encode(), addr(8080))
+            .unwrap();
+    }
+
+    /// Reads every datagram waiting for `client` and returns the token and salt of the challenge
+    /// among them, skipping whatever else the server sent.
+    fn read_challenge(client: &SocketTest) -> Option<(u64, [u8; 16])> {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let mut challenge = None;
+        while let Ok((len, _)) = client.recv_from(&mut buf) {
+            let datagram = Bytes::copy_from_slice(&buf[..len]);
+            if is_encrypted(&datagram) {
+                continue;
+            }
+            for packet in unbatch(datagram).unwrap_or_default() {
+                if let Packet::Unreliable(payload) = packet {
+                    if let Ok(Message::ConnectChallenge { token, salt, .. }) = deserialize(payload) {
+                        challenge = Some((token, salt));
+                    }
+                }
+            }
+        }
+        challenge
+    }
+
+    #[test]
+    fn test_connection_is_only_restarted_once_a_new_challenge_is_answered() {
+        let network = VirtualNetwork::default();
+        let client = network.connect(addr(3000), addr(8080));
+        let mut app = server_app(&network);
+        let request = || Message::ConnectRequest {
+            protocol_version: PROTOCOL_VERSION,
+            build_hash: build_hash(),
+        };
+        let connect = |app: &mut App| {
+            send_in_the_clear(&client, request());
+            app.update();
+            let (token, salt) = read_challenge(&client).expect("no challenge");
+            let public_key = Encryption::default().public_key();
+            let response = Message::ChallengeResponse {
+                token,
+                salt,
+                compression: false,
+                public_key,
+            };
+            send_in_the_clear(&client, response);
+            app.update();
+        };
+        let events = |app: &App| {
+            let events = app.world.resource::<Events<NetworkEvent>>();
+            events
+                .iter_current_update_events()
+                .filter_map(|event| match event {
+                    NetworkEvent::Connected(_) => Some(None),
+                    NetworkEvent::Disconnected(_, reason) => Some(Some(*reason)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        connect(&mut app);
+        assert_eq!(events(&app), vec![None]);
+
+        // Anyone could have sent this, so it is answered without touching the connection
+        send_in_the_clear(&client, request());
+        app.update();
+        assert!(read_challenge(&client).is_some());
+        assert!(events(&app).is_empty());
+        assert!(app.world.resource::<NetworkResource>().connections.contains_key(&addr(3000)));
+
+        connect(&mut app);
+        assert_eq!(events(&app), vec![Some(DisconnectReason::Restarted), None]);
+        assert!(app.world.resource::<Encryption>().is_confirmed(addr(3000)));
+    }
+
+    #[test]
+    fn test_server_plugin_runs_over_in_memory_socket() {
+        let network = VirtualNetwork::default();
+        let client = network.connect(addr(3000), addr(8080));
+        let mut app = server_app(&network);
+
+        let request = Message::ConnectRequest {
+            protocol_version: PROTOCOL_VERSION,
+            build_hash: build_hash(),
+        };
+        client
+            .send_to(&Packet::Unreliable(serialize(request)).encode(), addr(8080))
+            .unwrap();
+        app.update();
+
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let (len, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(from, addr(8080));
+        let reply = match Packet::decode(Bytes::copy_from_slice(&buf[..len])) {
+            Some(Packet::Unreliable(payload)) => deserialize(payload),
+            other => panic!("expected an unreliable packet, got {:?}", other),
+        };
+        assert!(matches!(reply, Ok(Message::ConnectChallenge { .. })));
     }
 }
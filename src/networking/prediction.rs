@@ -0,0 +1,317 @@
+/*
+   Client side prediction for the local player.
+
+   The client moves its LogicalPlayer straight away from its own input instead
+   of waiting for the server, and numbers every input it sends. Each input is
+   kept in a ring buffer along with the frame time it was applied for and the
+   position it led to.
+
+   The server answers with the state of the player after the latest input it
+   has applied. Inputs up to that one are dropped from the buffer, and if the
+   position predicted for it is further than the correction threshold from the
+   server's, the player is rewound to the server's state and the remaining
+   inputs are replayed through the FPS controller.
+
+   Replayed movement is integrated without collision response, so small errors
+   against walls are left for the next correction rather than snapped away.
+*/
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::ecs::system::BoxedSystem;
+use bevy_fps_controller::controller::{fps_controller_move, FpsControllerInput, LogicalPlayer};
+use bevy_rapier3d::prelude::Velocity;
+
+use crate::game::simulation::apply_network_input;
+use crate::networking::message::Message;
+
+/// How many unacknowledged inputs are kept for replaying.
+const DEFAULT_INPUT_HISTORY_LEN: usize = 128;
+/// How far, in metres, the predicted position may drift from the server's before it is corrected.
+const DEFAULT_CORRECTION_THRESHOLD: f32 = 0.25;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PredictedInput {
+    pub sequence: u32,
+    // The NetworkInput sent to the server
+    pub input: Message,
+    pub dt: Duration,
+    // Where the player ended up after the input was applied
+    pub position: Vec3,
+}
+
+/// The server's state of the local player after it applied an input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ServerState {
+    pub sequence: u32,
+    pub position: Vec3,
+    pub velocity: Vec3,
+}
+
+#[derive(Resource)]
+pub struct Prediction {
+    pub correction_threshold: f32,
+    pub capacity: usize,
+    next_sequence: u32,
+    history: VecDeque<PredictedInput>,
+    latest_state: Option<ServerState>,
+}
+
+impl Default for Prediction {
+    fn default() -> Self {
+        Self {
+            correction_threshold: DEFAULT_CORRECTION_THRESHOLD,
+            capacity: DEFAULT_INPUT_HISTORY_LEN,
+            // 0 is left for "no input applied yet" on the server
+            next_sequence: 1,
+            history: Default::default(),
+            latest_state: None,
+        }
+    }
+}
+
+impl Prediction {
+    pub fn next_sequence(&mut self) -> u32 {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        sequence
+    }
+
+    /// Forgets every input and server state, for a new connection whose inputs are numbered from
+    /// the start again.
+    pub fn reset(&mut self) {
+        self.next_sequence = 1;
+        self.history.clear();
+        self.latest_state = None;
+    }
+
+    /// Remembers an input that has just been applied locally, dropping the oldest one once the
+    /// buffer is full.
+    pub fn record(&mut self, input: PredictedInput) {
+        if self.history.len() == self.capacity {
+            self.history.pop_front();
+        }
+        self.history.push_back(input);
+    }
+
+    /// Stores where the latest input moved the player to.
+    pub fn record_position(&mut self, position: Vec3) {
+        if let Some(latest) = self.history.back_mut() {
+            latest.position = position;
+        }
+    }
+
+    /// Keeps the newest state received from the server until it is reconciled.
+    pub fn receive_state(&mut self, state: ServerState) {
+        match self.latest_state {
+            Some(latest) if latest.sequence >= state.sequence => (),
+            _ => self.latest_state = Some(state),
+        }
+    }
+
+    /// Drops every input the server has applied. Returns the inputs left to replay on top of the
+    /// server's state if the prediction had diverged from it.
+    pub fn reconcile(&mut self, state: ServerState) -> Option<Vec<PredictedInput>> {
+        let predicted = self
+            .history
+            .iter()
+            .find(|predicted| predicted.sequence == state.sequence)
+            .map(|predicted| predicted.position);
+        self.history
+            .retain(|predicted| predicted.sequence > state.sequence);
+
+        // Without the prediction for this input there is nothing to compare against
+        let predicted = predicted?;
+        if predicted.distance(state.position) <= self.correction_threshold {
+            return None;
+        }
+        Some(self.history.iter().cloned().collect())
+    }
+
+    fn set_position(&mut self, sequence: u32, position: Vec3) {
+        if let Some(predicted) = self
+            .history
+            .iter_mut()
+            .find(|predicted| predicted.sequence == sequence)
+        {
+            predicted.position = position;
+        }
+    }
+}
+
+/// Records where physics moved the local player to this frame.
+pub fn record_predicted_position(
+    mut prediction: ResMut<Prediction>,
+    players: Query<&Transform, With<LogicalPlayer>>,
+) {
+    if let Ok(transform) = players.get_single() {
+        prediction.record_position(transform.translation);
+    }
+}
+
+/// Corrects the local player once the server's state for it has diverged from the prediction,
+/// by rewinding to the server's state and replaying the unacknowledged inputs.
+pub fn reconcile_prediction(world: &mut World, mut move_system: Local<Option<BoxedSystem>>) {
+    let state = match world.resource_mut::<Prediction>().latest_state.take() {
+        Some(state) => state,
+        None => return,
+    };
+    let player = match world
+        .query_filtered::<Entity, With<LogicalPlayer>>()
+        .get_single(world)
+    {
+        Ok(player) => player,
+        Err(_) => return,
+    };
+    let replay = match world.resource_mut::<Prediction>().reconcile(state) {
+        Some(replay) => replay,
+        None => return,
+    };
+
+    debug!(
+        "Correcting prediction at input {} and replaying {} inputs",
+        state.sequence,
+        replay.len()
+    );
+    {
+        let mut entity = world.entity_mut(player);
+        entity.get_mut::<Transform>().unwrap().translation = state.position;
+        entity.get_mut::<Velocity>().unwrap().linvel = state.velocity;
+    }
+
+    let move_system = move_system.get_or_insert_with(|| {
+        let mut system: BoxedSystem = Box::new(IntoSystem::into_system(fps_controller_move));
+        system.initialize(world);
+        system
+    });
+
+    let time = world.resource::<Time>().clone();
+    let current_input = std::mem::take(
+        world
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .as_mut(),
+    );
+
+    for predicted in replay {
+        // Run the controller as if this input's frame were happening again
+        let mut replay_time = time.clone();
+        replay_time.update_with_instant(
+            time.last_update().unwrap_or(time.startup()) + predicted.dt,
+        );
+        world.insert_resource(replay_time);
+        apply_network_input(
+            &predicted.input,
+            &mut world.get_mut::<FpsControllerInput>(player).unwrap(),
+        );
+        move_system.run((), world);
+        move_system.apply_deferred(world);
+
+        let mut entity = world.entity_mut(player);
+        let velocity = entity.get::<Velocity>().unwrap().linvel;
+        let mut transform = entity.get_mut::<Transform>().unwrap();
+        transform.translation += velocity * predicted.dt.as_secs_f32();
+        let position = transform.translation;
+        world
+            .resource_mut::<Prediction>()
+            .set_position(predicted.sequence, position);
+    }
+
+    world.insert_resource(time);
+    *world.get_mut::<FpsControllerInput>(player).unwrap() = current_input;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drops_acknowledged_inputs_without_correcting() {
+        let mut prediction = Prediction::default();
+        record_inputs(&mut prediction, 5);
+
+        let state = ServerState {
+            sequence: 3,
+            position: Vec3::new(3.1, 0., 0.),
+            velocity: Vec3::ZERO,
+        };
+        assert_eq!(prediction.reconcile(state), None);
+        assert_eq!(prediction.history.len(), 2);
+    }
+
+    #[test]
+    fn test_replays_unacknowledged_inputs_after_divergence() {
+        let mut prediction = Prediction::default();
+        record_inputs(&mut prediction, 5);
+
+        let state = ServerState {
+            sequence: 3,
+            position: Vec3::new(10., 0., 0.),
+            velocity: Vec3::ZERO,
+        };
+        let replay = prediction.reconcile(state).unwrap();
+        let sequences: Vec<u32> = replay.iter().map(|predicted| predicted.sequence).collect();
+        assert_eq!(sequences, vec![4, 5]);
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let mut prediction = Prediction {
+            capacity: 4,
+            ..Default::default()
+        };
+        record_inputs(&mut prediction, 10);
+
+        assert_eq!(prediction.history.len(), 4);
+        assert_eq!(prediction.history.front().unwrap().sequence, 7);
+    }
+
+    #[test]
+    fn test_keeps_newest_server_state() {
+        let mut prediction = Prediction::default();
+        let state = |sequence| ServerState {
+            sequence,
+            position: Vec3::ZERO,
+            velocity: Vec3::ZERO,
+        };
+
+        prediction.receive_state(state(5));
+        prediction.receive_state(state(4));
+        assert_eq!(prediction.latest_state, Some(state(5)));
+    }
+
+    #[test]
+    fn test_reset_numbers_inputs_from_the_start() {
+        let mut prediction = Prediction {
+            correction_threshold: 1.,
+            ..Default::default()
+        };
+        record_inputs(&mut prediction, 5);
+        prediction.receive_state(ServerState {
+            sequence: 5,
+            position: Vec3::ZERO,
+            velocity: Vec3::ZERO,
+        });
+
+        prediction.reset();
+        assert!(prediction.history.is_empty());
+        assert_eq!(prediction.latest_state, None);
+        assert_eq!(prediction.next_sequence(), 1);
+        assert_eq!(prediction.correction_threshold, 1.);
+    }
+
+    // Records inputs that each moved the player one metre along x
+    fn record_inputs(prediction: &mut Prediction, count: u32) {
+        for _ in 0..count {
+            let sequence = prediction.next_sequence();
+            prediction.record(PredictedInput {
+                sequence,
+                input: Message::Disconnect,
+                dt: Duration::from_millis(16),
+                position: Vec3::new(sequence as f32, 0., 0.),
+            });
+        }
+    }
+}
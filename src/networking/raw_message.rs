@@ -2,19 +2,34 @@ use std::net::SocketAddr;
 
 use bytes::Bytes;
 
+/// The delivery guarantee requested for a message.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Delivery {
+    /// Sent once; may be lost, duplicated or arrive out of order.
+    Unreliable,
+    /// Resent until acknowledged and delivered in the order it was sent.
+    ReliableOrdered,
+    /// Sent once like `Unreliable`, but never encrypted, for handshake replies to a client that
+    /// may not have the keys of its session any more.
+    Unencrypted,
+}
+
 pub struct RawMessage {
     /// The destination to send the message.
     pub destination: SocketAddr,
     /// The serialized payload itself.
     pub payload: Bytes,
+    /// How the message should be delivered.
+    pub delivery: Delivery,
 }
 
 impl RawMessage {
     /// Creates and returns a new Message.
-    pub(crate) fn new(destination: SocketAddr, payload: &[u8]) -> Self {
+    pub(crate) fn new(destination: SocketAddr, payload: &[u8], delivery: Delivery) -> Self {
         Self {
             destination,
             payload: Bytes::copy_from_slice(payload),
+            delivery,
         }
     }
 }
@@ -0,0 +1,310 @@
+/*
+   Reliable, ordered delivery on top of the unreliable UDP transport.
+
+   Every reliable message is given a per-connection sequence number and kept
+   in flight until the peer acknowledges it, being resent on a timer until it
+   is acknowledged or gives up after a maximum number of attempts. The
+   receiving side acknowledges every reliable packet it sees and only hands
+   messages on once every earlier sequence number has been delivered.
+
+   Since the peer would wait forever for a message that was given up on, the
+   connection is dropped when that happens, and both ends forget their channel
+   and start again from sequence number 0 once they reconnect.
+
+   Acks for packets that were only sent once also measure the round trip time,
+   which is left for the network statistics to collect.
+*/
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bevy::ecs::system::Resource;
+use bytes::Bytes;
+
+use super::packet::Packet;
+use super::raw_message::{Delivery, RawMessage};
+
+/// How long to wait for an ack before sending a reliable packet again.
+const DEFAULT_RESEND_INTERVAL_SECS: f32 = 0.2;
+/// How many times a reliable packet is sent before it is reported as failed.
+const DEFAULT_MAX_SEND_ATTEMPTS: u32 = 10;
+/// How far ahead of the next expected sequence number packets are buffered.
+const RECEIVE_WINDOW: u16 = 256;
+
+/// Returns true if `s1` comes after `s2`, accounting for wraparound.
+pub fn sequence_greater_than(s1: u16, s2: u16) -> bool {
+    s1 != s2 && s1.wrapping_sub(s2) < u16::MAX / 2
+}
+
+struct InFlight {
+    payload: Bytes,
+    last_sent: Duration,
+    attempts: u32,
+}
+
+#[derive(Default)]
+struct ReliableChannel {
+    next_send_sequence: u16,
+    next_recv_sequence: u16,
+    in_flight: HashMap<u16, InFlight>,
+    received: HashMap<u16, Bytes>,
+}
+
+#[derive(Resource)]
+pub struct ReliableChannels {
+    channels: HashMap<SocketAddr, ReliableChannel>,
+    pending_acks: Vec<(SocketAddr, u16)>,
+    // Round trip times measured since the last call to take_rtt_samples
+    rtt_samples: Vec<(SocketAddr, Duration)>,
+    pub resend_interval: Duration,
+    pub max_send_attempts: u32,
+}
+
+impl Default for ReliableChannels {
+    fn default() -> Self {
+        Self {
+            channels: Default::default(),
+            pending_acks: Vec::new(),
+            rtt_samples: Vec::new(),
+            resend_interval: Duration::from_secs_f32(DEFAULT_RESEND_INTERVAL_SECS),
+            max_send_attempts: DEFAULT_MAX_SEND_ATTEMPTS,
+        }
+    }
+}
+
+impl ReliableChannels {
+    /// Assigns the next sequence number for `destination` to the payload and keeps it in flight
+    /// until acknowledged. Returns the packet to put on the wire.
+    pub fn queue(&mut self, destination: SocketAddr, payload: Bytes, now: Duration) -> Packet {
+        let channel = self.channels.entry(destination).or_default();
+        let sequence = channel.next_send_sequence;
+        channel.next_send_sequence = sequence.wrapping_add(1);
+        channel.in_flight.insert(
+            sequence,
+            InFlight {
+                payload: payload.clone(),
+                last_sent: now,
+                attempts: 1,
+            },
+        );
+        Packet::Reliable { sequence, payload }
+    }
+
+    /// Records a reliable packet from `source` and returns every payload that can now be
+    /// delivered in order. Duplicates and packets too far ahead are dropped.
+    pub fn receive(&mut self, source: SocketAddr, sequence: u16, payload: Bytes) -> Vec<Bytes> {
+        let channel = self.channels.entry(source).or_default();
+        let expected = channel.next_recv_sequence;
+
+        if sequence.wrapping_sub(expected) >= RECEIVE_WINDOW && sequence_greater_than(sequence, expected) {
+            // Not acknowledged so the sender keeps it until we have room.
+            return Vec::new();
+        }
+        // Always acknowledge, even duplicates, in case our previous ack was lost.
+        self.pending_acks.push((source, sequence));
+
+        if sequence_greater_than(expected, sequence) {
+            return Vec::new();
+        }
+        channel.received.entry(sequence).or_insert(payload);
+
+        let mut delivered = Vec::new();
+        while let Some(payload) = channel.received.remove(&channel.next_recv_sequence) {
+            delivered.push(payload);
+            channel.next_recv_sequence = channel.next_recv_sequence.wrapping_add(1);
+        }
+        delivered
+    }
+
+    /// Stops resending the packet `sequence` previously sent to `source`. If it was only sent
+    /// once, the time since then is kept as a round trip time; an ack for a resent packet could
+    /// be for any of its copies.
+    pub fn acknowledge(&mut self, source: SocketAddr, sequence: u16, now: Duration) {
+        let in_flight = self
+            .channels
+            .get_mut(&source)
+            .and_then(|channel| channel.in_flight.remove(&sequence));
+        if let Some(in_flight) = in_flight.filter(|in_flight| in_flight.attempts == 1) {
+            self.rtt_samples.push((source, now.saturating_sub(in_flight.last_sent)));
+        }
+    }
+
+    /// Drains the round trip times measured since the last call.
+    pub fn take_rtt_samples(&mut self) -> Vec<(SocketAddr, Duration)> {
+        std::mem::take(&mut self.rtt_samples)
+    }
+
+    /// Drains the acks that need to be sent since the last call.
+    pub fn take_acks(&mut self) -> Vec<(SocketAddr, Packet)> {
+        self.pending_acks
+            .drain(..)
+            .map(|(addr, sequence)| (addr, Packet::Ack(sequence)))
+            .collect()
+    }
+
+    /// Returns the packets whose resend timer has elapsed, along with the messages that ran out
+    /// of attempts and were given up on.
+    pub fn collect_resends(&mut self, now: Duration) -> (Vec<(SocketAddr, Packet)>, Vec<RawMessage>) {
+        let mut resends = Vec::new();
+        let mut failures = Vec::new();
+
+        for (addr, channel) in self.channels.iter_mut() {
+            channel.in_flight.retain(|sequence, in_flight| {
+                if now - in_flight.last_sent < self.resend_interval {
+                    return true;
+                }
+                if in_flight.attempts >= self.max_send_attempts {
+                    failures.push(RawMessage::new(*addr, &in_flight.payload, Delivery::ReliableOrdered));
+                    return false;
+                }
+                in_flight.attempts += 1;
+                in_flight.last_sent = now;
+                resends.push((
+                    *addr,
+                    Packet::Reliable {
+                        sequence: *sequence,
+                        payload: in_flight.payload.clone(),
+                    },
+                ));
+                true
+            });
+        }
+
+        (resends, failures)
+    }
+
+    /// Forgets all sequencing state for a connection, e.g. once it has disconnected.
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.channels.remove(addr);
+        self.pending_acks.retain(|(ack_addr, _)| ack_addr != addr);
+        self.rtt_samples.retain(|(sample_addr, _)| sample_addr != addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence_greater_than_wraps() {
+        assert!(sequence_greater_than(1, 0));
+        assert!(sequence_greater_than(0, u16::MAX));
+        assert!(!sequence_greater_than(u16::MAX, 0));
+        assert!(!sequence_greater_than(5, 5));
+    }
+
+    #[test]
+    fn test_delivers_in_order() {
+        let mut channels = ReliableChannels::default();
+        let addr = test_addr();
+
+        assert!(channels.receive(addr, 1, payload(b"b")).is_empty());
+        assert!(channels.receive(addr, 2, payload(b"c")).is_empty());
+        assert_eq!(
+            channels.receive(addr, 0, payload(b"a")),
+            vec![payload(b"a"), payload(b"b"), payload(b"c")]
+        );
+        assert_eq!(channels.take_acks().len(), 3);
+    }
+
+    #[test]
+    fn test_duplicates_are_acked_but_not_delivered() {
+        let mut channels = ReliableChannels::default();
+        let addr = test_addr();
+
+        assert_eq!(channels.receive(addr, 0, payload(b"a")).len(), 1);
+        assert!(channels.receive(addr, 0, payload(b"a")).is_empty());
+        assert_eq!(channels.take_acks(), vec![(addr, Packet::Ack(0)), (addr, Packet::Ack(0))]);
+    }
+
+    #[test]
+    fn test_resends_until_acknowledged() {
+        let mut channels = ReliableChannels::default();
+        let addr = test_addr();
+        let interval = channels.resend_interval;
+
+        channels.queue(addr, payload(b"a"), Duration::ZERO);
+        assert!(channels.collect_resends(Duration::ZERO).0.is_empty());
+
+        let (resends, failures) = channels.collect_resends(interval);
+        assert_eq!(resends.len(), 1);
+        assert!(failures.is_empty());
+
+        channels.acknowledge(addr, 0, interval * 2);
+        assert!(channels.collect_resends(interval * 2).0.is_empty());
+        // The ack could have been for either copy, so it doesn't tell the round trip time
+        assert!(channels.take_rtt_samples().is_empty());
+    }
+
+    #[test]
+    fn test_measures_rtt_of_packets_sent_once() {
+        let mut channels = ReliableChannels::default();
+        let addr = test_addr();
+
+        channels.queue(addr, payload(b"a"), Duration::from_millis(100));
+        channels.acknowledge(addr, 0, Duration::from_millis(180));
+        channels.acknowledge(addr, 0, Duration::from_millis(190));
+        assert_eq!(channels.take_rtt_samples(), vec![(addr, Duration::from_millis(80))]);
+        assert!(channels.take_rtt_samples().is_empty());
+    }
+
+    #[test]
+    fn test_reports_failure_after_max_attempts() {
+        let mut channels = ReliableChannels::default();
+        let addr = test_addr();
+        let interval = channels.resend_interval;
+
+        channels.queue(addr, payload(b"a"), Duration::ZERO);
+        for attempt in 1..channels.max_send_attempts {
+            let (resends, failures) = channels.collect_resends(interval * attempt);
+            assert_eq!(resends.len(), 1);
+            assert!(failures.is_empty());
+        }
+
+        let (resends, failures) = channels.collect_resends(interval * channels.max_send_attempts);
+        assert!(resends.is_empty());
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].payload, payload(b"a"));
+    }
+
+    #[test]
+    fn test_channel_starts_over_after_failure() {
+        let mut sender = ReliableChannels::default();
+        let mut receiver = ReliableChannels::default();
+        let addr = test_addr();
+        let interval = sender.resend_interval;
+
+        // The first message is lost every time, so the second is held back behind it
+        sender.queue(addr, payload(b"a"), Duration::ZERO);
+        let b = sender.queue(addr, payload(b"b"), Duration::ZERO);
+        let Packet::Reliable { sequence, payload: b } = b else {
+            panic!("expected a reliable packet");
+        };
+        assert!(receiver.receive(addr, sequence, b).is_empty());
+        sender.acknowledge(addr, sequence, Duration::ZERO);
+
+        let mut failures = Vec::new();
+        for attempt in 1..=sender.max_send_attempts {
+            failures.extend(sender.collect_resends(interval * attempt).1);
+        }
+        assert_eq!(failures.len(), 1);
+
+        // Dropping the connection resets both ends, after which messages arrive again
+        sender.remove(&addr);
+        receiver.remove(&addr);
+        let Packet::Reliable { sequence, payload: c } = sender.queue(addr, payload(b"c"), interval * 20)
+        else {
+            panic!("expected a reliable packet");
+        };
+        assert_eq!(receiver.receive(addr, sequence, c), vec![payload(b"c")]);
+    }
+
+    fn payload(bytes: &'static [u8]) -> Bytes {
+        Bytes::from_static(bytes)
+    }
+
+    fn test_addr() -> SocketAddr {
+        "127.0.0.1:3000".parse().unwrap()
+    }
+}
@@ -0,0 +1,361 @@
+/*
+   Replicates gameplay components from the server's NetworkObjects to clients.
+
+   A component is marked replicated by implementing `Replicated` for it and
+   calling `App::replicate` on both the server and the client. Each replicated
+   component type gets a kind number from the order it was registered in, so
+   both sides must register the same components in the same order; the
+   handshake already guarantees both run the same build.
+
+   Every tick the server serializes each replicated component and compares it
+   with what it last sent each client. Only the components that changed are
+   sent, reliably, in a ComponentUpdate, and clients insert them on the
+   entity with the matching owner and object id. A component that was sent
+   and is gone from an object that still exists is sent as a ComponentRemoved,
+   and clients remove it too; objects that are gone altogether are despawned
+   instead. Newly connected clients have nothing recorded, so they are sent
+   every component.
+*/
+
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use bevy::ecs::system::EntityCommands;
+use bevy::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::networking::components::{NetworkObject, ObjectId};
+use crate::networking::message::{serialize, DeserializeError, Message};
+use crate::networking::resources::{NetworkGame, PlayerId};
+use crate::networking::Transport;
+
+/// A component whose value the server replicates to every client.
+pub trait Replicated: Component + Serialize + DeserializeOwned {}
+
+struct ReplicatedKind {
+    name: &'static str,
+    insert: fn(&mut EntityCommands, &[u8]) -> Result<(), DeserializeError>,
+    remove: fn(&mut EntityCommands),
+}
+
+#[derive(Resource, Default)]
+pub struct ReplicationRegistry {
+    kinds: Vec<ReplicatedKind>,
+    by_type: HashMap<TypeId, u16>,
+}
+
+impl ReplicationRegistry {
+    fn register<T: Replicated>(&mut self) -> u16 {
+        if let Some(kind) = self.by_type.get(&TypeId::of::<T>()) {
+            return *kind;
+        }
+        let kind = u16::try_from(self.kinds.len()).expect("too many replicated components");
+        self.kinds.push(ReplicatedKind {
+            name: std::any::type_name::<T>(),
+            insert: insert_component::<T>,
+            remove: remove_component::<T>,
+        });
+        self.by_type.insert(TypeId::of::<T>(), kind);
+        kind
+    }
+
+    pub fn kind_of<T: Replicated>(&self) -> Option<u16> {
+        self.by_type.get(&TypeId::of::<T>()).copied()
+    }
+}
+
+fn insert_component<T: Replicated>(entity: &mut EntityCommands, data: &[u8]) -> Result<(), DeserializeError> {
+    let component: T = serde_cbor::from_slice(data).map_err(|err| DeserializeError(err.to_string()))?;
+    entity.insert(component);
+    Ok(())
+}
+
+fn remove_component<T: Replicated>(entity: &mut EntityCommands) {
+    entity.remove::<T>();
+}
+
+/// A replicated component of an object: its owner, object id and component kind.
+type ComponentKey = (PlayerId, ObjectId, u16);
+
+/// The serialized components the server last sent each client.
+#[derive(Resource, Default)]
+pub struct ReplicationState {
+    clients: HashMap<SocketAddr, HashMap<ComponentKey, Vec<u8>>>,
+}
+
+pub trait AppReplicationExt {
+    /// Replicates `T` from the server to clients. Must be called on both, in the same order.
+    fn replicate<T: Replicated>(&mut self) -> &mut Self;
+}
+
+impl AppReplicationExt for App {
+    fn replicate<T: Replicated>(&mut self) -> &mut Self {
+        let kind = self
+            .world
+            .get_resource_or_insert_with(ReplicationRegistry::default)
+            .register::<T>();
+        debug!("Replicating {} as kind {}", std::any::type_name::<T>(), kind);
+
+        self.init_resource::<ReplicationState>().add_systems(
+            Update,
+            send_component_updates::<T>.run_if(resource_exists::<NetworkGame>()),
+        )
+    }
+}
+
+/// Sends each client the components of type `T` that differ from what it was last sent, and
+/// tells it about the ones removed from objects that still exist.
+pub fn send_component_updates<T: Replicated>(
+    registry: Res<ReplicationRegistry>,
+    mut state: ResMut<ReplicationState>,
+    network: Res<NetworkGame>,
+    components: Query<(&NetworkObject, &T)>,
+    mut transport: ResMut<Transport>,
+) {
+    let kind = match registry.kind_of::<T>() {
+        Some(kind) => kind,
+        None => return,
+    };
+    let current: HashMap<(PlayerId, ObjectId), Vec<u8>> = components
+        .iter()
+        .map(|(object, component)| {
+            let data = serde_cbor::to_vec(component).expect("could not serialize component");
+            ((object.owner, object.id), data)
+        })
+        .collect();
+
+    // Forget clients that have gone, so they are sent everything again if they come back
+    state
+        .clients
+        .retain(|addr, _| network.players.players.values().any(|player| player == addr));
+
+    for addr in network.players.players.values() {
+        let sent = state.clients.entry(*addr).or_default();
+        for ((owner, object), data) in current.iter() {
+            let key = (*owner, *object, kind);
+            if sent.get(&key) == Some(data) {
+                continue;
+            }
+            transport.send_reliable(
+                *addr,
+                &serialize(Message::ComponentUpdate {
+                    owner: *owner,
+                    object: *object,
+                    kind,
+                    data: data.clone(),
+                }),
+            );
+            sent.insert(key, data.clone());
+        }
+        sent.retain(|(owner, object, sent_kind), _| {
+            if *sent_kind != kind || current.contains_key(&(*owner, *object)) {
+                return true;
+            }
+            let exists = network
+                .objects
+                .objects
+                .keys()
+                .any(|network_object| network_object.owner == *owner && network_object.id == *object);
+            if exists {
+                transport.send_reliable(
+                    *addr,
+                    &serialize(Message::ComponentRemoved {
+                        owner: *owner,
+                        object: *object,
+                        kind,
+                    }),
+                );
+            }
+            false
+        });
+    }
+}
+
+/// Inserts replicated components received from the server on the objects they belong to, and
+/// removes the ones the server removed.
+pub fn apply_component_updates(
+    mut commands: Commands,
+    registry: Res<ReplicationRegistry>,
+    mut messages: EventReader<Message>,
+    objects: Query<(Entity, &NetworkObject)>,
+) {
+    for message in messages.iter() {
+        let (owner, object, kind, data) = match message {
+            Message::ComponentUpdate {
+                owner,
+                object,
+                kind,
+                data,
+            } => (owner, object, kind, Some(data)),
+            Message::ComponentRemoved {
+                owner,
+                object,
+                kind,
+            } => (owner, object, kind, None),
+            _ => continue,
+        };
+        let replicated = match registry.kinds.get(*kind as usize) {
+            Some(replicated) => replicated,
+            None => {
+                warn!("Received update for unknown component kind {}", kind);
+                continue;
+            }
+        };
+        let entity = objects
+            .iter()
+            .find(|(_, network_object)| network_object.owner == *owner && network_object.id == *object)
+            .map(|(entity, _)| entity);
+        let entity = match entity {
+            Some(entity) => entity,
+            None => {
+                debug!(
+                    "Received {} for unknown object {:?}/{}",
+                    replicated.name, owner, object
+                );
+                continue;
+            }
+        };
+        match data {
+            Some(data) => {
+                if let Err(err) = (replicated.insert)(&mut commands.entity(entity), data) {
+                    warn!("Could not apply {}: {}", replicated.name, err);
+                }
+            }
+            None => (replicated.remove)(&mut commands.entity(entity)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::components::NetworkObjectType;
+    use crate::networking::message::deserialize;
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Component, Serialize, Deserialize, PartialEq, Debug)]
+    struct Health(u8);
+
+    impl Replicated for Health {}
+
+    #[test]
+    fn test_sends_only_changed_components() {
+        let addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let mut app = App::new();
+        let mut network = NetworkGame::default();
+        network.players.add_player(PlayerId(1), addr);
+        app.insert_resource(network)
+            .insert_resource(Transport::new())
+            .replicate::<Health>();
+        let object = NetworkObject {
+            id: 7,
+            owner: PlayerId(1),
+            object_type: NetworkObjectType::Player,
+            is_owned: false,
+        };
+        let entity = app.world.spawn((object, Health(100))).id();
+
+        app.update();
+        assert_eq!(sent_updates(&mut app), 1);
+        app.update();
+        assert_eq!(sent_updates(&mut app), 0);
+
+        app.world.get_mut::<Health>(entity).unwrap().0 = 50;
+        app.update();
+        assert_eq!(sent_updates(&mut app), 1);
+    }
+
+    #[test]
+    fn test_sends_removals_from_objects_that_still_exist() {
+        let addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let mut app = App::new();
+        let mut network = NetworkGame::default();
+        network.players.add_player(PlayerId(1), addr);
+        let object = NetworkObject {
+            id: 7,
+            owner: PlayerId(1),
+            object_type: NetworkObjectType::Player,
+            is_owned: false,
+        };
+        let despawned = NetworkObject { id: 8, ..object };
+        network.objects.objects.insert(object, Default::default());
+        network.objects.objects.insert(despawned, Default::default());
+        app.insert_resource(network)
+            .insert_resource(Transport::new())
+            .replicate::<Health>();
+        let entity = app.world.spawn((object, Health(100))).id();
+        let despawned_entity = app.world.spawn((despawned, Health(100))).id();
+        app.update();
+        assert_eq!(sent_updates(&mut app), 2);
+
+        app.world.entity_mut(entity).remove::<Health>();
+        // Clients are told to despawn the whole object instead
+        app.world.despawn(despawned_entity);
+        app.world.resource_mut::<NetworkGame>().objects.objects.remove(&despawned);
+        app.update();
+        let sent: Vec<Message> = app
+            .world
+            .resource_mut::<Transport>()
+            .drain_messages_to_send(|_| true)
+            .into_iter()
+            .map(|message| deserialize(message.payload).unwrap())
+            .collect();
+        assert_eq!(
+            sent,
+            vec![Message::ComponentRemoved {
+                owner: PlayerId(1),
+                object: 7,
+                kind: 0,
+            }]
+        );
+
+        // Sent again if the component comes back
+        app.world.entity_mut(entity).insert(Health(100));
+        app.update();
+        assert_eq!(sent_updates(&mut app), 1);
+    }
+
+    #[test]
+    fn test_applies_updates_to_matching_object() {
+        let mut app = App::new();
+        app.add_event::<Message>()
+            .replicate::<Health>()
+            .add_systems(Update, apply_component_updates);
+        let object = NetworkObject {
+            id: 7,
+            owner: PlayerId(1),
+            object_type: NetworkObjectType::Player,
+            is_owned: false,
+        };
+        let entity = app.world.spawn(object).id();
+        let other = app.world.spawn(NetworkObject { id: 8, ..object }).id();
+
+        app.world.send_event(Message::ComponentUpdate {
+            owner: PlayerId(1),
+            object: 7,
+            kind: 0,
+            data: serde_cbor::to_vec(&Health(42)).unwrap(),
+        });
+        app.update();
+
+        assert_eq!(app.world.get::<Health>(entity), Some(&Health(42)));
+        assert_eq!(app.world.get::<Health>(other), None);
+
+        app.world.send_event(Message::ComponentRemoved {
+            owner: PlayerId(1),
+            object: 7,
+            kind: 0,
+        });
+        app.update();
+        assert_eq!(app.world.get::<Health>(entity), None);
+    }
+
+    fn sent_updates(app: &mut App) -> usize {
+        app.world
+            .resource_mut::<Transport>()
+            .drain_messages_to_send(|_| true)
+            .len()
+    }
+}
@@ -1,55 +1,154 @@
 use crate::networking::components::NetworkObjects;
+use crate::networking::id_allocator::IdAllocator;
 use bevy::prelude::Resource;
 
-use rand::Rng;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::time::Duration;
 
 #[derive(Resource, Default, Debug)]
 pub struct NetworkGame {
     pub(crate) players: Players,
     pub(crate) objects: NetworkObjects,
+    // Players whose connection dropped, kept until they resume or expire
+    pub(crate) suspended: HashMap<PlayerId, SuspendedPlayer>,
+}
+
+/// A player whose connection dropped without it saying goodbye.
+#[derive(Debug, Clone, Copy)]
+pub struct SuspendedPlayer {
+    pub since: Duration,
+    // The token of the session that was suspended, the only one that can resume it
+    session_token: u64,
+}
+
+impl NetworkGame {
+    /// Keeps a player whose connection dropped around for its client to resume, along with the
+    /// token of its session.
+    pub fn suspend(&mut self, id: PlayerId, now: Duration) {
+        // A player always has a session, but should it not, it gets one no client can know
+        let session_token = self
+            .players
+            .session_tokens
+            .remove(&id)
+            .unwrap_or_else(rand::random);
+        self.suspended.insert(
+            id,
+            SuspendedPlayer {
+                since: now,
+                session_token,
+            },
+        );
+    }
+
+    /// Resumes a suspended player if `session_token` is the one of its suspended session.
+    /// Returns the token of the new session.
+    pub fn resume(&mut self, id: PlayerId, session_token: u64) -> Option<u64> {
+        if self.suspended.get(&id)?.session_token != session_token {
+            return None;
+        }
+        self.suspended.remove(&id);
+        Some(self.players.new_session_token(id))
+    }
 }
 
 #[derive(PartialEq, Debug, Serialize, Hash, Deserialize, Resource, Eq, Clone, Copy)]
-pub struct PlayerId(pub u8);
+pub struct PlayerId(pub u16);
 
 #[derive(Resource, Default, Debug)]
 pub struct Players {
     pub players: HashMap<PlayerId, SocketAddr>,
+    // Ids sent to clients in ServerAcknowledgement that haven't been acknowledged yet
+    pub pending: HashMap<SocketAddr, PlayerId>,
+    // Names players gave themselves, kept while their player exists
+    pub names: HashMap<PlayerId, String>,
+    // Random token of each player's current session, which its client can resume it with
+    pub session_tokens: HashMap<PlayerId, u64>,
+    pub ids: IdAllocator,
 }
 
 impl Players {
-    pub fn for_all_except<F>(&self, excluded_id: PlayerId, mut action: F)
-    where
-        F: FnMut(&SocketAddr),
-    {
-        for (player_id, value) in &self.players {
-            if *player_id != excluded_id {
-                action(value);
-            }
-        }
-    }
-
     pub fn add_player(&mut self, id: PlayerId, addr: SocketAddr) {
         self.players.insert(id, addr);
     }
 
-    pub fn generate_id() -> PlayerId {
-        let mut rng = rand::thread_rng();
+    /// Reserves a new player id for a client that is still completing the handshake, and starts
+    /// a session for it.
+    pub fn assign_id(&mut self, addr: SocketAddr, now: Duration) -> Option<PlayerId> {
+        let id = PlayerId(self.ids.allocate(now)?);
+        if let Some(previous) = self.pending.insert(addr, id) {
+            self.release_id(previous, now);
+        }
+        self.new_session_token(id);
+        Some(id)
+    }
+
+    /// Starts a new session for a player, replacing the token of any previous one.
+    pub fn new_session_token(&mut self, id: PlayerId) -> u64 {
+        let session_token = rand::random();
+        self.session_tokens.insert(id, session_token);
+        session_token
+    }
 
-        // Generate a random i16 value in the range [-32768, 32767]
-        return PlayerId(rng.gen());
+    /// Releases a player's id for reuse, and forgets the token of its session so that no later
+    /// holder of the id can be resumed with it.
+    pub fn release_id(&mut self, id: PlayerId, now: Duration) {
+        self.session_tokens.remove(&id);
+        self.ids.release(id.0, now);
     }
 
     pub fn player_from_socket(&mut self, addr: SocketAddr) -> Option<PlayerId> {
         for (key, value) in self.players.iter() {
             if *value == addr {
-                return Some(key.clone());
+                return Some(*key);
             }
         }
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resumes_only_with_the_token_of_the_suspended_session() {
+        let addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let mut network = NetworkGame::default();
+        let id = network.players.assign_id(addr, Duration::ZERO).unwrap();
+        let token = network.players.session_tokens[&id];
+        network.suspend(id, Duration::ZERO);
+
+        assert_eq!(network.resume(id, token.wrapping_add(1)), None);
+        let resumed = network.resume(id, token).unwrap();
+        assert_ne!(resumed, token);
+        assert_eq!(network.players.session_tokens.get(&id), Some(&resumed));
+        // The session can't be resumed twice
+        assert_eq!(network.resume(id, token), None);
+    }
+
+    #[test]
+    fn test_token_of_a_recycled_id_is_rejected() {
+        let addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let mut network = NetworkGame::default();
+        network.players.ids = IdAllocator::new(Duration::ZERO);
+
+        // The first holder of the id leaves for good
+        let id = network.players.assign_id(addr, Duration::ZERO).unwrap();
+        let old_token = network.players.session_tokens[&id];
+        network.suspend(id, Duration::ZERO);
+        network.suspended.remove(&id);
+        network.players.release_id(id, Duration::ZERO);
+
+        // Another player gets the same id and is suspended in turn
+        let other_addr: SocketAddr = "127.0.0.1:3001".parse().unwrap();
+        assert_eq!(network.players.assign_id(other_addr, Duration::from_secs(1)), Some(id));
+        let new_token = network.players.session_tokens[&id];
+        network.suspend(id, Duration::from_secs(1));
+
+        assert_eq!(network.resume(id, old_token), None);
+        assert!(network.resume(id, new_token).is_some());
+    }
+}
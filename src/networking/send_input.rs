@@ -1,25 +1,81 @@
-use crate::networking::message::serialize;
-use crate::networking::message::Message::NetworkInput;
+use crate::networking::handshake::ConnectionStatus;
+use crate::networking::message::{quantize_angle, serialize};
+use crate::networking::message::Message::{NetworkInput, Throw};
 use crate::networking::packet_systems::Socket;
 use crate::networking::Transport;
+use crate::networking::prediction::{PredictedInput, Prediction};
 use bevy::input::Input;
-use bevy::prelude::{KeyCode, Res, ResMut};
+use bevy::prelude::{MouseButton, Query, Res, ResMut, Time, Transform, With};
+use bevy_fps_controller::controller::{FpsControllerInput, LogicalPlayer};
 
+/// Sends the server the local player's input every frame. The server moves the player from these,
+/// so they are sent unreliably and a lost one is simply replaced by the next. Each input is also
+/// kept for replaying if the local prediction turns out wrong. Runs before the controller moves
+/// the player, so that the prediction looks in the direction the server will read.
 pub fn send_player_input(
+    time: Res<Time>,
     socket: Res<Socket>,
-    keys: Res<Input<KeyCode>>,
+    connection_status: Res<ConnectionStatus>,
+    mut inputs: Query<(&mut FpsControllerInput, &Transform), With<LogicalPlayer>>,
+    mut prediction: ResMut<Prediction>,
     mut transport: ResMut<Transport>,
 ) {
-    transport.send(
-        socket
-            .0
-            .peer_addr()
-            .expect("Socket address could not be found"),
-        &serialize(NetworkInput {
-            w: keys.pressed(KeyCode::W),
-            s: keys.pressed(KeyCode::S),
-            a: keys.pressed(KeyCode::A),
-            d: keys.pressed(KeyCode::D),
-        }),
-    )
+    if !matches!(*connection_status, ConnectionStatus::Complete) {
+        return;
+    }
+
+    for (mut input, transform) in inputs.iter_mut() {
+        input.yaw = quantize_angle(input.yaw);
+        input.pitch = quantize_angle(input.pitch);
+        let sequence = prediction.next_sequence();
+        let message = NetworkInput {
+            sequence,
+            dt: time.delta(),
+            w: input.movement.z > 0.,
+            s: input.movement.z < 0.,
+            a: input.movement.x < 0.,
+            d: input.movement.x > 0.,
+            jump: input.jump,
+            crouch: input.crouch,
+            sprint: input.sprint,
+            yaw: input.yaw,
+            pitch: input.pitch,
+        };
+        prediction.record(PredictedInput {
+            sequence,
+            input: message.clone(),
+            dt: time.delta(),
+            position: transform.translation,
+        });
+
+        transport.send(
+            socket
+                .0
+                .peer_addr()
+                .expect("Socket address could not be found"),
+            &serialize(message),
+        )
+    }
+}
+
+/// Asks the server to throw an item whenever the right mouse button is pressed. Sent reliably,
+/// since unlike movement a lost throw isn't made up for by the next message.
+pub fn send_throw_requests(
+    socket: Res<Socket>,
+    connection_status: Res<ConnectionStatus>,
+    buttons: Res<Input<MouseButton>>,
+    mut transport: ResMut<Transport>,
+) {
+    if !matches!(*connection_status, ConnectionStatus::Complete) {
+        return;
+    }
+    if buttons.just_pressed(MouseButton::Right) {
+        transport.send_reliable(
+            socket
+                .0
+                .peer_addr()
+                .expect("Socket address could not be found"),
+            &serialize(Throw),
+        );
+    }
 }
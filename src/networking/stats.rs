@@ -0,0 +1,339 @@
+/*
+   Statistics about every connection, for watching how the network behaves.
+
+   The packet systems count the datagrams and bytes sent to and received from
+   each connected address, along with every reliable packet sent and resent.
+   Once an interval has passed the counts are turned into rates and added to a
+   short history of samples, which the client's debug overlay draws graphs of.
+   Bytes are counted as they are on the wire, after compression and encryption,
+   and received datagrams only once they are authenticated.
+
+   Round trip times are measured from acks for reliable packets, and on the
+   client from its pings too.
+
+   Loss is measured from the streams that carry a number going up by one with
+   every message: the tick of each world snapshot the server sends, and the
+   sequence number of each input a client sends. Numbers skipped over are
+   counted as lost, so the client sees the loss on the way from the server
+   and the server the loss on the way from each client. The resend rate, the
+   share of reliable packets that had to be resent, is kept alongside it; it
+   rises with loss in either direction, but also when acks are late.
+*/
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::networking::clock::ServerClock;
+use crate::networking::packet_systems::SocketAddress;
+use crate::networking::reliable::ReliableChannels;
+use crate::networking::NetworkResource;
+
+/// How often the counts are turned into a sample.
+const DEFAULT_SAMPLE_INTERVAL_SECS: f32 = 1.;
+/// How many samples are kept for each connection.
+const DEFAULT_HISTORY_LEN: usize = 60;
+/// How much each new round trip time moves the smoothed one.
+const RTT_SMOOTHING: f64 = 0.1;
+/// How far behind the highest number a numbered message can be before its stream is taken to
+/// have started over, as a client's inputs do when it reconnects.
+const MAX_NUMBER_REORDERING: u32 = 1024;
+
+/// What has been sent and received since the last sample.
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    packets_sent: u32,
+    packets_received: u32,
+    bytes_sent: u64,
+    bytes_received: u64,
+    // Reliable packets sent, resends included
+    reliable_sent: u32,
+    resent: u32,
+    // Numbered messages that should have arrived going by the highest number, and those that did
+    numbered_expected: u32,
+    numbered_received: u32,
+}
+
+/// Rates measured over one interval.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct StatsSample {
+    pub rtt: Option<Duration>,
+    pub packets_sent_per_sec: f32,
+    pub packets_received_per_sec: f32,
+    pub bytes_sent_per_sec: f32,
+    pub bytes_received_per_sec: f32,
+    // Percentage of the numbered messages from the other side that never arrived
+    pub loss: f32,
+    // Percentage of the reliable packets sent that were resends
+    pub resend_rate: f32,
+}
+
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    // Smoothed round trip time, once one has been measured
+    pub rtt: Option<Duration>,
+    // Reliable packets resent since the connection started
+    pub resends: u64,
+    // Oldest sample first
+    pub history: VecDeque<StatsSample>,
+    counts: Counts,
+    // Highest number received in a numbered message
+    highest_number: Option<u32>,
+}
+
+impl ConnectionStats {
+    /// The most recent sample, or an empty one before the first interval has passed.
+    pub fn latest(&self) -> StatsSample {
+        self.history.back().copied().unwrap_or_default()
+    }
+
+    fn record_rtt(&mut self, rtt: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(current) => {
+                let current_secs = current.as_secs_f64();
+                Duration::from_secs_f64(
+                    current_secs + (rtt.as_secs_f64() - current_secs) * RTT_SMOOTHING,
+                )
+            }
+            None => rtt,
+        });
+    }
+
+    fn sample(&mut self, elapsed: Duration, history_len: usize) {
+        let secs = elapsed.as_secs_f32();
+        let counts = std::mem::take(&mut self.counts);
+        let resend_rate = match counts.reliable_sent {
+            0 => 0.,
+            sent => counts.resent as f32 / sent as f32 * 100.,
+        };
+        // Late arrivals fill in gaps counted in an earlier interval, so can outnumber those expected
+        let loss = match counts.numbered_expected {
+            0 => 0.,
+            expected => (1. - counts.numbered_received as f32 / expected as f32).max(0.) * 100.,
+        };
+        self.history.push_back(StatsSample {
+            rtt: self.rtt,
+            packets_sent_per_sec: counts.packets_sent as f32 / secs,
+            packets_received_per_sec: counts.packets_received as f32 / secs,
+            bytes_sent_per_sec: counts.bytes_sent as f32 / secs,
+            bytes_received_per_sec: counts.bytes_received as f32 / secs,
+            loss,
+            resend_rate,
+        });
+        while self.history.len() > history_len {
+            self.history.pop_front();
+        }
+    }
+}
+
+/// Statistics for every connection: each client on the server, and the server on a client.
+#[derive(Resource)]
+pub struct NetworkStats {
+    pub connections: HashMap<SocketAddr, ConnectionStats>,
+    /// Time between samples.
+    pub interval: Duration,
+    /// How many samples are kept for each connection.
+    pub history_len: usize,
+    // When the current interval started
+    interval_start: Duration,
+}
+
+impl Default for NetworkStats {
+    fn default() -> Self {
+        Self {
+            connections: HashMap::new(),
+            interval: Duration::from_secs_f32(DEFAULT_SAMPLE_INTERVAL_SECS),
+            history_len: DEFAULT_HISTORY_LEN,
+            interval_start: Duration::ZERO,
+        }
+    }
+}
+
+impl NetworkStats {
+    pub fn get(&self, addr: SocketAddr) -> Option<&ConnectionStats> {
+        self.connections.get(&addr)
+    }
+
+    pub fn record_sent(&mut self, addr: SocketAddr, bytes: usize) {
+        let counts = &mut self.connections.entry(addr).or_default().counts;
+        counts.packets_sent += 1;
+        counts.bytes_sent += bytes as u64;
+    }
+
+    pub fn record_received(&mut self, addr: SocketAddr, bytes: usize) {
+        let counts = &mut self.connections.entry(addr).or_default().counts;
+        counts.packets_received += 1;
+        counts.bytes_received += bytes as u64;
+    }
+
+    /// Counts a message received from `addr` whose number goes up by one with every message its
+    /// sender sends, so that the numbers skipped over can be counted as lost.
+    pub fn record_numbered(&mut self, addr: SocketAddr, number: u32) {
+        let connection = self.connections.entry(addr).or_default();
+        let counts = &mut connection.counts;
+        match connection.highest_number {
+            Some(highest) if number > highest => {
+                counts.numbered_expected += number - highest;
+                counts.numbered_received += 1;
+                connection.highest_number = Some(number);
+            }
+            Some(highest) if highest - number > MAX_NUMBER_REORDERING => {
+                connection.highest_number = Some(number);
+            }
+            // Late, filling a gap that was counted as lost
+            Some(_) => counts.numbered_received += 1,
+            None => connection.highest_number = Some(number),
+        }
+    }
+
+    /// Counts a reliable packet queued for `addr`, which is a resend if it was sent before.
+    pub fn record_reliable_sent(&mut self, addr: SocketAddr, resend: bool) {
+        let connection = self.connections.entry(addr).or_default();
+        connection.counts.reliable_sent += 1;
+        if resend {
+            connection.counts.resent += 1;
+            connection.resends += 1;
+        }
+    }
+
+    /// Smooths a round trip time just measured into the connection's.
+    pub fn record_rtt(&mut self, addr: SocketAddr, rtt: Duration) {
+        self.connections.entry(addr).or_default().record_rtt(rtt);
+    }
+
+    /// Replaces the connection's round trip time with one that was already smoothed elsewhere.
+    pub fn set_rtt(&mut self, addr: SocketAddr, rtt: Duration) {
+        self.connections.entry(addr).or_default().rtt = Some(rtt);
+    }
+
+    /// Adds a sample to every connection once the interval has passed. Returns whether it did.
+    pub fn sample(&mut self, now: Duration) -> bool {
+        let elapsed = now.saturating_sub(self.interval_start);
+        if elapsed < self.interval || elapsed.is_zero() {
+            return false;
+        }
+        for connection in self.connections.values_mut() {
+            connection.sample(elapsed, self.history_len);
+        }
+        self.interval_start = now;
+        true
+    }
+}
+
+/// Collects the round trip times measured by the reliable channels, forgets the statistics of
+/// addresses that are no longer connected and takes a sample once an interval has passed.
+pub fn update_network_stats(
+    time: Res<Time>,
+    net: Res<NetworkResource>,
+    mut reliable: ResMut<ReliableChannels>,
+    mut stats: ResMut<NetworkStats>,
+) {
+    for (addr, rtt) in reliable.take_rtt_samples() {
+        stats.record_rtt(addr, rtt);
+    }
+    // Also drops what was counted for addresses that never got past the handshake
+    stats
+        .connections
+        .retain(|addr, _| net.connections.contains_key(addr));
+    stats.sample(time.elapsed());
+}
+
+/// Uses the round trip time measured by the client's pings, which go out far more often than
+/// anything reliable does.
+pub fn record_ping_rtt(
+    clock: Res<ServerClock>,
+    remote_addr: Res<SocketAddress>,
+    mut stats: ResMut<NetworkStats>,
+) {
+    if clock.is_changed() && clock.is_synced() {
+        stats.set_rtt(remote_addr.0, clock.rtt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_addr() -> SocketAddr {
+        "127.0.0.1:3000".parse().unwrap()
+    }
+
+    #[test]
+    fn test_samples_rates_over_interval() {
+        let mut stats = NetworkStats::default();
+        let addr = test_addr();
+        for _ in 0..4 {
+            stats.record_sent(addr, 100);
+            stats.record_reliable_sent(addr, false);
+        }
+        stats.record_reliable_sent(addr, true);
+        stats.record_received(addr, 50);
+
+        assert!(!stats.sample(Duration::from_millis(500)));
+        assert!(stats.sample(Duration::from_secs(2)));
+        let sample = stats.get(addr).unwrap().latest();
+        assert_eq!(sample.packets_sent_per_sec, 2.);
+        assert_eq!(sample.bytes_sent_per_sec, 200.);
+        assert_eq!(sample.bytes_received_per_sec, 25.);
+        assert_eq!(sample.resend_rate, 20.);
+        assert_eq!(stats.get(addr).unwrap().resends, 1);
+
+        // Counts start over for the next interval
+        assert!(stats.sample(Duration::from_secs(3)));
+        assert_eq!(stats.get(addr).unwrap().latest().packets_sent_per_sec, 0.);
+    }
+
+    #[test]
+    fn test_measures_loss_from_skipped_numbers() {
+        let mut stats = NetworkStats::default();
+        let addr = test_addr();
+        // 2000 is the first, so nothing is expected of it; 2002, 2005 and 2006 go missing
+        for number in [2000, 2001, 2003, 2004, 2007, 2008, 2009, 2010] {
+            stats.record_numbered(addr, number);
+        }
+        assert!(stats.sample(Duration::from_secs(1)));
+        let loss = stats.get(addr).unwrap().latest().loss;
+        assert!((loss - 30.).abs() < 1e-3, "measured {}% loss", loss);
+
+        // One of them turns up late, and then the sender starts over without any looking lost
+        stats.record_numbered(addr, 2006);
+        stats.record_numbered(addr, 1);
+        stats.record_numbered(addr, 2);
+        assert!(stats.sample(Duration::from_secs(2)));
+        assert_eq!(stats.get(addr).unwrap().latest().loss, 0.);
+        assert_eq!(stats.get(addr).unwrap().highest_number, Some(2));
+    }
+
+    #[test]
+    fn test_keeps_limited_history() {
+        let mut stats = NetworkStats {
+            history_len: 3,
+            ..default()
+        };
+        let addr = test_addr();
+        for second in 1..=5 {
+            stats.record_sent(addr, second);
+            stats.sample(Duration::from_secs(second as u64));
+        }
+        let history = &stats.get(addr).unwrap().history;
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.back().unwrap().bytes_sent_per_sec, 5.);
+    }
+
+    #[test]
+    fn test_smooths_rtt() {
+        let mut stats = NetworkStats::default();
+        let addr = test_addr();
+        stats.record_rtt(addr, Duration::from_millis(100));
+        assert_eq!(
+            stats.get(addr).unwrap().rtt,
+            Some(Duration::from_millis(100))
+        );
+
+        stats.record_rtt(addr, Duration::from_millis(200));
+        let rtt = stats.get(addr).unwrap().rtt.unwrap();
+        assert!(rtt > Duration::from_millis(100) && rtt < Duration::from_millis(200));
+    }
+}
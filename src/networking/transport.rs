@@ -1,11 +1,11 @@
 use std::{collections::VecDeque, net::SocketAddr};
 
-use super::raw_message::RawMessage;
+use super::raw_message::{Delivery, RawMessage};
 use bevy::ecs::system::Resource;
 
 /// Resource serving as the owner of the queue of messages to be sent. This resource also serves
 /// as the interface for other systems to send messages.
-#[derive(Resource)]
+#[derive(Resource, Default)]
 pub struct Transport {
     messages: VecDeque<RawMessage>,
 }
@@ -22,7 +22,23 @@ impl Transport {
     /// Creates a `Message` with the default guarantees provided by the `Socket` implementation and
     /// pushes it onto the messages queue to be sent on the next frame.
     pub fn send(&mut self, destination: SocketAddr, payload: &[u8]) {
-        let message = RawMessage::new(destination, payload);
+        let message = RawMessage::new(destination, payload, Delivery::Unreliable);
+        self.messages.push_back(message);
+    }
+
+    /// Creates a `Message` that is resent until the destination acknowledges it and is delivered
+    /// after every reliable message sent to that destination before it, then pushes it onto the
+    /// messages queue to be sent on the next frame.
+    pub fn send_reliable(&mut self, destination: SocketAddr, payload: &[u8]) {
+        let message = RawMessage::new(destination, payload, Delivery::ReliableOrdered);
+        self.messages.push_back(message);
+    }
+
+    /// Creates a `Message` that is sent unreliably and in the clear, even to a destination that
+    /// has an encrypted session, then pushes it onto the messages queue to be sent on the next
+    /// frame.
+    pub fn send_unencrypted(&mut self, destination: SocketAddr, payload: &[u8]) {
+        let message = RawMessage::new(destination, payload, Delivery::Unencrypted);
         self.messages.push_back(message);
     }
 
@@ -34,6 +50,7 @@ impl Transport {
 
     /// Returns a reference to the owned messages.
     #[must_use]
+    #[allow(dead_code)]
     pub fn get_messages(&self) -> &VecDeque<RawMessage> {
         &self.messages
     }
@@ -60,14 +77,6 @@ impl Transport {
     }
 }
 
-impl Default for Transport {
-    fn default() -> Self {
-        Self {
-            messages: VecDeque::new(),
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -85,6 +94,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_has_messages() {
         let mut transport = create_test_transport();
         assert_eq!(transport.has_messages(), false);
@@ -92,6 +102,22 @@ mod tests {
         assert_eq!(transport.has_messages(), true);
     }
 
+    #[test]
+    fn test_drain_only_reliable_messages() {
+        let mut transport = create_test_transport();
+
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        transport.send(addr, test_payload());
+        transport.send_reliable(addr, test_payload());
+        transport.send(addr, heartbeat_payload());
+
+        let reliable =
+            transport.drain_messages_to_send(|m| m.delivery == Delivery::ReliableOrdered);
+        assert_eq!(reliable.len(), 1);
+        assert_eq!(reliable[0].payload, test_payload());
+        assert_eq!(transport.drain_messages_to_send(|_| true).len(), 2);
+    }
+
     #[test]
     fn test_drain_only_heartbeat_messages() {
         let mut transport = create_test_transport();
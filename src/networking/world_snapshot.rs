@@ -0,0 +1,333 @@
+/*
+   Sends the state of every object to clients as one snapshot per server tick.
+
+   The server keeps the world state of its last few ticks, and each client
+   acknowledges every snapshot it receives. A client's next snapshot is encoded
+   against the latest one it acknowledged: only objects whose state differs
+   from that baseline are sent, along with the objects removed since. Snapshots
+   are sent unreliably, so a lost one is never resent; the next is simply
+   encoded against the same, older baseline until an ack gets through, which
+   brings the client back in line without any extra round trips. Clients with
+   no usable baseline, such as those that just joined, are sent the full world.
+
+   Clients rebuild the full world state of each tick from the delta and the
+   baseline they kept, and buffer every object's state for interpolation.
+   Ticks start over when the server restarts, which a client only ever
+   notices by having to go through the handshake again, so it forgets every
+   world state when it reconnects. Until then any snapshot older than the
+   latest one is stale and ignored, full or not.
+*/
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+
+use bevy::prelude::*;
+
+use crate::networking::clock::{ServerClock, ServerTick};
+use crate::networking::components::{NetworkObject, NetworkTransform, ObjectId, ObjectState, Snapshot};
+use crate::networking::message::{serialize, Message};
+use crate::networking::packet_systems::SocketAddress;
+use crate::networking::resources::{NetworkGame, PlayerId};
+use crate::networking::stats::NetworkStats;
+use crate::networking::{NetworkEvent, Transport};
+
+/// How many ticks of world state either side keeps to encode and decode deltas against.
+const SNAPSHOT_HISTORY_LEN: usize = 64;
+
+/// The state of every object at a tick, keyed by owner and object id.
+pub type WorldState = HashMap<(PlayerId, ObjectId), ObjectState>;
+
+/// The objects that changed, and the owners and ids of those that were removed.
+pub type Delta = (Vec<(PlayerId, ObjectId, ObjectState)>, Vec<(PlayerId, ObjectId)>);
+
+/// The objects whose state differs between `baseline` and `current`, and those that have been
+/// removed since. Without a baseline every object is changed.
+pub fn encode_delta(
+    baseline: Option<&WorldState>,
+    current: &WorldState,
+) -> Delta {
+    let changed = current
+        .iter()
+        .filter(|(key, state)| baseline.and_then(|baseline| baseline.get(key)) != Some(state))
+        .map(|((owner, id), state)| (*owner, *id, *state))
+        .collect();
+    let removed = baseline
+        .map(|baseline| baseline.keys().filter(|key| !current.contains_key(key)).copied().collect())
+        .unwrap_or_default();
+    (changed, removed)
+}
+
+/// Rebuilds the world state a delta was encoded from.
+pub fn apply_delta(
+    baseline: Option<&WorldState>,
+    changed: &[(PlayerId, ObjectId, ObjectState)],
+    removed: &[(PlayerId, ObjectId)],
+) -> WorldState {
+    let mut world = baseline.cloned().unwrap_or_default();
+    for key in removed {
+        world.remove(key);
+    }
+    for (owner, id, state) in changed {
+        world.insert((*owner, *id), *state);
+    }
+    world
+}
+
+/// World states of recent ticks, oldest first.
+#[derive(Default, Debug)]
+struct SnapshotHistory(VecDeque<(u32, WorldState)>);
+
+impl SnapshotHistory {
+    fn get(&self, tick: u32) -> Option<&WorldState> {
+        self.0.iter().find(|(t, _)| *t == tick).map(|(_, world)| world)
+    }
+
+    fn push(&mut self, tick: u32, world: WorldState) {
+        if self.0.len() == SNAPSHOT_HISTORY_LEN {
+            self.0.pop_front();
+        }
+        self.0.push_back((tick, world));
+    }
+}
+
+/// The server's recent world states and the latest tick each client acknowledged.
+#[derive(Resource, Default, Debug)]
+pub struct ServerSnapshots {
+    history: SnapshotHistory,
+    acked: HashMap<SocketAddr, u32>,
+}
+
+impl ServerSnapshots {
+    /// Records that `addr` has the world state of `tick`. Acks arriving out of order are ignored.
+    pub fn acknowledge(&mut self, addr: SocketAddr, tick: u32) {
+        let acked = self.acked.entry(addr).or_insert(tick);
+        *acked = (*acked).max(tick);
+    }
+
+    /// The snapshot of `current` to send `addr`, encoded against the latest tick it acknowledged.
+    fn snapshot_for(&self, addr: SocketAddr, tick: u32, current: &WorldState) -> Message {
+        let baseline = self
+            .acked
+            .get(&addr)
+            .and_then(|acked| self.history.get(*acked).map(|world| (*acked, world)));
+        let (changed, removed) = encode_delta(baseline.map(|(_, world)| world), current);
+        Message::WorldSnapshot {
+            tick,
+            baseline: baseline.map(|(tick, _)| tick),
+            changed,
+            removed,
+        }
+    }
+}
+
+pub fn receive_snapshot_acks(mut events: EventReader<NetworkEvent>, mut snapshots: ResMut<ServerSnapshots>) {
+    for event in events.iter() {
+        if let NetworkEvent::RawMessage(addr, Message::SnapshotAck(tick)) = event {
+            snapshots.acknowledge(*addr, *tick);
+        }
+    }
+}
+
+/// Sends every client the objects that changed since the latest snapshot it acknowledged.
+pub fn send_world_snapshots(
+    tick: Res<ServerTick>,
+    network: Res<NetworkGame>,
+    mut snapshots: ResMut<ServerSnapshots>,
+    mut transport: ResMut<Transport>,
+) {
+    let current: WorldState = network
+        .objects
+        .objects
+        .iter()
+        .map(|(object, state)| ((object.owner, object.id), *state))
+        .collect();
+
+    // Forget clients that have gone, so they are sent the full world if they come back
+    snapshots
+        .acked
+        .retain(|addr, _| network.players.players.values().any(|player| player == addr));
+
+    for addr in network.players.players.values() {
+        let message = snapshots.snapshot_for(*addr, tick.tick, &current);
+        transport.send(*addr, &serialize(message));
+    }
+    snapshots.history.push(tick.tick, current);
+}
+
+/// The world states a client has rebuilt from the snapshots it received.
+#[derive(Resource, Default, Debug)]
+pub struct ClientSnapshots {
+    history: SnapshotHistory,
+}
+
+impl ClientSnapshots {
+    /// Rebuilds the world state of `tick` from a received snapshot. Returns None for snapshots
+    /// older than the latest one, and for deltas encoded against a baseline no longer kept.
+    pub fn receive(
+        &mut self,
+        tick: u32,
+        baseline: Option<u32>,
+        changed: &[(PlayerId, ObjectId, ObjectState)],
+        removed: &[(PlayerId, ObjectId)],
+    ) -> Option<&WorldState> {
+        if self.history.0.back().is_some_and(|(latest, _)| tick <= *latest) {
+            return None;
+        }
+        let world = match baseline {
+            Some(baseline) => apply_delta(Some(self.history.get(baseline)?), changed, removed),
+            None => apply_delta(None, changed, removed),
+        };
+        self.history.push(tick, world);
+        self.history.0.back().map(|(_, world)| world)
+    }
+
+    /// Forgets every world state, for a new connection to a server whose ticks may have started
+    /// over.
+    pub fn reset(&mut self) {
+        self.history.0.clear();
+    }
+}
+
+/// Acknowledges every snapshot received from the server, counts the ones that were lost, and
+/// buffers the state of each object in it for interpolation once the clock is synced.
+pub fn receive_world_snapshots(
+    mut messages: EventReader<Message>,
+    mut snapshots: ResMut<ClientSnapshots>,
+    clock: Res<ServerClock>,
+    remote_addr: Res<SocketAddress>,
+    mut transport: ResMut<Transport>,
+    mut stats: ResMut<NetworkStats>,
+    mut objects: Query<(&NetworkObject, &mut NetworkTransform)>,
+) {
+    for message in messages.iter() {
+        if let Message::WorldSnapshot {
+            tick,
+            baseline,
+            changed,
+            removed,
+        } = message
+        {
+            // The server sends one every tick, so a skipped tick is a lost snapshot
+            stats.record_numbered(remote_addr.0, *tick);
+            let world = match snapshots.receive(*tick, *baseline, changed, removed) {
+                Some(world) => world,
+                None => continue,
+            };
+            transport.send(remote_addr.0, &serialize(Message::SnapshotAck(*tick)));
+
+            // Positions can't be placed on the server's timeline until the clock is synced
+            if !clock.is_synced() {
+                continue;
+            }
+            for (object, mut transform) in objects.iter_mut() {
+                if let Some(state) = world.get(&(object.owner, object.id)) {
+                    transform.push(Snapshot {
+                        time: clock.tick_time(*tick),
+                        state: *state,
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unchanged_objects_are_not_sent() {
+        let baseline = world(&[(1, Vec3::ZERO), (2, Vec3::X)]);
+        let current = world(&[(1, Vec3::ZERO), (2, Vec3::Y), (3, Vec3::Z)]);
+
+        let (changed, removed) = encode_delta(Some(&baseline), &current);
+        let mut changed_ids: Vec<ObjectId> = changed.iter().map(|(_, id, _)| *id).collect();
+        changed_ids.sort();
+        assert_eq!(changed_ids, vec![2, 3]);
+        assert!(removed.is_empty());
+        assert_eq!(apply_delta(Some(&baseline), &changed, &removed), current);
+    }
+
+    #[test]
+    fn test_removed_objects_are_sent() {
+        let baseline = world(&[(1, Vec3::ZERO), (2, Vec3::X)]);
+        let current = world(&[(1, Vec3::ZERO)]);
+
+        let (changed, removed) = encode_delta(Some(&baseline), &current);
+        assert!(changed.is_empty());
+        assert_eq!(removed, vec![(PlayerId(1), 2)]);
+        assert_eq!(apply_delta(Some(&baseline), &changed, &removed), current);
+    }
+
+    #[test]
+    fn test_client_converges_after_lost_snapshots() {
+        let addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let mut server = ServerSnapshots::default();
+        let mut client = ClientSnapshots::default();
+
+        // The first snapshot is received and acknowledged, the next two are lost
+        for tick in 0..3 {
+            let current = world(&[(1, Vec3::X * tick as f32)]);
+            let message = server.snapshot_for(addr, tick, &current);
+            if tick == 0 {
+                receive(&mut client, &message).unwrap();
+                server.acknowledge(addr, tick);
+            }
+            server.history.push(tick, current);
+        }
+
+        let current = world(&[(1, Vec3::X * 3.), (2, Vec3::Y)]);
+        let message = server.snapshot_for(addr, 3, &current);
+        assert!(matches!(message, Message::WorldSnapshot { baseline: Some(0), .. }));
+        assert_eq!(receive(&mut client, &message), Some(&current));
+    }
+
+    #[test]
+    fn test_unknown_baseline_is_dropped() {
+        let mut client = ClientSnapshots::default();
+        assert!(client.receive(5, Some(4), &[], &[]).is_none());
+    }
+
+    #[test]
+    fn test_stale_full_snapshot_is_ignored_until_reset() {
+        let mut client = ClientSnapshots::default();
+        let object = (PlayerId(1), 1, ObjectState::default());
+        assert!(client.receive(10, None, &[object], &[]).is_some());
+
+        // Late or duplicated, rather than from a restarted server
+        assert!(client.receive(5, None, &[], &[]).is_none());
+        assert!(client.receive(10, None, &[], &[]).is_none());
+        assert_eq!(client.receive(11, Some(10), &[], &[]).map(|world| world.len()), Some(1));
+
+        // Reconnected to a server that counts ticks from the start again
+        client.reset();
+        assert_eq!(client.receive(5, None, &[], &[]).map(|world| world.len()), Some(0));
+    }
+
+    fn receive<'a>(client: &'a mut ClientSnapshots, message: &Message) -> Option<&'a WorldState> {
+        match message {
+            Message::WorldSnapshot {
+                tick,
+                baseline,
+                changed,
+                removed,
+            } => client.receive(*tick, *baseline, changed, removed),
+            _ => panic!("not a snapshot: {:?}", message),
+        }
+    }
+
+    fn world(objects: &[(ObjectId, Vec3)]) -> WorldState {
+        objects
+            .iter()
+            .map(|(id, position)| {
+                (
+                    (PlayerId(1), *id),
+                    ObjectState {
+                        position: *position,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect()
+    }
+}